@@ -0,0 +1,92 @@
+// Command-line client for Tigron's SMS-API, printing results as JSON
+// Build with: cargo build --features cli
+//
+// Usage:
+//   tigron-sms [--username <username>] [--password <password>] send <to> <from> <message>
+//   tigron-sms [--username <username>] [--password <password>] status <message_id>
+//   tigron-sms [--username <username>] [--password <password>] quota
+//   tigron-sms [--username <username>] [--password <password>] balance
+//
+// Credentials default to the TIGRON_USERNAME and TIGRON_PASSWORD environment variables.
+// Results are written to stdout, errors to stderr as {"error": "..."} with exit code 1.
+
+use serde_json::json;
+use std::process;
+use tigron_sms::prelude::*;
+
+#[tokio::main]
+async fn main() {
+    match run(std::env::args().skip(1).collect()).await {
+        Ok(result) => println!("{}", result),
+        Err(error) => {
+            eprintln!("{}", json!({ "error": error }));
+            process::exit(1);
+        }
+    }
+}
+
+// Execute the command given on the command-line
+async fn run(args: Vec<String>) -> Result<serde_json::Value, String> {
+    let mut username = std::env::var("TIGRON_USERNAME").ok();
+    let mut password = std::env::var("TIGRON_PASSWORD").ok();
+
+    let mut args = args.into_iter();
+    let mut command: Vec<String> = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--username" => username = args.next(),
+            "--password" => password = args.next(),
+            _ => command.push(arg),
+        }
+    }
+
+    let client = match (username, password) {
+        (Some(username), Some(password)) => TigronSms::new(username, password),
+        _ => return Err("No credentials: set TIGRON_USERNAME and TIGRON_PASSWORD or pass --username and --password".to_string()),
+    };
+
+    match command.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>().as_slice() {
+        ["send", to, from, message] => {
+            let outcome = client
+                .send(to.to_string(), from.to_string(), message.to_string())
+                .await
+                .map_err(|e| e.to_string())?;
+
+            serde_json::to_value(&outcome).map_err(|e| e.to_string())
+        }
+        ["status", message_id] => {
+            let report = client
+                .delivery_report(message_id)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            Ok(json!({
+                "message_id": report.message_id,
+                "status": report.status.to_string(),
+                "timestamp": report.timestamp.map(|timestamp| timestamp.to_rfc3339()),
+                "error_code": report.error_code,
+                "error_text": report.error_text,
+            }))
+        }
+        ["quota"] => {
+            let quota = client.quota().await.map_err(|e| e.to_string())?;
+
+            Ok(json!({
+                "remaining": quota.remaining,
+                "resets_in_seconds": quota.resets_at.map(|resets_at| {
+                    resets_at.saturating_duration_since(std::time::Instant::now()).as_secs()
+                }),
+            }))
+        }
+        ["balance"] => {
+            let balance = client.balance().await.map_err(|e| e.to_string())?;
+
+            // f64 or an exact decimal depending on the features, either is printed as a JSON number
+            Ok(json!({
+                "balance": balance
+                    .and_then(|balance| balance.to_string().parse::<serde_json::Number>().ok()),
+            }))
+        }
+        _ => Err("Usage: tigron-sms [--username <username>] [--password <password>] (send <to> <from> <message> | status <message_id> | quota | balance)".to_string()),
+    }
+}
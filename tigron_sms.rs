@@ -3,249 +3,8382 @@
     Written by: Niel Duysters (contact@ndvibes.com)
 */
 
+// A network hiccup must surface as an error, never as a panic
+#![deny(clippy::unwrap_used, clippy::expect_used)]
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use futures::future::BoxFuture;
+use once_cell::sync::OnceCell;
+#[cfg(not(feature = "validate"))]
 use regex::Regex;
-use xml::reader::{EventReader, XmlEvent};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use xml::reader::{EventReader, Events, XmlEvent};
+
+// Types needed for a typical send flow: use tigron_sms::prelude::*;
+pub mod prelude {
+    pub use super::{
+        DeliveryReport, DeliveryStatus, MessageType, SendOptions, SendOutcome, SendRequest,
+        TigronError, TigronSms,
+    };
+}
+
+// Client to send a text-message through Tigron's API
+// The async methods must be awaited inside a tokio 0.2 runtime (e.g. #[tokio::main]), outside one the HTTP-client and
+// the timers panic. Without a runtime, use 'send_blocking'.
+// Cloning is cheap: clones share the credentials, the HTTP connection pool, the cached user_id and the spend counter.
+#[derive(Clone)]
+pub struct TigronSms {
+    credentials: Arc<RwLock<Arc<(String, Password)>>>,
+    segment_price: Amount,
+    price_table: HashMap<String, Amount>,
+    price_currency: Currency,
+    spend_cap: Option<Amount>,
+    max_response_bytes: usize,
+    spent: Arc<Mutex<Amount>>,
+    tls: TlsConfig,
+    local_address: Option<std::net::IpAddr>,
+    family_failover: bool,
+    connect_timeout: Option<Duration>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    in_flight: Option<Arc<tokio::sync::Semaphore>>,
+    activity: Arc<Activity>,
+    dedup: Option<Arc<DedupWindow>>,
+    country_rate_limits: Option<Arc<CountryRateLimiter>>,
+    latencies: Option<Arc<LatencyTracker>>,
+    rate_limit_behavior: RateLimitBehavior,
+    rate_limit_queue_cap: usize,
+    retry: RetryPolicy,
+    retryability: HashMap<String, Retryability>,
+    environment: Environment,
+    endpoint_suffix: String,
+    accept: String,
+    capture_on_error: bool,
+    pii_policy: PiiPolicy,
+    namespace_style: NamespaceStyle,
+    gateway_concatenation: bool,
+    allow_self_send: bool,
+    check_sender: bool,
+    success_statuses: std::vec::Vec<u16>,
+    queued_statuses: std::vec::Vec<u16>,
+    clock: Arc<dyn Clock>,
+    on_attempt: Option<OnAttempt>,
+    dead_letter: Option<DeadLetter>,
+    on_expiry: Option<OnExpiry>,
+    url_shortener: Option<Arc<dyn UrlShortener>>,
+    response_parser: Arc<dyn ResponseParser>,
+    normalize_text: bool,
+    trim_message: bool,
+    message_prefix: String,
+    message_suffix: String,
+    on_substitution: Option<OnSubstitution>,
+    content_filter: Option<ContentFilter>,
+    quota: Arc<Mutex<Option<Quota>>>,
+    #[cfg(feature = "signing")]
+    signing_key: Option<std::vec::Vec<u8>>,
+    http: Arc<OnceCell<reqwest::Client>>,
+    http_by_family: Arc<OnceCell<(reqwest::Client, reqwest::Client)>>,
+    blocking_runtime: Arc<OnceCell<Mutex<tokio::runtime::Runtime>>>,
+    user_id_ttl: Duration,
+    user_id: Arc<Mutex<Option<(String, Instant)>>>,
+    sender_numbers: Arc<Mutex<Option<(std::vec::Vec<String>, Instant)>>>,
+    wsdl: Arc<Mutex<HashMap<String, String>>>,
+}
+
+// TLS-settings of the HTTP-client. Defaults to the system roots with verification on.
+#[derive(Clone, Default)]
+struct TlsConfig {
+    root_certificates: std::vec::Vec<reqwest::Certificate>,
+    accept_invalid_certs: bool,
+}
+
+// Source of time for retries, backoff, the circuit breaker and the caches
+// Swap in a ManualClock to test them without real delays. The per-attempt timeout of the deadline
+// runs on the tokio timer, use tokio::time::pause/advance for it.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+// Wall-clock time with tokio's timer
+pub struct SystemClock;
+
+// Clock that only moves when advanced, sleeping advances it instantly
+pub struct ManualClock {
+    start: Instant,
+    offset: Mutex<Duration>,
+}
+
+// Service rewriting the URLs of a message into short ones, e.g. your own link shortener
+// Only the URL is passed in, the rest of the message is never sent to the service.
+pub trait UrlShortener: Send + Sync {
+    fn shorten<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<String, TigronError>>;
+}
+
+// Interpretation of the responses of the gateway, XmlResponseParser by default
+// 'pairs' reads the (key, value) pairs, 'records' splits a list-response, 'fault' returns the error a response reports.
+pub trait ResponseParser: Send + Sync {
+    fn pairs(&self, xml: &str) -> std::vec::Vec<(String, String)>;
+    fn records(&self, xml: &str) -> std::vec::Vec<std::vec::Vec<(String, String)>>;
+    fn fault(&self, xml: &str) -> Option<TigronError>;
+}
+
+// Retrying of calls that failed in transport or were rejected with a transient code. Defaults to no retries and no deadline.
+// 'max_backoff' caps the doubling delay, defaults to DEFAULT_MAX_BACKOFF
+#[derive(Clone)]
+struct RetryPolicy {
+    max_retries: u32,
+    base_delay: Duration,
+    max_backoff: Duration,
+    deadline: Option<Duration>,
+}
+
+// Whether a rejection of the gateway may succeed when sent again
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retryability {
+    Transient,
+    Permanent,
+}
+
+// Stops calling the gateway after consecutive failed calls (transport failures, 5xx-statuses, maintenance pages and
+// transient rejections), shared by all clones
+// Closed: calls pass. Open: calls fail fast until the reset timeout. Then a single trial call decides.
+struct CircuitBreaker {
+    failure_threshold: u32,
+    reset_timeout: Duration,
+    state: Mutex<CircuitState>,
+}
+
+// Recently sent (to, message) pairs, a repeat within the window returns the earlier outcome
+// Shared by all clones. Two identical sends racing each other can both pass.
+struct DedupWindow {
+    window: Duration,
+    sent: Mutex<HashMap<u64, (SendOutcome, Instant)>>,
+}
+
+// Spaces the sends to each destination country by its per-second limit, shared by all clones
+// Every send books the next free slot of its country, so sends to other countries are never held up.
+struct CountryRateLimiter {
+    limits: HashMap<CountryCode, u32>,
+    next_slot: Mutex<HashMap<CountryCode, Instant>>,
+}
+
+// Send-to-delivered latencies of the latest delivered messages, shared by all clones
+// Bounded: once full, the oldest latency makes room for the newest.
+struct LatencyTracker {
+    capacity: usize,
+    samples: Mutex<std::collections::VecDeque<Duration>>,
+}
+
+// Calls and background tasks in progress on a client and its clones, 'shutdown' waits for it to drop to zero
+struct Activity {
+    running: std::sync::atomic::AtomicUsize,
+    idle: tokio::sync::Notify,
+}
+
+// A call or background task counted by an Activity until dropped
+struct Running(Arc<Activity>);
+
+// What a send does when the rate limiter already has too many sends waiting for its country
+// Block waits its turn (back-pressure), Reject fails at once with TigronError::RateLimited (load shedding).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBehavior {
+    Block,
+    Reject,
+}
+
+#[derive(Default)]
+struct CircuitState {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    trial_in_flight: bool,
+}
+
+// Call let through by the circuit breaker, its result is reported with 'finish'
+// A trial call dropped unfinished (e.g. its future was cancelled) frees the trial for the next call.
+struct CircuitCall<'a> {
+    circuit_breaker: &'a CircuitBreaker,
+    trial: bool,
+    finished: bool,
+}
+
+// Password of the Tigron account. With the 'zeroize' feature it is scrubbed from memory on drop.
+#[cfg(feature = "zeroize")]
+pub type Password = zeroize::Zeroizing<String>;
+#[cfg(not(feature = "zeroize"))]
+pub type Password = String;
+
+// Text holding the password, e.g. the header or the envelope. Scrubbed on drop like the Password.
+type SecretText = Password;
+
+// A single text-message of a batch or of the offline queue
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SendRequest {
+    pub to: String,
+    pub from: String,
+    pub message: String,
+}
+
+// Result of a single send, handed back to the caller that queued it
+type Reply = tokio::sync::oneshot::Sender<Result<SendOutcome, TigronError>>;
+
+// Coalesces single sends arriving close together into one 'send_batch'
+// A batch is sent once it holds 'max_batch' messages or its first message waited 'max_delay'.
+// Must be created inside a tokio runtime, the batches are sent by a background task.
+#[derive(Clone)]
+pub struct BatchingSender {
+    queue: tokio::sync::mpsc::UnboundedSender<(SendRequest, Reply)>,
+}
+
+// Store-and-forward client for devices with intermittent connectivity
+// Sends that fail on the network are queued durably and sent, in order, by 'flush'.
+pub struct QueuedSms {
+    pub client: TigronSms,
+    store: Box<dyn Store>,
+    dead_letter: Option<DeadLetter>,
+}
+
+// Durable FIFO-queue of text-messages waiting for connectivity
+pub trait Store: Send + Sync {
+    fn push(&self, request: &SendRequest) -> Result<(), TigronError>;
+    fn peek(&self) -> Result<Option<SendRequest>, TigronError>;
+    fn pop(&self) -> Result<(), TigronError>;
+}
+
+// Store keeping one JSON-encoded text-message per line in a file
+pub struct FileStore {
+    path: std::path::PathBuf,
+    lock: Mutex<()>,
+}
+
+// Optional parameters for a single text-message
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    dcs: Option<u8>,
+    message_type: MessageType,
+    route: Option<String>,
+    validity: Option<Duration>,
+}
+
+// Settings of a client, e.g. loaded from config.toml or JSON with serde
+// Only username and password are required. The password is masked in Debug-output.
+// ${NAME} in the username or password is replaced with the environment variable NAME, e.g. "${TIGRON_PASSWORD}".
+// 'country_rate_limits' is keyed by ISO 3166-1 code, e.g. {"BE": 10}. 'rate_limit_behavior' is "block" or "reject".
+#[derive(Clone, Deserialize)]
+pub struct TigronConfig {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub namespace: Option<String>,
+    #[serde(default)]
+    pub deadline_ms: Option<u64>,
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub retries: Option<u32>,
+    #[serde(default)]
+    pub retry_delay_ms: Option<u64>,
+    #[serde(default)]
+    pub max_backoff_ms: Option<u64>,
+    #[serde(default)]
+    pub user_id_ttl_secs: Option<u64>,
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+    #[serde(default)]
+    pub country_rate_limits: Option<HashMap<String, u32>>,
+    #[serde(default)]
+    pub rate_limit_behavior: Option<RateLimitBehavior>,
+    #[serde(default)]
+    pub rate_limit_queue_cap: Option<usize>,
+}
+
+// What of the personal data in captured XML is kept readable, see 'with_pii_redaction'
+// Defaults to masked numbers and a hidden message body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PiiPolicy {
+    pub mask_numbers: bool,
+    pub log_message: bool,
+}
+
+// Gateway the client talks to, each sets the matching base URL and namespace
+// Sandbox is pending until the endpoint of a Tigron sandbox is known, see the Todo in the README.
+// Until then point Custom at a sandbox or a local mock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Environment {
+    Production,
+    Custom { url: String, ns: String },
+}
+
+// How the command element of the SOAP-body is bound to Tigron's namespace
+// Default: <send_sms xmlns="..."><to>..</to></send_sms>
+// Prefixed: <ns:send_sms xmlns:ns="..."><to>..</to></ns:send_sms>
+// PrefixedQualified: <ns:send_sms xmlns:ns="..."><ns:to>..</ns:to></ns:send_sms>
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NamespaceStyle {
+    Default,
+    Prefixed,
+    PrefixedQualified,
+}
+
+// Regulatory category of a text-message, sent as the 'type' parameter of 'send_sms'
+// Allowed values: "transactional" (default, e.g. OTP) and "promotional" (marketing)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    Transactional,
+    Promotional,
+}
+
+// Answer of the gateway to a text-message. A rejection is an outcome, not an error.
+// Accepted and Queued only mean the gateway took the message, neither means it was delivered:
+// Accepted is handed to the network, Queued is acknowledged and sent later. Track delivery with 'delivery_status'.
+// 'request_id' correlates the outcome with the logs of the gateway
+// 'accepted_at' is the time the gateway took the message, None when it doesn't report it
+// 'message_ids' holds an id per part of a concatenated or bulk send, 'id' is the first of them
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum SendOutcome {
+    Accepted {
+        id: String,
+        message_ids: std::vec::Vec<String>,
+        request_id: String,
+        accepted_at: Option<DateTime<Utc>>,
+    },
+    Queued {
+        id: String,
+        message_ids: std::vec::Vec<String>,
+        request_id: String,
+        accepted_at: Option<DateTime<Utc>>,
+    },
+    Rejected { code: String, reason: String, request_id: String },
+}
+
+// Delivery report of a text-message as sent by the gateway
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeliveryReport {
+    pub message_id: String,
+    pub status: DeliveryStatus,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub error_code: Option<u32>,
+    pub error_text: Option<String>,
+}
+
+// A sent text-message as listed by 'list_messages'
+#[derive(Clone, Debug, PartialEq)]
+pub struct MessageSummary {
+    pub id: String,
+    pub to: String,
+    pub status: DeliveryStatus,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+// State of the account as returned by the 'info' procedure
+// Fields the gateway doesn't report are None or empty. Limits are the numeric fields named '*limit*'.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccountInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub balance: Option<Amount>,
+    pub senders: std::vec::Vec<String>,
+    pub limits: HashMap<String, u64>,
+}
+
+// Final or intermediate state of a text-message on the network
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    Delivered,
+    Pending,
+    Failed,
+    Expired,
+    Unknown(String),
+}
+
+// Hook called after every attempt of a call, retries included
+pub type OnAttempt = Arc<dyn Fn(&AttemptInfo) + Send + Sync>;
+
+// Hook called with the (original, replacement) characters whenever text normalization changed a message
+pub type OnSubstitution = Arc<dyn Fn(&[(char, &'static str)]) + Send + Sync>;
+
+// Policy deciding whether a message may be sent, Err carries the reason it may not
+pub type ContentFilter = Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+// Hook called for every message of a batch that failed for good
+pub type DeadLetter = Arc<dyn Fn(FailedSend) + Send + Sync>;
+
+// Hook called once the validity of a sent message passed, with its outcome and the number of cancelled parts or the error
+pub type OnExpiry = Arc<dyn Fn(&SendOutcome, &Result<usize, TigronError>) + Send + Sync>;
+
+// A message of a batch that failed for good, as passed to the 'with_dead_letter' hook
+// A rejection by the gateway is passed as TigronError::SendRejected.
+#[derive(Clone, Debug)]
+pub struct FailedSend {
+    pub request: SendRequest,
+    pub error: Arc<TigronError>,
+}
+
+// A single attempt of a call, as passed to the 'with_on_attempt' hook
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttemptInfo {
+    pub command: String,
+    pub attempt: u32,
+    pub latency: Duration,
+    pub result: Result<u16, String>,
+}
+
+// Rate-limit quota of the account as last reported by the gateway
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub remaining: u64,
+    pub resets_at: Option<Instant>,
+}
+
+// Send-to-delivered latencies observed by the client, as returned by 'observed_latency_percentiles'
+// 'count' is the number of latencies they are taken from, all are zero when nothing was observed yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Percentiles {
+    pub count: usize,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+// Where the time of a send went, as returned by 'send_timed'
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    // Checks of the numbers and the sender, shortening and validation of the message
+    pub preparation: Duration,
+    pub user_id_lookup: Duration,
+    // The SOAP-exchange of 'send_sms' only, including its retries
+    pub send_call: Duration,
+    pub total: Duration,
+}
+
+// Steps of 'diagnose' in the order they ran, each with its outcome and time
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiagnosisReport {
+    pub steps: std::vec::Vec<DiagnosisStep>,
+}
+
+// A single step of 'diagnose'
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiagnosisStep {
+    pub name: &'static str,
+    pub status: DiagnosisStatus,
+    pub elapsed: Duration,
+}
+
+// Outcome of a step of 'diagnose', a step is skipped when one it depends on failed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiagnosisStatus {
+    Passed(String),
+    Failed(String),
+    Skipped,
+}
+
+// Event of the XML of a response, as yielded by 'parse_events'
+// Names are local, without namespace prefix. Whitespace between elements, comments and the declaration are left out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParsedEvent {
+    Start {
+        name: String,
+        attributes: std::vec::Vec<(String, String)>,
+    },
+    Text(String),
+    End { name: String },
+}
+
+// Result of checking a text-message without sending it
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidationReport {
+    pub routable: bool,
+    pub country: Option<CountryCode>,
+    pub segments: u64,
+    pub estimated_cost: Money,
+}
+
+// Exact decimal amounts with the 'decimal' feature, floating point otherwise
+#[cfg(feature = "decimal")]
+pub type Amount = rust_decimal::Decimal;
+#[cfg(not(feature = "decimal"))]
+pub type Amount = f64;
+
+// Unit of the prices, Tigron bills text-messages in credits
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Currency {
+    Credits,
+    Iso(String),
+}
+
+// An amount together with its currency
+#[derive(Clone, Debug, PartialEq)]
+pub struct Money {
+    pub amount: Amount,
+    pub currency: Currency,
+}
+
+// Errors raised when the gateway could not be reached or understood
+#[derive(Debug)]
+pub enum TigronError {
+    InvalidInput(String),
+    InvalidNumber(String),
+    InvalidUtf8(std::str::Utf8Error),
+    Config(String),
+    UserNotFound,
+    SendRejected { code: String, reason: String },
+    Fault { code: String, reason: String },
+    SpendCapExceeded { cap: Money, spent: Money },
+    RateLimited { country: CountryCode },
+    ResponseTooLarge { limit: usize },
+    TruncatedResponse { expected: u64, received: u64 },
+    CircuitOpen,
+    DeadlineExceeded,
+    ConfirmationTimeout { message_id: String },
+    QuotaUnavailable,
+    SameFromTo,
+    UnregisteredSender { from: String },
+    ContentRejected(String),
+    AlreadySent { message_id: String },
+    MessageNotFound { message_id: String },
+    Redirect { location: String },
+    HttpStatus { status: u16 },
+    Shared(Arc<TigronError>),
+    NotXml { content_type: Option<String> },
+    Storage(std::io::Error),
+    Io(std::io::Error),
+    Captured {
+        error: Box<TigronError>,
+        request: String,
+        response: Option<String>,
+    },
+    Transport(reqwest::Error),
+    Parse(String),
+}
+
+// Value of a parameter of a call, rendered the way the gateway expects it
+// Strings are sent as they are, numbers in decimal and flags as 1/0.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParamValue {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+// Schema-defined order of the parameters of 'send_sms', unknown parameters are emitted after these
+pub const SEND_SMS_PARAMETER_ORDER: &[&str] = &["user_id", "from", "to", "message", "type", "dcs", "route", "concat"];
+
+// Time after which the cached user_id is looked up again
+const DEFAULT_USER_ID_TTL: Duration = Duration::from_secs(60 * 60);
+
+// Longest delay between two retries, however many attempts came before
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+// Recipients read by 'send_from_reader' before they are sent together
+const READER_BATCH_SIZE: usize = 100;
+
+// Number of delivery reports requested at the same time by 'reconcile'
+const RECONCILE_CONCURRENCY: usize = 4;
+
+// The envelope is SOAP 1.1 (text/xml), SOAP 1.2 answers are accepted as well
+const DEFAULT_ACCEPT: &str = "text/xml, application/soap+xml";
+
+// Concatenation counts the segments of a message in a single octet
+const MAX_CONCATENATED_SEGMENTS: u64 = 255;
+
+// Far above any legitimate SOAP-response of the API
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 4 * 1024 * 1024;
+
+// Characters of the GSM 03.38 default alphabet, the extended ones take two septets
+const GSM7_CHARACTERS: &str = "@£$¥èéùìòÇ\nØø\rÅåΔ_ΦΓΛΩΠΨΣΘΞÆæßÉ !\"#¤%&'()*+,-./0123456789:;<=>?¡ABCDEFGHIJKLMNOPQRSTUVWXYZÄÖÑÜ§¿abcdefghijklmnopqrstuvwxyzäöñüà^{}\\[~]|€\x0C";
+const GSM7_EXTENDED: &str = "^{}\\[~]|€\x0C";
+
+// Typographic characters that force UCS-2, with their GSM-7 equivalent
+const GSM7_SUBSTITUTIONS: &[(char, &str)] = &[
+    ('\u{2018}', "'"), ('\u{2019}', "'"), ('\u{201A}', "'"), ('\u{2032}', "'"),
+    ('\u{201C}', "\""), ('\u{201D}', "\""), ('\u{201E}', "\""), ('\u{2033}', "\""),
+    ('\u{2013}', "-"), ('\u{2014}', "-"), ('\u{2212}', "-"),
+    ('\u{00A0}', " "), ('\u{2007}', " "), ('\u{2009}', " "), ('\u{202F}', " "),
+    ('\u{2026}', "..."),
+];
+
+// Country a telephone number belongs to, derived from its dialing prefix
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CountryCode {
+    pub iso: &'static str,
+    pub dialing_code: &'static str,
+}
+
+// Dialing prefixes and the ISO 3166-1 country they belong to
+// Prefixes shared by several countries resolve to the principal one: +1 is US, +7 is RU,
+// +44 is GB, +47 is NO, +61 is AU, +262 is RE, +590 is GP and +599 is CW.
+const DIALING_CODES: &[(&str, &str)] = &[
+    ("1", "US"), ("7", "RU"), ("20", "EG"), ("27", "ZA"), ("30", "GR"), ("31", "NL"), ("32", "BE"),
+    ("33", "FR"), ("34", "ES"), ("36", "HU"), ("39", "IT"), ("40", "RO"), ("41", "CH"),
+    ("43", "AT"), ("44", "GB"), ("45", "DK"), ("46", "SE"), ("47", "NO"), ("48", "PL"),
+    ("49", "DE"), ("51", "PE"), ("52", "MX"), ("53", "CU"), ("54", "AR"), ("55", "BR"),
+    ("56", "CL"), ("57", "CO"), ("58", "VE"), ("60", "MY"), ("61", "AU"), ("62", "ID"),
+    ("63", "PH"), ("64", "NZ"), ("65", "SG"), ("66", "TH"), ("81", "JP"), ("82", "KR"),
+    ("84", "VN"), ("86", "CN"), ("90", "TR"), ("91", "IN"), ("92", "PK"), ("93", "AF"),
+    ("94", "LK"), ("95", "MM"), ("98", "IR"), ("211", "SS"), ("212", "MA"), ("213", "DZ"),
+    ("216", "TN"), ("218", "LY"), ("220", "GM"), ("221", "SN"), ("222", "MR"), ("223", "ML"),
+    ("224", "GN"), ("225", "CI"), ("226", "BF"), ("227", "NE"), ("228", "TG"), ("229", "BJ"),
+    ("230", "MU"), ("231", "LR"), ("232", "SL"), ("233", "GH"), ("234", "NG"), ("235", "TD"),
+    ("236", "CF"), ("237", "CM"), ("238", "CV"), ("239", "ST"), ("240", "GQ"), ("241", "GA"),
+    ("242", "CG"), ("243", "CD"), ("244", "AO"), ("245", "GW"), ("248", "SC"), ("249", "SD"),
+    ("250", "RW"), ("251", "ET"), ("252", "SO"), ("253", "DJ"), ("254", "KE"), ("255", "TZ"),
+    ("256", "UG"), ("257", "BI"), ("258", "MZ"), ("260", "ZM"), ("261", "MG"), ("262", "RE"),
+    ("263", "ZW"), ("264", "NA"), ("265", "MW"), ("266", "LS"), ("267", "BW"), ("268", "SZ"),
+    ("269", "KM"), ("290", "SH"), ("291", "ER"), ("297", "AW"), ("298", "FO"), ("299", "GL"),
+    ("350", "GI"), ("351", "PT"), ("352", "LU"), ("353", "IE"), ("354", "IS"), ("355", "AL"),
+    ("356", "MT"), ("357", "CY"), ("358", "FI"), ("359", "BG"), ("370", "LT"), ("371", "LV"),
+    ("372", "EE"), ("373", "MD"), ("374", "AM"), ("375", "BY"), ("376", "AD"), ("377", "MC"),
+    ("378", "SM"), ("380", "UA"), ("381", "RS"), ("382", "ME"), ("383", "XK"), ("385", "HR"),
+    ("386", "SI"), ("387", "BA"), ("389", "MK"), ("420", "CZ"), ("421", "SK"), ("423", "LI"),
+    ("500", "FK"), ("501", "BZ"), ("502", "GT"), ("503", "SV"), ("504", "HN"), ("505", "NI"),
+    ("506", "CR"), ("507", "PA"), ("508", "PM"), ("509", "HT"), ("590", "GP"), ("591", "BO"),
+    ("592", "GY"), ("593", "EC"), ("594", "GF"), ("595", "PY"), ("596", "MQ"), ("597", "SR"),
+    ("598", "UY"), ("599", "CW"), ("670", "TL"), ("673", "BN"), ("674", "NR"), ("675", "PG"),
+    ("676", "TO"), ("677", "SB"), ("678", "VU"), ("679", "FJ"), ("680", "PW"), ("685", "WS"),
+    ("686", "KI"), ("687", "NC"), ("689", "PF"), ("691", "FM"), ("692", "MH"), ("850", "KP"),
+    ("852", "HK"), ("853", "MO"), ("855", "KH"), ("856", "LA"), ("880", "BD"), ("886", "TW"),
+    ("960", "MV"), ("961", "LB"), ("962", "JO"), ("963", "SY"), ("964", "IQ"), ("965", "KW"),
+    ("966", "SA"), ("967", "YE"), ("968", "OM"), ("970", "PS"), ("971", "AE"), ("972", "IL"),
+    ("973", "BH"), ("974", "QA"), ("975", "BT"), ("976", "MN"), ("977", "NP"), ("992", "TJ"),
+    ("993", "TM"), ("994", "AZ"), ("995", "GE"), ("996", "KG"), ("998", "UZ"),
+];
+
+// Basic SOAP-client to interact with API
+// Note: This SOAP-client will only suffice for the sms use-case.
+struct SoapClient<'a> {
+    pub http: &'a reqwest::Client,
+    pub http_by_family: Option<&'a (reqwest::Client, reqwest::Client)>,
+    pub local_address: Option<std::net::IpAddr>,
+    pub circuit_breaker: Option<&'a CircuitBreaker>,
+    pub in_flight: Option<&'a tokio::sync::Semaphore>,
+    pub activity: &'a Arc<Activity>,
+    pub retry: &'a RetryPolicy,
+    pub endpoint_suffix: &'a str,
+    pub accept: &'a str,
+    pub capture_on_error: bool,
+    pub pii_policy: PiiPolicy,
+    pub namespace_style: NamespaceStyle,
+    pub success_statuses: &'a [u16],
+    pub clock: &'a dyn Clock,
+    pub on_attempt: Option<&'a (dyn Fn(&AttemptInfo) + Send + Sync)>,
+    pub quota: &'a Mutex<Option<Quota>>,
+    #[cfg(feature = "signing")]
+    pub signing_key: Option<&'a [u8]>,
+    pub max_response_bytes: usize,
+    pub url: String,
+    pub ns: String,
+    pub credentials: Arc<(String, Password)>,
+}
+
+// Basic XML Parser to interpet the response from the Tigron-API, the default ResponseParser
+// Pairs are read from <item><key>..</key><value>..</value></item> or <item key=".." value=".."/>.
+pub struct XmlResponseParser;
+
+// 'authenticate_user' SOAP-header carrying the credentials of every call
+struct AuthHeader<'a> {
+    ns: &'a str,
+    username: &'a str,
+    password: &'a str,
+}
+
+// (key, value) pairs of a response, parsed one at a time
+struct Pairs<R: std::io::Read> {
+    events: Events<R>,
+    read_key: bool,
+    read_value: bool,
+    key: String,
+    done: bool,
+}
+
+// Records of a list-response, parsed one at a time so only the current record is held in memory
+// A new record starts when a key repeats.
+pub struct Records<R: std::io::Read = std::io::Cursor<std::vec::Vec<u8>>> {
+    pairs: Pairs<R>,
+    pending: Option<(String, String)>,
+}
+
+// Response of a SOAP-call
+// 'envelope' is the redacted request, only kept when errors capture the exchange.
+struct SoapResponse {
+    body: String,
+    request_id: String,
+    status: u16,
+    envelope: String,
+}
+
+// Decides whether a response is sent again, e.g. a send rejected with a transient code
+type Resend<'a> = dyn Fn(&SoapResponse) -> bool + Send + Sync + 'a;
+
+impl TigronSms {
+
+    /*
+        Create a client for the given Tigron account
+        :param username: Username of the Tigron account
+        :param password: Password of the Tigron account
+        :return TigronSms: Returns a client without spend cap, charging 1 credit per segment
+    */
+    #[allow(clippy::useless_conversion)] // Identity conversion without the 'zeroize' feature
+    pub fn new(username: String, password: String) -> Self {
+        TigronSms {
+            credentials: Arc::new(RwLock::new(Arc::new((username, password.into())))),
+            segment_price: Amount::from(1u32),
+            price_table: HashMap::new(),
+            price_currency: Currency::Credits,
+            spend_cap: None,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            spent: Arc::new(Mutex::new(Amount::default())),
+            tls: TlsConfig::default(),
+            local_address: None,
+            family_failover: false,
+            connect_timeout: None,
+            circuit_breaker: None,
+            in_flight: None,
+            activity: Arc::new(Activity {
+                running: std::sync::atomic::AtomicUsize::new(0),
+                idle: tokio::sync::Notify::new(),
+            }),
+            dedup: None,
+            country_rate_limits: None,
+            latencies: None,
+            rate_limit_behavior: RateLimitBehavior::Block,
+            rate_limit_queue_cap: usize::MAX,
+            retry: RetryPolicy::default(),
+            retryability: default_retryability(),
+            environment: Environment::Production,
+            endpoint_suffix: "?WSDL".to_string(),
+            accept: DEFAULT_ACCEPT.to_string(),
+            capture_on_error: false,
+            pii_policy: PiiPolicy::default(),
+            namespace_style: NamespaceStyle::Default,
+            gateway_concatenation: false,
+            allow_self_send: false,
+            check_sender: false,
+            success_statuses: std::vec::Vec::new(),
+            queued_statuses: vec![202],
+            clock: Arc::new(SystemClock),
+            on_attempt: None,
+            dead_letter: None,
+            on_expiry: None,
+            url_shortener: None,
+            response_parser: Arc::new(XmlResponseParser),
+            normalize_text: false,
+            trim_message: false,
+            message_prefix: String::new(),
+            message_suffix: String::new(),
+            on_substitution: None,
+            content_filter: None,
+            quota: Arc::new(Mutex::new(None)),
+            #[cfg(feature = "signing")]
+            signing_key: None,
+            http: Arc::new(OnceCell::new()),
+            http_by_family: Arc::new(OnceCell::new()),
+            blocking_runtime: Arc::new(OnceCell::new()),
+            user_id_ttl: DEFAULT_USER_ID_TTL,
+            user_id: Arc::new(Mutex::new(None)),
+            sender_numbers: Arc::new(Mutex::new(None)),
+            wsdl: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /*
+        Replace the credentials of this client and all its clones, e.g. when they are rotated
+        Calls already in flight finish with the old credentials. The cached user_id is looked up again.
+        :param username: Username of the Tigron account
+        :param password: Password of the Tigron account
+    */
+    #[allow(clippy::useless_conversion)] // Identity conversion without the 'zeroize' feature
+    pub fn update_credentials(&self, username: String, password: String) {
+        *self.credentials.write().unwrap_or_else(|e| e.into_inner()) =
+            Arc::new((username, password.into()));
+        *self.user_id.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        *self.sender_numbers.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    // Username of the Tigron account
+    pub fn username(&self) -> String {
+        self.credentials
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .0
+            .to_string()
+    }
+
+    /*
+        Create a client from loaded settings
+        :param config: Settings of the client. E.g: toml::from_str::<TigronConfig>(&file)?
+        :return Result<TigronSms, TigronError>: Returns the client with the settings applied, unset ones keep their default. Returns TigronError::Config for an unset environment variable or an unknown country.
+    */
+    pub fn from_config(config: TigronConfig) -> Result<Self, TigronError> {
+        let mut client = TigronSms::new(
+            interpolate_env(&config.username)?,
+            interpolate_env(&config.password)?,
+        );
+
+        if let Some(url) = config.url {
+            let ns = config
+                .namespace
+                .unwrap_or_else(|| Environment::Production.ns().to_string());
+            client = client.with_environment(Environment::Custom { url, ns });
+        }
+        if let Some(deadline_ms) = config.deadline_ms {
+            client = client.with_deadline(Duration::from_millis(deadline_ms));
+        }
+        if let Some(retries) = config.retries {
+            let base_delay = config
+                .retry_delay_ms
+                .map(Duration::from_millis)
+                .unwrap_or(client.retry.base_delay);
+            client = client.with_retries(retries, base_delay);
+        }
+        if let Some(max_backoff_ms) = config.max_backoff_ms {
+            client = client.with_max_backoff(Duration::from_millis(max_backoff_ms));
+        }
+        if let Some(user_id_ttl_secs) = config.user_id_ttl_secs {
+            client = client.with_user_id_ttl(Duration::from_secs(user_id_ttl_secs));
+        }
+        if let Some(connect_timeout_ms) = config.connect_timeout_ms {
+            client = client.with_connect_timeout(Duration::from_millis(connect_timeout_ms));
+        }
+        if let Some(max_concurrent) = config.max_concurrent {
+            client = client.with_max_concurrent(max_concurrent);
+        }
+        if let Some(country_rate_limits) = config.country_rate_limits {
+            let mut limits = HashMap::new();
+            for (iso, limit) in country_rate_limits {
+                let country = CountryCode::from_iso(&iso).ok_or_else(|| {
+                    TigronError::Config(format!("Unknown country '{}' in the rate limits.", iso))
+                })?;
+                limits.insert(country, limit);
+            }
+            client = client.with_country_rate_limits(limits);
+        }
+        if config.rate_limit_behavior.is_some() || config.rate_limit_queue_cap.is_some() {
+            let behavior = config
+                .rate_limit_behavior
+                .unwrap_or(client.rate_limit_behavior);
+            let queue_cap = config
+                .rate_limit_queue_cap
+                .unwrap_or(client.rate_limit_queue_cap);
+            client = client.with_rate_limit_behavior(behavior, queue_cap);
+        }
+
+        Ok(client)
+    }
+
+    /*
+        Refuse further sends once the estimated cost of this client reaches the cap
+        :param credits: Maximum amount this client may spend, in the currency of the prices. E.g: 500.0
+        :return TigronSms: Returns the client with the cap applied
+    */
+    pub fn with_spend_cap(mut self, credits: Amount) -> Self {
+        self.spend_cap = Some(credits);
+        self
+    }
+
+    /*
+        Set the price of a single segment used to estimate the cost of a send
+        :param credits: Credits charged per segment. Defaults to 1.0
+        :return TigronSms: Returns the client with the price applied
+    */
+    pub fn with_segment_price(mut self, credits: Amount) -> Self {
+        self.segment_price = credits;
+        self
+    }
+
+    /*
+        Load the price per segment of each destination country
+        Tigron's SOAP-API has no pricing service, so prices are taken from this static table.
+        Countries missing from the table are charged the price set with 'with_segment_price'.
+        :param table: Credits per segment keyed by country code. E.g: [("32", 0.08), ("31", 0.09)]
+        :return TigronSms: Returns the client with the price table applied
+    */
+    pub fn with_price_table(mut self, table: HashMap<String, Amount>) -> Self {
+        self.price_table = table;
+        self
+    }
+
+    /*
+        Set the currency the segment price, the price table and the spend cap are expressed in
+        :param currency: Currency of the prices. Defaults to Currency::Credits
+        :return TigronSms: Returns the client with the currency applied
+    */
+    pub fn with_price_currency(mut self, currency: Currency) -> Self {
+        self.price_currency = currency;
+        self
+    }
+
+    /*
+        Limit the size of a response body, larger responses are aborted while they are read
+        :param bytes: Maximum size of a response body. Defaults to 4 MiB
+        :return TigronSms: Returns the client with the limit applied
+    */
+    pub fn with_max_response_bytes(mut self, bytes: usize) -> Self {
+        self.max_response_bytes = bytes;
+        self
+    }
+
+    /*
+        Pin the gateway to a root certificate, protecting the SMS-channel against MITM
+        Once a certificate is pinned the system roots are no longer trusted. Can be called several times.
+        The HTTP-client is built again on the next send, clones made before keep the previous one.
+        :param certificate: CA or self-signed leaf certificate of the gateway. E.g: reqwest::Certificate::from_pem(pem)?
+        :return TigronSms: Returns the client with the certificate pinned
+    */
+    pub fn with_root_certificate(mut self, certificate: reqwest::Certificate) -> Self {
+        self.tls.root_certificates.push(certificate);
+        self.reset_http();
+        self
+    }
+
+    /*
+        DANGEROUS: Accept any certificate, including expired, self-signed and wrongly named ones
+        Anyone on the network path can then read the credentials and messages. Only use it against a local test gateway.
+        :param accept: Whether to skip verification. Defaults to false
+        :return TigronSms: Returns the client with the setting applied
+    */
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.tls.accept_invalid_certs = accept;
+        self.reset_http();
+        self
+    }
+
+    /*
+        Give up on connecting to the gateway after a while, a failed connect is retried like any transport failure
+        The deadline set with 'with_deadline' still bounds the call as a whole.
+        :param connect_timeout: Longest time to set up a connection. E.g: Duration::from_secs(3)
+        :return TigronSms: Returns the client with the timeout applied
+    */
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self.reset_http();
+        self
+    }
+
+    /*
+        Connect from a specific local address, e.g. to use one interface of a multi-homed host
+        By default the system picks the source address and both IPv4 and IPv6 addresses of the gateway are tried.
+        Binding to an IPv4 address restricts connections to IPv4, binding to an IPv6 address to IPv6.
+        reqwest 0.10 has no pluggable DNS resolver, names are resolved by the system resolver.
+        :param address: Local address to bind to. E.g: "192.0.2.10".parse().unwrap()
+        :return TigronSms: Returns the client with the address applied
+    */
+    pub fn with_local_address(mut self, address: std::net::IpAddr) -> Self {
+        self.local_address = Some(address);
+        self.reset_http();
+        self
+    }
+
+    // Drop the HTTP-clients built so far, the next call builds them with the new connection settings
+    // A fresh cell, so clones made before keep sharing the previous clients.
+    fn reset_http(&mut self) {
+        self.http = Arc::new(OnceCell::new());
+        self.http_by_family = Arc::new(OnceCell::new());
+    }
+
+    /*
+        Only connect to the gateway over IPv4, for hosts where IPv6 to the gateway is unreliable
+        Binds to the unspecified IPv4 address, so the system still picks the interface.
+        :return TigronSms: Returns the client restricted to IPv4
+    */
+    pub fn ipv4_only(self) -> Self {
+        self.with_local_address(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED))
+    }
+
+    /*
+        Retry over the other IP family after a failed connection, to ride out an outage of IPv4 or IPv6 to the gateway
+        After a connection failure the next attempt is bound to the other family: IPv6 after IPv4 and IPv4 after IPv6.
+        Without a local address the first switch is to IPv4. A further connection failure switches back.
+        Only effective together with 'with_retries', the retries are not increased.
+        :param enabled: Whether to switch families. Defaults to false
+        :return TigronSms: Returns the client with failover applied
+    */
+    pub fn with_family_failover(mut self, enabled: bool) -> Self {
+        self.family_failover = enabled;
+        self
+    }
+
+    /*
+        Fail fast while the gateway is down instead of paying the full timeout on every send
+        :param failure_threshold: Consecutive failed calls (transport failures, 5xx-statuses, maintenance pages and transient rejections) after which the circuit opens. E.g: 5
+        :param reset_timeout: Time the circuit stays open before a trial call is let through. E.g: Duration::from_secs(30)
+        :return TigronSms: Returns the client with the circuit breaker applied
+    */
+    pub fn with_circuit_breaker(mut self, failure_threshold: u32, reset_timeout: Duration) -> Self {
+        self.circuit_breaker = Some(Arc::new(CircuitBreaker {
+            failure_threshold,
+            reset_timeout,
+            state: Mutex::new(CircuitState::default()),
+        }));
+        self
+    }
+
+    /*
+        Cap the number of requests this client and its clones have in flight at the same time
+        Applies to every call (single sends, batches, lookups), calls above the cap wait for a free slot.
+        The cap is independent of the concurrency of a batch, and a retry waits for a slot again.
+        A cap of 0 would make every call wait forever, it is raised to 1.
+        :param max_concurrent: Most requests in flight. E.g: 8
+        :return TigronSms: Returns the client with the cap applied
+    */
+    pub fn with_max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.in_flight = Some(Arc::new(tokio::sync::Semaphore::new(max_concurrent.max(1))));
+        self
+    }
+
+    /*
+        Keep the send-to-delivered latencies of delivered messages, for 'observed_latency_percentiles'
+        Latencies are observed by 'send_and_confirm', and by 'reconcile' when the gateway reports both the
+        acceptance and the delivery time. Off by default, so simple use pays nothing for it.
+        :param capacity: Most latencies kept, the oldest are dropped first. E.g: 10000
+        :return TigronSms: Returns the client with the tracking applied
+    */
+    pub fn with_latency_tracking(mut self, capacity: usize) -> Self {
+        self.latencies = if capacity == 0 {
+            None
+        } else {
+            Some(Arc::new(LatencyTracker {
+                capacity,
+                samples: Mutex::new(std::collections::VecDeque::new()),
+            }))
+        };
+        self
+    }
+
+    /*
+        Skip a send identical to one made shortly before, returning the earlier outcome instead
+        Guards against duplicates from retries higher in the stack. Sends are identical when destination and content match.
+        Only accepted and queued sends are remembered, a rejected send can be sent again.
+        :param window: Time a send is remembered. E.g: Duration::from_secs(60)
+        :return TigronSms: Returns the client with deduplication applied
+    */
+    pub fn with_dedup_window(mut self, window: Duration) -> Self {
+        self.dedup = Some(Arc::new(DedupWindow {
+            window,
+            sent: Mutex::new(HashMap::new()),
+        }));
+        self
+    }
+
+    /*
+        Limit the sends per second to each destination country, e.g. to stay under the limits of its carriers
+        A send to a country at its limit waits for a free slot, sends to other countries proceed.
+        Countries missing from the table, and numbers of an unknown country, are not limited. A limit of 0 is ignored.
+        :param limits: Sends per second keyed by country. E.g: [(country_of("+32").unwrap(), 10)]
+        :return TigronSms: Returns the client with the limits applied
+    */
+    pub fn with_country_rate_limits(mut self, limits: HashMap<CountryCode, u32>) -> Self {
+        self.country_rate_limits = Some(Arc::new(CountryRateLimiter {
+            limits: limits.into_iter().filter(|(_, limit)| *limit > 0).collect(),
+            next_slot: Mutex::new(HashMap::new()),
+        }));
+        self
+    }
+
+    /*
+        Choose between waiting and failing when the per-country rate limiter is saturated
+        A country is saturated once 'queue_cap' sends are waiting for a slot. Defaults to Block without a cap.
+        :param behavior: RateLimitBehavior::Block to wait regardless, RateLimitBehavior::Reject to fail with TigronError::RateLimited
+        :param queue_cap: Sends that may wait per country before it is saturated. E.g: 100
+        :return TigronSms: Returns the client with the behavior applied
+    */
+    pub fn with_rate_limit_behavior(mut self, behavior: RateLimitBehavior, queue_cap: usize) -> Self {
+        self.rate_limit_behavior = behavior;
+        self.rate_limit_queue_cap = queue_cap;
+        self
+    }
+
+    /*
+        Retry calls that failed in transport, doubling the delay after each attempt up to 'with_max_backoff'
+        Sends rejected with a transient code (see 'with_retryability') are retried the same way.
+        Note: a send that reached the gateway before the connection dropped may be delivered twice.
+        :param max_retries: Attempts after the first one. E.g: 3
+        :param base_delay: Delay before the first retry. E.g: Duration::from_millis(200)
+        :return TigronSms: Returns the client with retries applied
+    */
+    pub fn with_retries(mut self, max_retries: u32, base_delay: Duration) -> Self {
+        self.retry.max_retries = max_retries;
+        self.retry.base_delay = base_delay;
+        self
+    }
+
+    /*
+        Cap the doubling delay between retries, so a late retry doesn't sleep for minutes
+        :param max_backoff: Longest delay before a retry. Defaults to 5 seconds
+        :return TigronSms: Returns the client with the cap applied
+    */
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.retry.max_backoff = max_backoff;
+        self
+    }
+
+    /*
+        Replace the table deciding which rejection codes of the gateway are retried
+        A rejection with a Transient code is sent again, within the retries set with 'with_retries' and the deadline of the call.
+        Codes missing from the table are Permanent. Codes are matched case-insensitively.
+        :param table: Retryability keyed by rejection code. E.g: [("throttled", Retryability::Transient)]
+        :return TigronSms: Returns the client with the table applied
+    */
+    pub fn with_retryability(mut self, table: HashMap<String, Retryability>) -> Self {
+        self.retryability = table
+            .into_iter()
+            .map(|(code, retryability)| (code.to_lowercase(), retryability))
+            .collect();
+        self
+    }
+
+    // Retryability of a rejection code, unknown codes are not retried
+    fn retryability(&self, code: &str) -> Retryability {
+        self.retryability
+            .get(&code.to_lowercase())
+            .copied()
+            .unwrap_or(Retryability::Permanent)
+    }
+
+    /*
+        Bound the total wall-clock time of a call, including all retries and backoff sleeps
+        Once exceeded the last error or transient rejection is returned immediately, or TigronError::DeadlineExceeded if there was none.
+        :param deadline: Maximum time of a call. E.g: Duration::from_secs(10)
+        :return TigronSms: Returns the client with the deadline applied
+    */
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.retry.deadline = Some(deadline);
+        self
+    }
+
+    /*
+        Sign every request with an HMAC over the SOAP-envelope, for when the gateway requires signed requests
+        :param key: Secret signing key shared with the gateway
+        :return TigronSms: Returns the client with signing enabled
+    */
+    #[cfg(feature = "signing")]
+    pub fn with_signing_key(mut self, key: std::vec::Vec<u8>) -> Self {
+        self.signing_key = Some(key);
+        self
+    }
+
+    /*
+        Select the gateway to talk to
+        :param environment: Gateway with its base URL and namespace. Defaults to Environment::Production
+        :return TigronSms: Returns the client with the environment applied
+    */
+    pub fn with_environment(mut self, environment: Environment) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    /*
+        Replace the suffix appended to the URL of a service
+        :param suffix: Suffix after the service name. Defaults to "?WSDL". E.g: "?wsdl", "/v2" or ""
+        :return TigronSms: Returns the client with the suffix applied
+    */
+    pub fn with_endpoint_suffix(mut self, suffix: &str) -> Self {
+        self.endpoint_suffix = suffix.to_string();
+        self
+    }
+
+    /*
+        Replace the Accept header sent with every call
+        :param accept: Media types the gateway may answer with. Defaults to "text/xml, application/soap+xml"
+        :return TigronSms: Returns the client with the header applied
+    */
+    pub fn with_accept(mut self, accept: &str) -> Self {
+        self.accept = accept.to_string();
+        self
+    }
+
+    /*
+        Set how long the looked up user_id is trusted before it is looked up again
+        :param ttl: Age after which the user_id is refreshed. Defaults to 1 hour
+        :return TigronSms: Returns the client with the TTL applied
+    */
+    pub fn with_user_id_ttl(mut self, ttl: Duration) -> Self {
+        self.user_id_ttl = ttl;
+        self
+    }
+
+    /*
+        Override how HTTP-statuses map to outcomes, for deployments that deviate from the default
+        Default mapping: any 2xx carries a body that decides the outcome (a SOAP-fault in a 200 is still a rejection),
+        an accepted send answered with 202 is Queued, any other status is TigronError::HttpStatus.
+        :param success: Statuses whose body is parsed. An empty list means any 2xx
+        :param queued: Statuses that turn an accepted send into SendOutcome::Queued. E.g: [202]
+        :return TigronSms: Returns the client with the mapping applied
+    */
+    pub fn with_status_mapping(
+        mut self,
+        success: std::vec::Vec<u16>,
+        queued: std::vec::Vec<u16>,
+    ) -> Self {
+        self.success_statuses = success;
+        self.queued_statuses = queued;
+        self
+    }
+
+    /*
+        Replace the source of time, e.g. with a ManualClock in tests
+        :param clock: Clock used by retries, backoff, the circuit breaker and the caches. Defaults to SystemClock
+        :return TigronSms: Returns the client with the clock applied
+    */
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /*
+        Attach the exchanged XML to errors, for support tickets
+        The request is attached with the password masked, the response as received, also for a failed HTTP-status
+        or a page that isn't XML. Errors of the connection itself carry the request only.
+        Only effective in debug builds: release builds always return lightweight errors.
+        :param capture: Whether to capture. Defaults to false
+        :return TigronSms: Returns the client with capturing applied
+    */
+    pub fn with_capture_on_error(mut self, capture: bool) -> Self {
+        self.capture_on_error = capture;
+        self
+    }
+
+    /*
+        Control which personal data stays readable in the XML attached to errors by 'with_capture_on_error'
+        Telephone numbers are personal data under the GDPR and the message body may be sensitive.
+        Masked numbers keep the country code and the first and last digits. E.g: +32.470***456
+        :param policy: What to keep readable. Defaults to masked numbers and no message body
+        :return TigronSms: Returns the client with the policy applied
+    */
+    pub fn with_pii_redaction(mut self, policy: PiiPolicy) -> Self {
+        self.pii_policy = policy;
+        self
+    }
+
+    /*
+        Call a hook after every attempt of a call, e.g. to push it to your own metrics
+        :param on_attempt: Hook receiving the command, the attempt number (from 1), the latency and the HTTP-status or error
+        :return TigronSms: Returns the client with the hook applied
+    */
+    pub fn with_on_attempt(mut self, on_attempt: OnAttempt) -> Self {
+        self.on_attempt = Some(on_attempt);
+        self
+    }
+
+    /*
+        Bind the command to Tigron's namespace with a prefix, for SOAP-stacks that refuse a default namespace
+        :param namespace_style: Style of the SOAP-body. Defaults to NamespaceStyle::Default
+        :return TigronSms: Returns the client with the style applied
+    */
+    pub fn with_namespace_style(mut self, namespace_style: NamespaceStyle) -> Self {
+        self.namespace_style = namespace_style;
+        self
+    }
+
+    /*
+        Route the messages of a batch that failed for good to a single place, e.g. to persist or alert on them
+        Called for rejected messages, invalid ones and, once retries are exhausted, every message of a failed request.
+        The results of the batch are returned as before, errors passed to the hook are returned as TigronError::Shared.
+        :param dead_letter: Hook receiving the request and its final error
+        :return TigronSms: Returns the client with the hook applied
+    */
+    pub fn with_dead_letter(mut self, dead_letter: DeadLetter) -> Self {
+        self.dead_letter = Some(dead_letter);
+        self
+    }
+
+    /*
+        Call a hook when a validity window closes, e.g. to alert on a cancel that failed
+        The sends started with 'SendOptions::with_validity' run their expiry in the background and keep no handle,
+        so this hook is the only place a failure of those expiries surfaces.
+        :param on_expiry: Hook receiving the outcome of the send and the number of cancelled parts or the error
+        :return TigronSms: Returns the client with the hook applied
+    */
+    pub fn with_on_expiry(mut self, on_expiry: OnExpiry) -> Self {
+        self.on_expiry = Some(on_expiry);
+        self
+    }
+
+    /*
+        Let the gateway split long messages, sending them whole with the 'concat' parameter set
+        Without it a message must fit a single segment (160 GSM-7 characters). With it, the gateway splits the text
+        into concatenated segments that the phone joins again. Each segment is billed, at 153 GSM-7 or 67 UCS-2
+        characters per segment instead of 160/70. Splitting the text yourself into separate messages fits 160/70
+        characters per message, which can cost a segment less, but the parts arrive as unrelated messages.
+        :param enabled: Whether the gateway concatenates. Defaults to false
+        :return TigronSms: Returns the client with concatenation applied
+    */
+    pub fn with_gateway_concatenation(mut self, enabled: bool) -> Self {
+        self.gateway_concatenation = enabled;
+        self
+    }
+
+    /*
+        Allow sending a message to its own source number
+        By default such a send fails with TigronError::SameFromTo, as it is almost always a bug.
+        :param allow: Whether to allow it. Defaults to false
+        :return TigronSms: Returns the client with the check applied
+    */
+    pub fn allow_self_send(mut self, allow: bool) -> Self {
+        self.allow_self_send = allow;
+        self
+    }
+
+    /*
+        Refuse a send whose source is not one of the sender numbers of the account
+        The gateway would reject it anyway; checked up front it fails with TigronError::UnregisteredSender
+        without a charge. The list is cached like the user_id. An account listing no senders is not checked.
+        :param enabled: Whether to check the source. Defaults to false
+        :return TigronSms: Returns the client with the check applied
+    */
+    pub fn with_sender_check(mut self, enabled: bool) -> Self {
+        self.check_sender = enabled;
+        self
+    }
+
+    /*
+        Shorten the URLs of every message before it is checked, counted and sent
+        A long tracking URL can push a message into a second segment; shortened it may fit a single one again.
+        URLs are the words starting with http:// or https://, trailing punctuation is left in place.
+        :param url_shortener: Service rewriting a URL into a short one
+        :return TigronSms: Returns the client with the shortener applied
+    */
+    pub fn with_url_shortener(mut self, url_shortener: Arc<dyn UrlShortener>) -> Self {
+        self.url_shortener = Some(url_shortener);
+        self
+    }
+
+    /*
+        Interpret the responses of the gateway with another parser
+        For when the gateway changes the shape of its responses, or for a compatible endpoint that answers differently.
+        :param response_parser: Parser of pairs, list-responses and faults. Defaults to XmlResponseParser
+        :return TigronSms: Returns the client with the parser applied
+    */
+    pub fn with_response_parser(mut self, response_parser: Arc<dyn ResponseParser>) -> Self {
+        self.response_parser = response_parser;
+        self
+    }
+
+    /*
+        Replace typographic characters of pasted text with GSM-7 equivalents before a message is sent
+        Curly quotes, en/em dashes, non-breaking spaces and the ellipsis silently force UCS-2, which fits 70
+        instead of 160 characters per segment. The message is only changed when that makes it fit GSM-7 entirely.
+        :param normalize: Whether to normalize. Defaults to false
+        :return TigronSms: Returns the client with normalization applied
+    */
+    pub fn with_normalize_text(mut self, normalize: bool) -> Self {
+        self.normalize_text = normalize;
+        self
+    }
+
+    /*
+        Drop leading and trailing whitespace of every message, e.g. stray newlines of CSV-input
+        Off by default since trailing spaces can be intentional. Numbers are always trimmed, see 'normalize_number'.
+        :param trim: Whether to trim. Defaults to false
+        :return TigronSms: Returns the client with trimming applied
+    */
+    pub fn with_trim_message(mut self, trim: bool) -> Self {
+        self.trim_message = trim;
+        self
+    }
+
+    /*
+        Put a fixed text before every message, e.g. the name of the sender
+        Added after normalization and before validation, so it counts towards the length and the segments.
+        :param prefix: Text put before the message as it is, include a separator. E.g: "ACME: "
+        :return TigronSms: Returns the client with the prefix applied
+    */
+    pub fn with_message_prefix(mut self, prefix: String) -> Self {
+        self.message_prefix = prefix;
+        self
+    }
+
+    /*
+        Put a fixed text after every message, e.g. the opt-out notice some markets require
+        Added after normalization and before validation, so it counts towards the length and the segments.
+        :param suffix: Text put after the message as it is, include a separator. E.g: "\nReply STOP to unsubscribe"
+        :return TigronSms: Returns the client with the suffix applied
+    */
+    pub fn with_message_suffix(mut self, suffix: String) -> Self {
+        self.message_suffix = suffix;
+        self
+    }
+
+    /*
+        Call a hook whenever text normalization changed a message, e.g. to log it
+        :param on_substitution: Hook receiving the replaced characters with their replacement
+        :return TigronSms: Returns the client with the hook applied
+    */
+    pub fn with_on_substitution(mut self, on_substitution: OnSubstitution) -> Self {
+        self.on_substitution = Some(on_substitution);
+        self
+    }
+
+    /*
+        Check every message against a content policy before it is sent, e.g. banned phrases
+        The filter sees the message as it would go out, prefix and suffix included. A refused message fails
+        with TigronError::ContentRejected and nothing is sent or charged.
+        :param content_filter: Policy returning Err(reason) for a message that may not be sent
+        :return TigronSms: Returns the client with the filter applied
+    */
+    pub fn with_content_filter(mut self, content_filter: ContentFilter) -> Self {
+        self.content_filter = Some(content_filter);
+        self
+    }
+
+    // Whether errors get the exchanged XML attached
+    fn capture_on_error(&self) -> bool {
+        self.capture_on_error && cfg!(debug_assertions)
+    }
+
+    // Attach the redacted envelope and the raw response to an error found in a response, when capturing
+    fn capture(&self, error: TigronError, envelope: String, response: &str) -> TigronError {
+        if !self.capture_on_error() {
+            return error;
+        }
+        TigronError::Captured {
+            error: Box::new(error),
+            request: envelope,
+            response: Some(redact_pii(response, self.pii_policy)),
+        }
+    }
+
+    /*
+        Shut the client down once nothing is in flight
+        Waits for the calls of this client and all its clones, retries included, and for the expiries started by
+        'SendOptions::with_validity' to finish. Calls started while waiting are waited for as well.
+        Messages still waiting in a 'BatchingSender' are not sent yet, so they are not waited for.
+        Simply dropping the client cancels nothing: futures of pending sends are held (and awaited) by the caller
+        and background expiries keep a clone. The HTTP connection pool is closed once the last clone is dropped.
+    */
+    pub async fn shutdown(self) {
+        self.activity.idle().await;
+    }
+
+    /*
+        Estimate the cost of sending a message to a number
+        :param to: Telephone number to send message to. Format: +xx.xxxxxxxxx
+        :param message: Content of message to send
+        :return Money: Returns the estimated cost, based on the price of the destination country and the segment count
+    */
+    pub fn estimate_cost(&self, to: &str, message: &str) -> Money {
+        self.money(self.estimate_amount(to, message))
+    }
+
+    // Estimated amount spent by this client so far
+    pub fn spent(&self) -> Money {
+        self.money(*self.spent.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    // Estimated cost of a message in the currency of the prices
+    fn estimate_amount(&self, to: &str, message: &str) -> Amount {
+        let country_code = to.trim_start_matches('+').split('.').next().unwrap_or("");
+        let price = match self.price_table.get(country_code) {
+            Some(price) => *price,
+            None => self.segment_price,
+        };
+
+        Amount::from(Self::segments(message) as u32) * price
+    }
+
+    fn money(&self, amount: Amount) -> Money {
+        Money {
+            amount,
+            currency: self.price_currency.clone(),
+        }
+    }
+
+    /*
+        Method to send a text-message
+        :param to: Telephone number to send message to. Format: +xx.xxxxxxxxx
+        :param from: Source of message. Format: +xx.xxxxxxxxx
+        :param message: Content of message to send
+        :return Result<SendOutcome, TigronError>: Returns the answer of the gateway. Returns an error if it could not be reached.
+        Returns as soon as the gateway answers, it never waits for the delivery of the message.
+    */
+    pub async fn send(
+        &self,
+        to: String,
+        from: String,
+        message: String,
+    ) -> Result<SendOutcome, TigronError> {
+        self.send_with_options(to, from, message, SendOptions::default())
+            .await
+    }
+
+    /*
+        Method to send a text-message given as raw bytes, e.g. a buffer passed over FFI
+        The bytes are never converted lossily: garbled input would otherwise go out with replacement characters.
+        :param to: Telephone number to send message to. Format: +xx.xxxxxxxxx
+        :param from: Source of message. Format: +xx.xxxxxxxxx
+        :param bytes: Content of message to send, encoded as UTF-8
+        :return Result<SendOutcome, TigronError>: Returns the answer of the gateway. Returns TigronError::InvalidUtf8 if the bytes are not valid UTF-8.
+    */
+    pub async fn send_bytes(
+        &self,
+        to: String,
+        from: String,
+        bytes: &[u8],
+    ) -> Result<SendOutcome, TigronError> {
+        let message = std::str::from_utf8(bytes).map_err(TigronError::InvalidUtf8)?;
+
+        self.send(to, from, message.to_string()).await
+    }
+
+    /*
+        Method to send a text-message from code without an async runtime, e.g. a script or a synchronous service
+        The client starts its own single-threaded runtime on first use and shares it with its clones, blocking sends
+        of the same client run one at a time. Calling it inside a tokio runtime would panic, so that is refused.
+        :param to: Telephone number to send message to. Format: +xx.xxxxxxxxx
+        :param from: Source of message. Format: +xx.xxxxxxxxx
+        :param message: Content of message to send
+        :return Result<SendOutcome, TigronError>: Returns the answer of the gateway. Returns TigronError::Config when called inside a runtime, await 'send' there instead.
+    */
+    pub fn send_blocking(
+        &self,
+        to: String,
+        from: String,
+        message: String,
+    ) -> Result<SendOutcome, TigronError> {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(TigronError::Config(
+                "send_blocking was called inside a tokio runtime, await 'send' instead.".to_string(),
+            ));
+        }
+
+        let runtime = self.blocking_runtime.get_or_try_init(|| {
+            tokio::runtime::Builder::new()
+                .basic_scheduler()
+                .enable_all()
+                .build()
+                .map(Mutex::new)
+                .map_err(|e| TigronError::Config(format!("Could not start a runtime: {}", e)))
+        })?;
+        let mut runtime = runtime.lock().unwrap_or_else(|e| e.into_inner());
+
+        runtime.block_on(self.send(to, from, message))
+    }
+
+    /*
+        Method to send a text-message and report where the time went
+        :param to: Telephone number to send message to. Format: +xx.xxxxxxxxx
+        :param from: Source of message. Format: +xx.xxxxxxxxx
+        :param message: Content of message to send
+        :return Result<(SendOutcome, Timing), TigronError>: Returns the answer of the gateway with the time of the preparation, of the user_id lookup (near zero when cached) and of the SOAP-exchange
+    */
+    pub async fn send_timed(
+        &self,
+        to: String,
+        from: String,
+        message: String,
+    ) -> Result<(SendOutcome, Timing), TigronError> {
+        self.send_with_timing(to, from, message, SendOptions::default())
+            .await
+    }
+
+    /*
+        Method to send the content of a file as text-message
+        The file is read as UTF-8 and a single trailing newline is dropped, the content is then sent like with 'send'.
+        :param to: Telephone number to send message to. Format: +xx.xxxxxxxxx
+        :param from: Source of message. Format: +xx.xxxxxxxxx
+        :param path: File holding the content of the message. E.g: "message.txt"
+        :return Result<SendOutcome, TigronError>: Returns the answer of the gateway. Returns TigronError::Io if the file can't be read.
+    */
+    pub async fn send_from_file<P: AsRef<std::path::Path>>(
+        &self,
+        to: String,
+        from: String,
+        path: P,
+    ) -> Result<SendOutcome, TigronError> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .map_err(TigronError::Io)?;
+        let message = content
+            .strip_suffix('\n')
+            .map(|message| message.strip_suffix('\r').unwrap_or(message))
+            .unwrap_or(&content);
+
+        self.send(to, from, message.to_string()).await
+    }
+
+    /*
+        Method to send a text-message with per-send options
+        :param to: Telephone number to send message to. Format: +xx.xxxxxxxxx
+        :param from: Source of message. Format: +xx.xxxxxxxxx
+        :param message: Content of message to send
+        :param options: Overrides for this message only. E.g: SendOptions::default().with_dcs(8)
+        :return Result<SendOutcome, TigronError>: Returns the answer of the gateway. Returns an error if it could not be reached.
+    */
+    pub async fn send_with_options(
+        &self,
+        to: String,
+        from: String,
+        message: String,
+        options: SendOptions,
+    ) -> Result<SendOutcome, TigronError> {
+        self.send_with_timing(to, from, message, options)
+            .await
+            .map(|(outcome, _)| outcome)
+    }
+
+    /*
+        Method to send a text-message with per-send options and report where the time went
+        :param to: Telephone number to send message to. Format: +xx.xxxxxxxxx
+        :param from: Source of message. Format: +xx.xxxxxxxxx
+        :param message: Content of message to send
+        :param options: Overrides for this message only. E.g: SendOptions::default().with_dcs(8)
+        :return Result<(SendOutcome, Timing), TigronError>: Returns the answer of the gateway with its timing
+    */
+    async fn send_with_timing(
+        &self,
+        to: String,
+        from: String,
+        message: String,
+        options: SendOptions,
+    ) -> Result<(SendOutcome, Timing), TigronError> {
+        let started = self.clock.now();
+        let to = Self::normalize_number(&to)?;
+        let from = Self::normalize_number(&from)?;
+        self.check_self_send(&to, &from)?;
+        self.check_sender(&from).await?;
+        let message = self.prepare_message(&message).await?;
+        self.validate(&message, &options).await?;
+
+        if let Some(dedup) = &self.dedup {
+            if let Some(outcome) = dedup.lookup(&to, &message, self.clock.now()) {
+                let prepared = self.clock.now().saturating_duration_since(started);
+                let timing = Timing {
+                    preparation: prepared,
+                    user_id_lookup: Duration::from_secs(0),
+                    send_call: Duration::from_secs(0),
+                    total: prepared,
+                };
+                return Ok((outcome, timing));
+            }
+        }
+
+        let soap_client = self.soap_client()?;
+
+        let prepared = self.clock.now();
+        let user_id = &*self.get_user_id().await?;
+        if user_id.is_empty() {
+            return Err(TigronError::UserNotFound);
+        }
+        let looked_up = self.clock.now();
+
+        self.wait_for_country_slot(&[&*to]).await?;
+
+        let cost = self.estimate_amount(&to, &message);
+        self.reserve_spend(cost)?;
+
+        let mut sms_params = vec![
+            ("user_id", user_id),
+            ("from", &*from),
+            ("to", &*to),
+            ("message", &*message),
+            ("type", options.message_type.as_param()),
+        ];
+
+        // Without an override the gateway detects GSM-7/UCS-2 itself
+        let dcs = options.dcs.map(|dcs| dcs.to_string());
+        if let Some(dcs) = &dcs {
+            sms_params.push(("dcs", dcs));
+        }
+        if let Some(route) = &options.route {
+            sms_params.push(("route", route));
+        }
+        if self.gateway_concatenation {
+            sms_params.push(("concat", flag(true)));
+        }
+
+        // Rejections with a transient code are sent again, within the retries and the deadline of the call
+        let resend = |response: &SoapResponse| {
+            SendOutcome::rejection_code(&self.response_parser.pairs(&response.body))
+                .map_or(false, |code| self.retryability(&code) == Retryability::Transient)
+        };
+        let exchange_started = self.clock.now();
+        let SoapResponse {
+            body: response,
+            request_id,
+            status,
+            envelope,
+        } = match soap_client
+            .call_resending("sms", "send_sms", Some(sms_params), Some(&resend))
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                self.release_spend(cost);
+                return Err(e);
+            }
+        };
+        let exchanged = self.clock.now();
+        let response_items = match self.parse_response(&response) {
+            Ok(response_items) => response_items,
+            Err(e) => {
+                self.release_spend(cost);
+                return Err(self.capture(e, envelope, &response));
+            }
+        };
+
+        let outcome = SendOutcome::from_response(&response_items, &request_id)
+            .await
+            .map(|outcome| outcome.apply_http_status(status, &self.queued_statuses))
+            .map_err(|e| self.capture(e, envelope, &response));
+
+        // Only a message the gateway took is charged, a rejected or unreadable send is given back
+        if !matches!(outcome, Ok(SendOutcome::Accepted { .. }) | Ok(SendOutcome::Queued { .. })) {
+            self.release_spend(cost);
+        }
+        if let (Some(dedup), Ok(outcome)) = (&self.dedup, &outcome) {
+            dedup.record(&to, &message, outcome, self.clock.now());
+        }
+        if let (Some(validity), Ok(outcome)) = (options.validity, &outcome) {
+            self.expire_after(outcome, validity);
+        }
+
+        outcome.map(|outcome| {
+            let timing = Timing {
+                preparation: prepared.saturating_duration_since(started),
+                user_id_lookup: looked_up.saturating_duration_since(prepared),
+                send_call: exchanged.saturating_duration_since(exchange_started),
+                total: self.clock.now().saturating_duration_since(started),
+            };
+            (outcome, timing)
+        })
+    }
+
+    /*
+        Check a text-message without sending or charging it
+        Tigron's API has no validate-only command, so this is done client-side: the numbers, the encoding and the segment count are checked.
+        :param to: Telephone number to send message to. Format: +xx.xxxxxxxxx
+        :param from: Source of message. Format: +xx.xxxxxxxxx
+        :param message: Content of message to send
+        :return Result<ValidationReport, TigronError>: Returns the report. Returns an error if the numbers or the message are invalid.
+    */
+    pub async fn validate_send(
+        &self,
+        to: String,
+        from: String,
+        message: String,
+    ) -> Result<ValidationReport, TigronError> {
+        let to = Self::normalize_number(&to)?;
+        let from = Self::normalize_number(&from)?;
+        self.check_self_send(&to, &from)?;
+        let message = self.prepare_message(&message).await?;
+        self.validate(&message, &SendOptions::default()).await?;
+
+        let country = country_of(&to);
+
+        Ok(ValidationReport {
+            routable: country.is_some(),
+            country,
+            segments: Self::segments(&message),
+            estimated_cost: self.estimate_cost(&to, &message),
+        })
+    }
+
+    /*
+        Check the setup of this client step by step, e.g. when onboarding or for a support ticket
+        Runs the credential check, the user_id lookup, the balance check and a dry-run 'send_sms' envelope.
+        Never sends a text-message. The user_id is looked up fresh, bypassing the cache.
+        :return DiagnosisReport: Returns every step with its outcome and time. Print it for a readable summary.
+    */
+    pub async fn diagnose(&self) -> DiagnosisReport {
+        let mut steps = std::vec::Vec::new();
+        let mut step = |name: &'static str, started: Instant, status: DiagnosisStatus| {
+            steps.push(DiagnosisStep {
+                name,
+                status,
+                elapsed: self.clock.now().saturating_duration_since(started),
+            })
+        };
+
+        let started = self.clock.now();
+        let soap_client = self.soap_client();
+        step(
+            "credentials",
+            started,
+            match &soap_client {
+                Ok(_) => DiagnosisStatus::Passed(format!("username '{}'", self.username())),
+                Err(e) => DiagnosisStatus::Failed(e.to_string()),
+            },
+        );
+
+        // The balance is read from the same 'info' response as the user_id
+        let started = self.clock.now();
+        let info = match &soap_client {
+            Ok(soap_client) => Some(match soap_client.call("user", "info", None).await {
+                Ok(response) => match self.parse_response(&response) {
+                    Ok(items) => Ok(AccountInfo::from_items(&items).await),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            }),
+            Err(_) => None,
+        };
+        let user_id = match &info {
+            Some(Ok(account_info)) => account_info.id.to_string(),
+            _ => String::new(),
+        };
+        step(
+            "user_id",
+            started,
+            match &info {
+                Some(Ok(_)) if !user_id.is_empty() => DiagnosisStatus::Passed(user_id.to_string()),
+                Some(Ok(_)) => DiagnosisStatus::Failed(TigronError::UserNotFound.to_string()),
+                Some(Err(e)) => DiagnosisStatus::Failed(e.to_string()),
+                None => DiagnosisStatus::Skipped,
+            },
+        );
+
+        let started = self.clock.now();
+        step(
+            "balance",
+            started,
+            match &info {
+                Some(Ok(account_info)) if !user_id.is_empty() => match account_info.balance {
+                    Some(balance) => DiagnosisStatus::Passed(balance.to_string()),
+                    None => DiagnosisStatus::Failed(
+                        "Account info does not report a balance.".to_string(),
+                    ),
+                },
+                _ => DiagnosisStatus::Skipped,
+            },
+        );
+
+        let started = self.clock.now();
+        let status = if user_id.is_empty() {
+            DiagnosisStatus::Skipped
+        } else {
+            let params = vec![
+                ("user_id", &*user_id),
+                ("from", "+32.470000000"),
+                ("to", "+32.470000001"),
+                ("message", "Diagnosis"),
+                ("type", MessageType::default().as_param()),
+            ];
+            match self.envelope("send_sms", params).await {
+                Ok(envelope) => DiagnosisStatus::Passed(format!("{} bytes", envelope.len())),
+                Err(e) => DiagnosisStatus::Failed(e.to_string()),
+            }
+        };
+        step("envelope", started, status);
+
+        DiagnosisReport { steps }
+    }
+
+    /*
+        Method to send several independent text-messages in one pass
+        Requests sharing the same source and content are sent in one request, the user_id is fetched once.
+        :param requests: Text-messages to send. E.g: [SendRequest { to, from, message }]
+        :param options: Overrides applied to every message
+        :return Result<Vec<Result<SendOutcome, TigronError>>, TigronError>: Returns one result per request, in the same order. A failed request to the gateway fails the results of its own messages. Returns an error if the user_id can't be looked up.
+    */
+    pub async fn send_batch(
+        &self,
+        requests: std::vec::Vec<SendRequest>,
+        options: SendOptions,
+    ) -> Result<std::vec::Vec<Result<SendOutcome, TigronError>>, TigronError> {
+        let mut results: std::vec::Vec<Option<Result<SendOutcome, TigronError>>> =
+            requests.iter().map(|_| None).collect();
+
+        // Group the valid requests by (from, message), invalid ones fail in their own slot
+        type Group = (String, String, std::vec::Vec<(usize, String)>);
+        let mut groups: std::vec::Vec<Group> = std::vec::Vec::new();
+        for (i, request) in requests.iter().enumerate() {
+            let normalized = match (
+                Self::normalize_number(&request.to),
+                Self::normalize_number(&request.from),
+            ) {
+                (Ok(to), Ok(from)) => match self.check_self_send(&to, &from) {
+                    Ok(()) => match self.prepare_message(&request.message).await {
+                        Ok(message) => self
+                            .validate(&message, &options)
+                            .await
+                            .map(|_| (to, from, message)),
+                        Err(e) => Err(e),
+                    },
+                    Err(e) => Err(e),
+                },
+                (Err(e), _) | (_, Err(e)) => Err(e),
+            };
+            let (to, from, message) = match normalized {
+                Ok(normalized) => normalized,
+                Err(e) => {
+                    results[i] = Some(Err(e));
+                    continue;
+                }
+            };
+            if let Err(e) = self.check_sender(&from).await {
+                results[i] = Some(Err(e));
+                continue;
+            }
+
+            match groups
+                .iter_mut()
+                .find(|(f, m, _)| *f == from && *m == message)
+            {
+                Some((_, _, recipients)) => recipients.push((i, to)),
+                None => groups.push((from, message, vec![(i, to)])),
+            }
+        }
+
+        if !groups.is_empty() {
+            let user_id = self.get_user_id().await?;
+            if user_id.is_empty() {
+                return Err(TigronError::UserNotFound);
+            }
+
+            for (from, message, recipients) in groups.iter() {
+                let numbers: std::vec::Vec<&str> =
+                    recipients.iter().map(|(_, to)| &**to).collect();
+                let outcomes = match self
+                    .send_group(&user_id, from, message, &numbers, &options)
+                    .await
+                {
+                    Ok(outcomes) => outcomes,
+                    Err(e) => {
+                        let e = Arc::new(e);
+                        for (i, _) in recipients.iter() {
+                            results[*i] = Some(Err(TigronError::Shared(e.clone())));
+                        }
+                        continue;
+                    }
+                };
+                for ((i, _), outcome) in recipients.iter().zip(outcomes) {
+                    results[*i] = Some(outcome);
+                }
+            }
+        }
+
+        Ok(results
+            .into_iter()
+            .zip(requests.iter())
+            .map(|(result, request)| {
+                let result = result.unwrap_or_else(|| {
+                    Err(TigronError::Parse("No result for this request.".to_string()))
+                });
+                if let (Some(validity), Ok(outcome)) = (options.validity, &result) {
+                    self.expire_after(outcome, validity);
+                }
+                self.dead_letter(request, result)
+            })
+            .collect())
+    }
+
+    // Pass a failed or rejected message of a batch to the dead-letter hook, if one is set
+    fn dead_letter(
+        &self,
+        request: &SendRequest,
+        result: Result<SendOutcome, TigronError>,
+    ) -> Result<SendOutcome, TigronError> {
+        let dead_letter = match &self.dead_letter {
+            Some(dead_letter) => dead_letter,
+            None => return result,
+        };
+
+        match result {
+            Ok(SendOutcome::Rejected {
+                code,
+                reason,
+                request_id,
+            }) => {
+                dead_letter(FailedSend {
+                    request: request.clone(),
+                    error: Arc::new(TigronError::SendRejected {
+                        code: code.to_string(),
+                        reason: reason.to_string(),
+                    }),
+                });
+                Ok(SendOutcome::Rejected {
+                    code,
+                    reason,
+                    request_id,
+                })
+            }
+            Err(e) => {
+                let e = match e {
+                    TigronError::Shared(e) => e,
+                    e => Arc::new(e),
+                };
+                dead_letter(FailedSend {
+                    request: request.clone(),
+                    error: e.clone(),
+                });
+                Err(TigronError::Shared(e))
+            }
+            outcome => outcome,
+        }
+    }
+
+    /*
+        Method to send the same text-message to several recipients in one request
+        This is the bulk form of 'send_sms': a single envelope with a repeated 'to' element, answered with one result per recipient.
+        :param recipients: Telephone numbers to send message to. Format: +xx.xxxxxxxxx
+        :param from: Source of message. Format: +xx.xxxxxxxxx
+        :param message: Content of message to send
+        :param options: Overrides applied to every recipient
+        :return Result<Vec<Result<SendOutcome, TigronError>>, TigronError>: Returns one result per recipient, in the same order. A failed request fails every recipient with TigronError::Shared.
+    */
+    pub async fn send_to_many(
+        &self,
+        recipients: std::vec::Vec<String>,
+        from: String,
+        message: String,
+        options: SendOptions,
+    ) -> Result<std::vec::Vec<Result<SendOutcome, TigronError>>, TigronError> {
+        let requests = recipients
+            .into_iter()
+            .map(|to| SendRequest {
+                to,
+                from: from.to_string(),
+                message: message.to_string(),
+            })
+            .collect();
+
+        self.send_batch(requests, options).await
+    }
+
+    /*
+        Send one message to the recipients read from a list, e.g. a large file, without loading it in memory
+        Line format: one number per line, +xx.xxxxxxxxx or E.164. Surrounding whitespace is trimmed, blank lines
+        and lines starting with '#' are skipped. Recipients are read and sent in batches, like 'send_to_many'.
+        Errors are per line: an invalid number fails its own line only, a batch failing as a whole fails each of
+        its lines with TigronError::Shared. A read error (e.g. a line that isn't UTF-8) is yielded and ends the stream.
+        :param reader: Source of the list. E.g: tokio::io::BufReader::new(tokio::fs::File::open("recipients.txt").await?)
+        :param from: Source of message. Format: +xx.xxxxxxxxx
+        :param message: Content of message to send
+        :return Stream<Item = (usize, Result<SendOutcome, TigronError>)>: Returns the line number (from 1) and the result of every recipient, in order
+    */
+    pub fn send_from_reader<'a, R>(
+        &'a self,
+        reader: R,
+        from: String,
+        message: String,
+    ) -> impl futures::Stream<Item = (usize, Result<SendOutcome, TigronError>)> + 'a
+    where
+        R: tokio::io::AsyncBufRead + Unpin + 'a,
+    {
+        use tokio::io::AsyncBufReadExt;
+
+        let buffered: std::collections::VecDeque<(usize, Result<SendOutcome, TigronError>)> =
+            std::collections::VecDeque::new();
+
+        // 'read' is the number of lines read so far, None once the reader is exhausted
+        futures::stream::unfold(
+            (reader, Some(0usize), buffered, from, message),
+            move |(mut reader, mut read, mut buffered, from, message)| async move {
+                loop {
+                    if let Some(result) = buffered.pop_front() {
+                        return Some((result, (reader, read, buffered, from, message)));
+                    }
+
+                    let mut line_number = read?;
+                    let mut recipients: std::vec::Vec<(usize, String)> = std::vec::Vec::new();
+                    let mut read_error = None;
+                    let mut exhausted = false;
+                    while recipients.len() < READER_BATCH_SIZE {
+                        let mut line = String::new();
+                        match reader.read_line(&mut line).await {
+                            Ok(0) => {
+                                exhausted = true;
+                                break;
+                            }
+                            Ok(_) => {
+                                line_number += 1;
+                                let number = line.trim();
+                                if !number.is_empty() && !number.starts_with('#') {
+                                    recipients.push((line_number, number.to_string()));
+                                }
+                            }
+                            Err(e) => {
+                                read_error = Some((line_number + 1, Err(TigronError::Io(e))));
+                                exhausted = true;
+                                break;
+                            }
+                        }
+                    }
+
+                    if !recipients.is_empty() {
+                        let numbers = recipients.iter().map(|(_, to)| to.to_string()).collect();
+                        let line_numbers = recipients.iter().map(|(line_number, _)| *line_number);
+                        let outcomes = self
+                            .send_to_many(
+                                numbers,
+                                from.to_string(),
+                                message.to_string(),
+                                SendOptions::default(),
+                            )
+                            .await;
+                        match outcomes {
+                            Ok(results) => buffered.extend(line_numbers.zip(results)),
+                            Err(e) => {
+                                let e = Arc::new(e);
+                                buffered.extend(line_numbers.map(|line_number| {
+                                    (line_number, Err(TigronError::Shared(e.clone())))
+                                }));
+                            }
+                        }
+                    }
+                    buffered.extend(read_error);
+
+                    read = if exhausted { None } else { Some(line_number) };
+                }
+            },
+        )
+    }
+
+    /*
+        Send one message to several validated recipients in a single 'send_sms' call
+        :param user_id: Id of the Tigron user
+        :param from: Source of message. Format: +xx.xxxxxxxxx
+        :param message: Content of message to send
+        :param recipients: Normalized telephone numbers to send message to
+        :param options: Overrides applied to every recipient
+        :return Result<Vec<Result<SendOutcome, TigronError>>, TigronError>: Returns one result per recipient, in the same order
+    */
+    async fn send_group(
+        &self,
+        user_id: &str,
+        from: &str,
+        message: &str,
+        recipients: &[&str],
+        options: &SendOptions,
+    ) -> Result<std::vec::Vec<Result<SendOutcome, TigronError>>, TigronError> {
+        let soap_client = self.soap_client()?;
+
+        self.wait_for_country_slot(recipients).await?;
+
+        let cost = recipients
+            .iter()
+            .map(|to| self.estimate_amount(to, message))
+            .sum();
+        self.reserve_spend(cost)?;
+
+        let mut sms_params = vec![
+            ("user_id", user_id),
+            ("from", from),
+            ("message", message),
+            ("type", options.message_type.as_param()),
+        ];
+        for to in recipients.iter() {
+            sms_params.push(("to", to));
+        }
+
+        let dcs = options.dcs.map(|dcs| dcs.to_string());
+        if let Some(dcs) = &dcs {
+            sms_params.push(("dcs", dcs));
+        }
+        if let Some(route) = &options.route {
+            sms_params.push(("route", route));
+        }
+        if self.gateway_concatenation {
+            sms_params.push(("concat", flag(true)));
+        }
+
+        let SoapResponse {
+            body: response,
+            request_id,
+            status,
+            envelope,
+        } = match soap_client
+            .call_traced("sms", "send_sms", Some(sms_params))
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                self.release_spend(cost);
+                return Err(e);
+            }
+        };
+        let records = match self.parse_records(&response) {
+            Ok(records) => records,
+            Err(e) => {
+                self.release_spend(cost);
+                return Err(self.capture(e, envelope, &response));
+            }
+        };
+
+        // Records carrying a 'to' are matched on number, the others by position
+        let mut results = std::vec::Vec::new();
+        for (i, to) in recipients.iter().enumerate() {
+            let record = match records
+                .iter()
+                .find(|record| record.iter().any(|(k, v)| k == "to" && v == to))
+                .or_else(|| records.get(i))
+            {
+                Some(record) => record,
+                None => {
+                    self.release_spend(self.estimate_amount(to, message));
+                    results.push(Err(TigronError::Parse(format!(
+                        "Response of send_sms does not contain a result for {}.",
+                        to
+                    ))));
+                    continue;
+                }
+            };
+
+            let outcome = SendOutcome::from_response(record, &request_id)
+                .await
+                .map(|outcome| outcome.apply_http_status(status, &self.queued_statuses));
+            let taken = matches!(
+                outcome,
+                Ok(SendOutcome::Accepted { .. }) | Ok(SendOutcome::Queued { .. })
+            );
+            if !taken {
+                self.release_spend(self.estimate_amount(to, message));
+            }
+            results.push(match outcome {
+                Ok(SendOutcome::Rejected { code, reason, .. }) => {
+                    Err(TigronError::SendRejected { code, reason })
+                }
+                outcome => outcome,
+            });
+        }
+
+        Ok(results)
+    }
+
+    /*
+        Validate a telephone number and bring it in the format expected by the gateway
+        Surrounding whitespace (e.g. a stray space or newline of CSV-input) is trimmed first.
+        Exactly one dot ends up after the country code, however many the input had. A dot that doesn't sit
+        between two digits is refused. E.g: +32470123456, +32.470123456 and +3.2.470123456 are all +32.470123456
+        With the 'validate' feature the number is fully checked against the numbering plan of its country.
+        :param number: Telephone number. Format: +xx.xxxxxxxxx or E.164 (+xxxxxxxxxxx)
+        :return Result<String, TigronError>: Returns the number as +xx.xxxxxxxxx. Returns TigronError::InvalidNumber otherwise.
+    */
+    #[cfg(not(feature = "validate"))]
+    pub fn normalize_number(number: &str) -> Result<String, TigronError> {
+        let number = number.trim();
+        let e164 = strip_dots(number)?;
+
+        // The dot goes after the known country code; for an unknown one a single dot of the caller is trusted
+        let number = &*match to_tigron_format(&e164) {
+            Ok(dotted) => dotted,
+            Err(_) if number.matches('.').count() == 1 => number.to_string(),
+            Err(e) => return Err(e),
+        };
+
+        let phone_number_regex = Regex::new(r"^\+[0-9]{1,3}\.[0-9]{7,12}$")
+            .map_err(|e| TigronError::Parse(e.to_string()))?;
+        if !phone_number_regex.is_match(number) {
+            return Err(TigronError::InvalidNumber(
+                "Phone numbers must be in the format: +xx.yyyyyyyyy where xx is the country code."
+                    .to_string(),
+            ));
+        }
+
+        Ok(number.to_string())
+    }
+
+    #[cfg(feature = "validate")]
+    pub fn normalize_number(number: &str) -> Result<String, TigronError> {
+        let number = number.trim();
+        let e164 = strip_dots(number)?;
+        let parsed = phonenumber::parse(None, &e164)
+            .map_err(|e| TigronError::InvalidNumber(format!("{}: {}", number, e)))?;
+        if !phonenumber::is_valid(&parsed) {
+            return Err(TigronError::InvalidNumber(format!(
+                "{} is not a valid number in its country",
+                number
+            )));
+        }
+
+        Ok(format!(
+            "+{code}.{zeros}{national}",
+            code = parsed.code().value(),
+            zeros = "0".repeat(parsed.national().zeros() as usize),
+            national = parsed.national().value()
+        ))
+    }
+
+    /*
+        Validate the recipients of a campaign up front, without contacting the gateway
+        :param recipients: Telephone numbers. Format: +xx.xxxxxxxxx or E.164 (+xxxxxxxxxxx)
+        :return Vec<Result<String, TigronError>>: Returns the normalized number or the error per recipient, in the same order
+    */
+    pub fn validate_batch(recipients: &[String]) -> std::vec::Vec<Result<String, TigronError>> {
+        recipients
+            .iter()
+            .map(|recipient| Self::normalize_number(recipient))
+            .collect()
+    }
+
+    /*
+        Validate the input of a send before contacting the gateway
+        :param message: Content of message to send
+        :param options: Overrides for the message
+        :return Result<(), TigronError>: Returns Ok() if the input is valid. Returns TigronError::InvalidInput otherwise.
+    */
+    async fn validate(&self, message: &str, options: &SendOptions) -> Result<(), TigronError> {
+        if message.len() == 0 {
+            return Err(TigronError::InvalidInput(
+                "Message cannot be empty.".to_string(),
+            ));
+        }
+        if !self.gateway_concatenation && message.len() > 160 {
+            return Err(TigronError::InvalidInput(
+                "Message cannot be more than 160 characters".to_string(),
+            ));
+        }
+        if Self::segments(message) > MAX_CONCATENATED_SEGMENTS {
+            return Err(TigronError::InvalidInput(format!(
+                "Message cannot be more than {} segments",
+                MAX_CONCATENATED_SEGMENTS
+            )));
+        }
+        if let Some(dcs) = options.dcs {
+            if !SendOptions::is_valid_dcs(dcs) {
+                return Err(TigronError::InvalidInput(
+                    "Data coding scheme is not a valid GSM 03.38 value.".to_string(),
+                ));
+            }
+        }
+        if let Some(route) = &options.route {
+            if route.trim().is_empty() {
+                return Err(TigronError::InvalidInput(
+                    "Route cannot be empty.".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Pairs of a response with the configured parser, a fault reported in it is returned as the error
+    fn parse_response(&self, xml: &str) -> Result<std::vec::Vec<(String, String)>, TigronError> {
+        match self.response_parser.fault(xml) {
+            Some(fault) => Err(fault),
+            None => Ok(self.response_parser.pairs(xml)),
+        }
+    }
+
+    // Records of a list-response with the configured parser, a fault reported in it is returned as the error
+    fn parse_records(
+        &self,
+        xml: &str,
+    ) -> Result<std::vec::Vec<std::vec::Vec<(String, String)>>, TigronError> {
+        match self.response_parser.fault(xml) {
+            Some(fault) => Err(fault),
+            None => Ok(self.response_parser.records(xml)),
+        }
+    }
+
+    // One map per record of a list-response, e.g. the sender numbers of an account
+    fn parse_maps(&self, xml: &str) -> Result<std::vec::Vec<HashMap<String, String>>, TigronError> {
+        Ok(self
+            .parse_records(xml)?
+            .into_iter()
+            .map(|record| record.into_iter().collect())
+            .collect())
+    }
+
+    // Trim, normalize, frame, shorten and filter a message, before it is checked, counted and sent
+    async fn prepare_message(&self, message: &str) -> Result<String, TigronError> {
+        let message = if self.trim_message {
+            message.trim()
+        } else {
+            message
+        };
+        let message = if self.normalize_text {
+            self.normalize(message)
+        } else {
+            message.to_string()
+        };
+        let message = format!("{}{}{}", self.message_prefix, message, self.message_suffix);
+        let message = self.shorten_urls(&message).await?;
+
+        if let Some(content_filter) = &self.content_filter {
+            content_filter(&message).map_err(TigronError::ContentRejected)?;
+        }
+
+        Ok(message)
+    }
+
+    /*
+        Replace typographic characters with their GSM-7 equivalent, if the whole message then fits GSM-7
+        :param message: Content of message to send
+        :return String: Returns the normalized message, or the message unchanged if it would still need UCS-2
+    */
+    fn normalize(&self, message: &str) -> String {
+        let mut normalized = String::new();
+        let mut substitutions = std::vec::Vec::new();
+        for c in message.chars() {
+            match GSM7_SUBSTITUTIONS.iter().find(|(from, _)| *from == c) {
+                Some((from, to)) => {
+                    normalized.push_str(to);
+                    substitutions.push((*from, *to));
+                }
+                None => normalized.push(c),
+            }
+        }
+
+        if substitutions.is_empty() || !normalized.chars().all(|c| GSM7_CHARACTERS.contains(c)) {
+            return message.to_string();
+        }
+        if let Some(on_substitution) = &self.on_substitution {
+            on_substitution(&substitutions);
+        }
+
+        normalized
+    }
+
+    /*
+        Rewrite the URLs of a message with the URL shortener, if one is set
+        :param message: Content of message to send
+        :return Result<String, TigronError>: Returns the message with its URLs shortened. Returns the error of the shortener if it fails.
+    */
+    async fn shorten_urls(&self, message: &str) -> Result<String, TigronError> {
+        let url_shortener = match &self.url_shortener {
+            Some(url_shortener) => url_shortener,
+            None => return Ok(message.to_string()),
+        };
+
+        // Splitting on whitespace but keeping it, so line breaks and double spaces survive
+        let mut shortened = String::new();
+        for word in message.split_inclusive(char::is_whitespace) {
+            let text = word.trim_end_matches(char::is_whitespace);
+            let url = text.trim_end_matches(['.', ',', ';', ':', '!', '?', ')']);
+            if url.starts_with("http://") || url.starts_with("https://") {
+                shortened.push_str(&url_shortener.shorten(url).await?);
+                shortened.push_str(&word[url.len()..]);
+            } else {
+                shortened.push_str(word);
+            }
+        }
+
+        Ok(shortened)
+    }
+
+    // Sending to the source number is almost always a bug in how the numbers were filled in
+    // Compared after normalization, so formatting differences don't hide it
+    fn check_self_send(&self, to: &str, from: &str) -> Result<(), TigronError> {
+        if !self.allow_self_send && from_tigron_format(to) == from_tigron_format(from) {
+            return Err(TigronError::SameFromTo);
+        }
+
+        Ok(())
+    }
+
+    // Only a source listed among the sender numbers of the account is sent from, when the check is enabled
+    async fn check_sender(&self, from: &str) -> Result<(), TigronError> {
+        if !self.check_sender {
+            return Ok(());
+        }
+
+        let sender_numbers = self.sender_numbers().await?;
+        if sender_numbers.is_empty()
+            || sender_numbers
+                .iter()
+                .any(|sender| from_tigron_format(sender) == from_tigron_format(from))
+        {
+            return Ok(());
+        }
+
+        Err(TigronError::UnregisteredSender {
+            from: from.to_string(),
+        })
+    }
+
+    /*
+        Book the cost of a send against the spend cap
+        :param cost: Estimated cost of the send
+        :return Result<(), TigronError>: Returns Ok() if the cap allows it. Returns TigronError::SpendCapExceeded otherwise.
+    */
+    fn reserve_spend(&self, cost: Amount) -> Result<(), TigronError> {
+        let mut spent = self.spent.lock().unwrap_or_else(|e| e.into_inner());
+        match self.spend_cap {
+            Some(cap) if *spent + cost > cap => Err(TigronError::SpendCapExceeded {
+                cap: self.money(cap),
+                spent: self.money(*spent),
+            }),
+            _ => {
+                *spent += cost;
+                Ok(())
+            }
+        }
+    }
+
+    // Give back the cost of a send the gateway didn't take, e.g. a failed call or a rejection
+    fn release_spend(&self, cost: Amount) {
+        let mut spent = self.spent.lock().unwrap_or_else(|e| e.into_inner());
+        *spent -= cost;
+    }
+
+    /*
+        Count the segments a message is split into
+        :param message: Content of message to send
+        :return u64: Returns the number of segments. GSM-7 fits 160 characters (153 when concatenated), UCS-2 fits 70 (67).
+    */
+    fn segments(message: &str) -> u64 {
+        let gsm7 = message.chars().all(|c| GSM7_CHARACTERS.contains(c));
+        let length = if gsm7 {
+            message
+                .chars()
+                .map(|c| if GSM7_EXTENDED.contains(c) { 2 } else { 1 })
+                .sum::<u64>()
+        } else {
+            message.encode_utf16().count() as u64
+        };
+        let (single, concatenated) = if gsm7 { (160, 153) } else { (70, 67) };
+
+        if length <= single {
+            1
+        } else {
+            length.div_ceil(concatenated)
+        }
+    }
+
+    /*
+        Call a command of the API this client doesn't wrap
+        :param service: Service of API to execute a command on. E.g: "domain"
+        :param cmd: The command to execute. E.g: "get_list"
+        :param params: Parameters of the command, rendered with ParamValue and XML-escaped. E.g: [("user_id", "xxxx")] or [("page", ParamValue::Int(2))]
+        :return Result<Vec<(String, String)>, TigronError>: Returns the (key, value) pairs of the API-response
+    */
+    pub async fn raw_call<V: Into<ParamValue>>(
+        &self,
+        service: &str,
+        cmd: &str,
+        params: std::vec::Vec<(&str, V)>,
+    ) -> Result<std::vec::Vec<(String, String)>, TigronError> {
+        let soap_client = self.soap_client()?;
+
+        let params = render_params(params);
+        let response = soap_client
+            .call(service, cmd, Some(as_params(&params)))
+            .await?;
+
+        self.parse_response(&response)
+    }
+
+    /*
+        Call a command of the API that answers with a list, e.g. the sender numbers of the account
+        Commands without parameters, like 'user/info', take None.
+        :param service: Service of API to execute a command on. E.g: "sms"
+        :param cmd: The command to execute. E.g: "get_senders"
+        :param params: Parameters of the command, rendered with ParamValue and XML-escaped. E.g: Some([("user_id", "xxxx")]) or None::<Vec<(&str, &str)>>
+        :return Result<Vec<HashMap<String, String>>, TigronError>: Returns one map per record, in document order
+    */
+    pub async fn raw_call_list<V: Into<ParamValue>>(
+        &self,
+        service: &str,
+        cmd: &str,
+        params: Option<std::vec::Vec<(&str, V)>>,
+    ) -> Result<std::vec::Vec<HashMap<String, String>>, TigronError> {
+        let soap_client = self.soap_client()?;
+
+        let params = params.map(render_params);
+        let response = soap_client
+            .call(service, cmd, params.as_deref().map(as_params))
+            .await?;
+
+        self.parse_maps(&response)
+    }
+
+    /*
+        Build the SOAP-envelope of a command without sending it, with the password masked
+        :param cmd: The command. E.g: "send_sms"
+        :param params: Parameters of the command, rendered with ParamValue and XML-escaped. E.g: [("to", "+32.xxxxxxxxx")]
+        :return Result<String, TigronError>: Returns the envelope as it would be posted, apart from the password
+    */
+    pub async fn envelope<V: Into<ParamValue>>(
+        &self,
+        cmd: &str,
+        params: std::vec::Vec<(&str, V)>,
+    ) -> Result<String, TigronError> {
+        let soap_client = self.soap_client()?;
+
+        let params = render_params(params);
+        let cmd_xml = soap_client
+            .cmd_and_params_to_wsdl(cmd, as_params(&params))
+            .await;
+        let soap_body = soap_client.soap_body(cmd_xml).await;
+
+        Ok(soap_client.redact(&soap_body))
+    }
+
+    /*
+        Build the SOAP-envelope of a command without sending it, with the credentials in the clear
+        For a build-sign-send split: sign the envelope in a separate step, then post it with 'send_prepared'.
+        Treat the result like the password itself, use 'envelope' for anything that is logged or shared.
+        :param cmd: The command. E.g: "send_sms"
+        :param params: Parameters of the command, rendered with ParamValue and XML-escaped. E.g: [("to", "+32.xxxxxxxxx")]
+        :return Result<String, TigronError>: Returns the envelope exactly as it would be posted
+    */
+    pub async fn prepare_envelope<V: Into<ParamValue>>(
+        &self,
+        cmd: &str,
+        params: std::vec::Vec<(&str, V)>,
+    ) -> Result<String, TigronError> {
+        let soap_client = self.soap_client()?;
+
+        let params = render_params(params);
+        let cmd_xml = soap_client
+            .cmd_and_params_to_wsdl(cmd, as_params(&params))
+            .await;
+
+        Ok(soap_client.soap_body(cmd_xml).await.to_string())
+    }
+
+    /*
+        Post an envelope built (and possibly signed) beforehand, e.g. with 'prepare_envelope', and parse its answer
+        The envelope is posted as it is: no authentication header is added and nothing is validated or charged
+        against the spend cap. Retries, the deadline and the circuit breaker apply as for any other call.
+        :param service: Service of API to post to. E.g: "sms"
+        :param envelope: Complete 'send_sms' envelope, including its authentication header
+        :return Result<SendOutcome, TigronError>: Returns the answer of the gateway. Returns an error if it could not be reached.
+    */
+    pub async fn send_prepared(
+        &self,
+        service: &str,
+        envelope: String,
+    ) -> Result<SendOutcome, TigronError> {
+        let soap_client = self.soap_client()?;
+        let request_id = uuid::Uuid::new_v4().to_string();
+
+        let SoapResponse {
+            body: response,
+            request_id,
+            status,
+            ..
+        } = soap_client
+            .exchange(service, "send_sms", &envelope, &request_id, None)
+            .await?;
+        let response_items = self.parse_response(&response)?;
+
+        SendOutcome::from_response(&response_items, &request_id)
+            .await
+            .map(|outcome| outcome.apply_http_status(status, &self.queued_statuses))
+    }
+
+    /*
+        Call a command with a list-response and iterate its records
+        The body is buffered in full, within the size cap. Only the parsing is incremental: records are parsed
+        one at a time as they are iterated, so a large list is never held as a whole parsed list.
+        :param service: Service of API to execute a command on. E.g: "sms"
+        :param cmd: The command to execute. E.g: "get_list"
+        :param params: Parameters of the command, rendered with ParamValue and XML-escaped. E.g: [("user_id", "xxxx")]
+        :return Result<Records, TigronError>: Returns an iterator over the records, each a list of (key, value) pairs
+    */
+    pub async fn raw_call_records<V: Into<ParamValue>>(
+        &self,
+        service: &str,
+        cmd: &str,
+        params: std::vec::Vec<(&str, V)>,
+    ) -> Result<Records, TigronError> {
+        let soap_client = self.soap_client()?;
+
+        let params = render_params(params);
+        let response = soap_client
+            .call(service, cmd, Some(as_params(&params)))
+            .await?;
+
+        // Skip a byte order mark by starting the reader after it, instead of copying the body
+        let bom = "\u{FEFF}".as_bytes();
+        let mut start = 0;
+        while response.as_bytes()[start..].starts_with(bom) {
+            start += bom.len();
+        }
+        let mut reader = std::io::Cursor::new(response.into_bytes());
+        reader.set_position(start as u64);
+
+        Ok(Records::new(reader))
+    }
+
+    /*
+        Retrieve the delivery report of a sent text-message
+        :param message_id: Id returned by the gateway when the message was accepted or queued
+        :return Result<DeliveryReport, TigronError>: Returns the current report of the message
+    */
+    pub async fn delivery_report(&self, message_id: &str) -> Result<DeliveryReport, TigronError> {
+        let soap_client = self.soap_client()?;
+
+        let user_id = &*self.get_user_id().await?;
+        if user_id.is_empty() {
+            return Err(TigronError::UserNotFound);
+        }
+
+        let params = vec![("user_id", user_id), ("id", message_id)];
+        let response = soap_client.call("sms", "get_status", Some(params)).await?;
+
+        DeliveryReport::from_items(&self.parse_response(&response)?).await
+    }
+
+    /*
+        Retrieve the current delivery state of a sent text-message
+        Poll this after an Accepted or Queued outcome until the state is final (Delivered, Failed or Expired).
+        :param message_id: Id returned by the gateway when the message was accepted or queued
+        :return Result<DeliveryStatus, TigronError>: Returns the delivery state
+    */
+    pub async fn delivery_status(&self, message_id: &str) -> Result<DeliveryStatus, TigronError> {
+        Ok(self.delivery_report(message_id).await?.status)
+    }
+
+    /*
+        Retrieve a page of the sent text-messages of the account, with the 'get_list' command
+        :param page: Number of the page, the first page is 1
+        :return Result<Vec<MessageSummary>, TigronError>: Returns the messages of the page. An empty page means there are no more.
+    */
+    pub async fn list_messages(&self, page: u32) -> Result<std::vec::Vec<MessageSummary>, TigronError> {
+        let soap_client = self.soap_client()?;
+
+        let user_id = &*self.get_user_id().await?;
+        if user_id.is_empty() {
+            return Err(TigronError::UserNotFound);
+        }
+
+        let page = page.to_string();
+        let params = vec![("user_id", user_id), ("page", &*page)];
+        let response = soap_client.call("sms", "get_list", Some(params)).await?;
+
+        let mut messages = std::vec::Vec::new();
+        for record in self.parse_records(&response)? {
+            messages.push(MessageSummary::from_record(&record).await?);
+        }
+
+        Ok(messages)
+    }
+
+    /*
+        Iterate all sent text-messages of the account, fetching the next page as the stream is consumed
+        Stops at the first empty page, or after the first error. When the gateway reported that the
+        rate-limit quota is used up, the next page is only fetched once the quota resets.
+        :return Stream<Item = Result<MessageSummary, TigronError>>: Returns the messages of all pages, in order
+    */
+    pub fn list_all(
+        &self,
+    ) -> impl futures::Stream<Item = Result<MessageSummary, TigronError>> + '_ {
+        paginate(move |page| {
+            Box::pin(async move {
+                self.wait_for_quota().await;
+                self.list_messages(page).await
+            })
+        })
+    }
+
+    // Wait until every destination country has a free slot, a request to several recipients books one per recipient
+    // Returns TigronError::RateLimited instead when a country is saturated and the behavior is Reject
+    async fn wait_for_country_slot(&self, recipients: &[&str]) -> Result<(), TigronError> {
+        let country_rate_limits = match &self.country_rate_limits {
+            Some(country_rate_limits) => country_rate_limits,
+            None => return Ok(()),
+        };
+        let queue_cap = match self.rate_limit_behavior {
+            RateLimitBehavior::Block => None,
+            RateLimitBehavior::Reject => Some(self.rate_limit_queue_cap),
+        };
+
+        let countries: std::vec::Vec<CountryCode> =
+            recipients.iter().filter_map(|to| country_of(to)).collect();
+        let wait = country_rate_limits.reserve_all(&countries, self.clock.now(), queue_cap)?;
+        if wait > Duration::from_secs(0) {
+            self.clock.sleep(wait).await;
+        }
+
+        Ok(())
+    }
+
+    // Wait for the rate-limit window to reset when the last response reported no quota left
+    async fn wait_for_quota(&self) {
+        if let Some(Quota {
+            remaining: 0,
+            resets_at: Some(resets_at),
+        }) = self.last_quota()
+        {
+            self.clock
+                .sleep(resets_at.saturating_duration_since(self.clock.now()))
+                .await;
+        }
+    }
+
+    /*
+        Send a text-message and wait until its delivery is final, e.g. for a critical one-time password
+        :param to: Telephone number to send message to. Format: +xx.xxxxxxxxx
+        :param from: Source of message. Format: +xx.xxxxxxxxx
+        :param message: Content of message to send
+        :param poll_interval: Time between two delivery checks. E.g: Duration::from_secs(2)
+        :param timeout: Longest time to wait for a final state after sending. E.g: Duration::from_secs(60)
+        :return Result<DeliveryStatus, TigronError>: Returns the final state. Returns TigronError::SendRejected if the message was refused, TigronError::ConfirmationTimeout if the state is still open at the timeout.
+    */
+    pub async fn send_and_confirm(
+        &self,
+        to: String,
+        from: String,
+        message: String,
+        poll_interval: Duration,
+        timeout: Duration,
+    ) -> Result<DeliveryStatus, TigronError> {
+        let sent_at = self.clock.now();
+        let message_id = match self.send(to, from, message).await? {
+            SendOutcome::Accepted { id, .. } | SendOutcome::Queued { id, .. } => id,
+            SendOutcome::Rejected { code, reason, .. } => {
+                return Err(TigronError::SendRejected { code, reason })
+            }
+        };
+
+        let deadline = self.clock.now() + timeout;
+        loop {
+            let status = self.delivery_status(&message_id).await?;
+            if status.is_final() {
+                // Known up to the poll interval
+                if let (Some(latencies), DeliveryStatus::Delivered) = (&self.latencies, &status) {
+                    latencies.record(self.clock.now().saturating_duration_since(sent_at));
+                }
+                return Ok(status);
+            }
+
+            if self.clock.now() + poll_interval > deadline {
+                return Err(TigronError::ConfirmationTimeout { message_id });
+            }
+            self.clock.sleep(poll_interval).await;
+        }
+    }
+
+    /*
+        Percentiles of the send-to-delivered latencies observed so far, see 'with_latency_tracking'
+        :return Percentiles: Returns the p50, p90, p99 and maximum latency. All are zero without tracking or observations.
+    */
+    pub fn observed_latency_percentiles(&self) -> Percentiles {
+        self.latencies
+            .as_ref()
+            .map(|latencies| latencies.percentiles())
+            .unwrap_or_default()
+    }
+
+    /*
+        Cancel the parts of a sent text-message that are not delivered once the validity has passed
+        Runs as a background task on the current tokio runtime, sharing the state of this client. Parts already
+        delivered, failed or expired are left alone, as are parts the gateway already handed to the network:
+        a cancel can't recall a message from the carrier. 'SendOptions::with_validity' starts this for every
+        accepted message of a send or batch. The result is passed to the 'with_on_expiry' hook as well.
+        :param outcome: Outcome of the send. A rejected send has nothing to cancel.
+        :param validity: Time the message may take to be delivered, counted from now
+        :return Option<JoinHandle<Result<usize, TigronError>>>: Returns the task, yielding the number of cancelled parts. Returns None for a rejected send.
+    */
+    pub fn expire_after(
+        &self,
+        outcome: &SendOutcome,
+        validity: Duration,
+    ) -> Option<tokio::task::JoinHandle<Result<usize, TigronError>>> {
+        let message_ids = match outcome {
+            SendOutcome::Accepted { message_ids, .. } | SendOutcome::Queued { message_ids, .. } => {
+                message_ids.clone()
+            }
+            SendOutcome::Rejected { .. } => return None,
+        };
+
+        let client = self.clone();
+        let outcome = outcome.clone();
+        let running = Activity::start(&self.activity);
+        Some(tokio::spawn(async move {
+            let _running = running;
+            client.clock.sleep(validity).await;
+
+            let result = client.cancel_undelivered(&message_ids).await;
+            if let Some(on_expiry) = &client.on_expiry {
+                on_expiry(&outcome, &result);
+            }
+            result
+        }))
+    }
+
+    // Cancel the parts that are not final yet, returning the number of cancelled parts
+    async fn cancel_undelivered(&self, message_ids: &[String]) -> Result<usize, TigronError> {
+        let mut cancelled = 0;
+        for message_id in message_ids.iter() {
+            if self.delivery_status(message_id).await?.is_final() {
+                continue;
+            }
+            match self.cancel(message_id).await {
+                Ok(()) => cancelled += 1,
+                Err(TigronError::AlreadySent { .. }) | Err(TigronError::MessageNotFound { .. }) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(cancelled)
+    }
+
+    /*
+        Cancel a scheduled text-message before it is sent, with the 'cancel' command
+        :param message_id: Id returned by the gateway when the message was accepted or queued
+        :return Result<(), TigronError>: Returns Ok() once cancelled. Returns TigronError::AlreadySent or TigronError::MessageNotFound if it can't be.
+    */
+    pub async fn cancel(&self, message_id: &str) -> Result<(), TigronError> {
+        let soap_client = self.soap_client()?;
+
+        let user_id = &*self.get_user_id().await?;
+        if user_id.is_empty() {
+            return Err(TigronError::UserNotFound);
+        }
+
+        let params = vec![("user_id", user_id), ("id", message_id)];
+        let response = soap_client.call("sms", "cancel", Some(params)).await?;
+        let response_items = self.parse_response(&response)?;
+        let status = XmlResponseParser::value(&response_items, "status").await;
+
+        match status.to_lowercase().as_str() {
+            "cancelled" | "canceled" | "ok" => Ok(()),
+            "already_sent" | "sent" | "delivered" => Err(TigronError::AlreadySent {
+                message_id: message_id.to_string(),
+            }),
+            "not_found" | "unknown" => Err(TigronError::MessageNotFound {
+                message_id: message_id.to_string(),
+            }),
+            "" => Err(TigronError::Parse(
+                "Response of cancel does not contain a status.".to_string(),
+            )),
+            other => Err(TigronError::Parse(format!(
+                "Unexpected status '{}' in response of cancel.",
+                other
+            ))),
+        }
+    }
+
+    /*
+        Refresh the delivery status of the messages of earlier sends
+        Statuses are queried a few at a time, so a large campaign doesn't flood the gateway.
+        :param outcomes: Outcomes of earlier sends. Every part of a concatenated send is queried, rejected ones have no id and are skipped.
+        :return Vec<(String, Result<DeliveryStatus, TigronError>)>: Returns the status per message id, in the same order
+    */
+    pub async fn reconcile(
+        &self,
+        outcomes: &[SendOutcome],
+    ) -> std::vec::Vec<(String, Result<DeliveryStatus, TigronError>)> {
+        let message_ids: std::vec::Vec<(&str, Option<DateTime<Utc>>)> = outcomes
+            .iter()
+            .flat_map(|outcome| match outcome {
+                SendOutcome::Accepted {
+                    message_ids,
+                    accepted_at,
+                    ..
+                }
+                | SendOutcome::Queued {
+                    message_ids,
+                    accepted_at,
+                    ..
+                } => message_ids
+                    .iter()
+                    .map(|message_id| (message_id.as_str(), *accepted_at))
+                    .collect(),
+                SendOutcome::Rejected { .. } => std::vec::Vec::new(),
+            })
+            .collect();
+
+        let mut statuses = std::vec::Vec::new();
+        for chunk in message_ids.chunks(RECONCILE_CONCURRENCY) {
+            let reports = futures::future::join_all(
+                chunk
+                    .iter()
+                    .map(|(message_id, _)| self.delivery_report(message_id)),
+            )
+            .await;
+
+            for ((message_id, accepted_at), report) in chunk.iter().zip(reports) {
+                if let (Some(latencies), Ok(report)) = (&self.latencies, &report) {
+                    if let (DeliveryStatus::Delivered, Some(accepted_at), Some(delivered_at)) =
+                        (&report.status, accepted_at, report.timestamp)
+                    {
+                        if let Ok(latency) = (delivered_at - *accepted_at).to_std() {
+                            latencies.record(latency);
+                        }
+                    }
+                }
+                statuses.push((message_id.to_string(), report.map(|report| report.status)));
+            }
+        }
+
+        statuses
+    }
+
+    /*
+        Retrieve the remaining rate-limit quota of the account
+        Tigron has no quota command; the X-RateLimit-Remaining and X-RateLimit-Reset headers of the last response are used.
+        When no response was seen yet, the account info is requested to obtain them.
+        :return Result<Quota, TigronError>: Returns the quota. Returns TigronError::QuotaUnavailable if the gateway doesn't report it.
+    */
+    pub async fn quota(&self) -> Result<Quota, TigronError> {
+        if let Some(quota) = self.last_quota() {
+            return Ok(quota);
+        }
+
+        self.soap_client()?.call("user", "info", None).await?;
+
+        self.last_quota().ok_or(TigronError::QuotaUnavailable)
+    }
+
+    /*
+        Retrieve the WSDL-schema of a service, it is cached per service and shared by all clones
+        :param service: Service of the API. E.g: "sms"
+        :return Result<String, TigronError>: Returns the schema text
+    */
+    pub async fn fetch_wsdl(&self, service: &str) -> Result<String, TigronError> {
+        let cached = self
+            .wsdl
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(service)
+            .cloned();
+        if let Some(wsdl) = cached {
+            return Ok(wsdl);
+        }
+
+        let wsdl = self.soap_client()?.get_wsdl(service).await?;
+        self.wsdl
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(service.to_string(), wsdl.clone());
+
+        Ok(wsdl)
+    }
+
+    /*
+        Check that a command and its parameters are named in the WSDL-schema of the service
+        Catches typos in parameter names before a call is sent. Only names are checked, not types or order.
+        :param service: Service of the API. E.g: "sms"
+        :param cmd: The command. E.g: "send_sms"
+        :param params: Names of the parameters. E.g: ["user_id", "from", "to", "message"]
+        :return Result<(), TigronError>: Returns TigronError::InvalidInput naming the first unknown name
+    */
+    #[cfg(feature = "wsdl")]
+    pub async fn validate_envelope_against_wsdl(
+        &self,
+        service: &str,
+        cmd: &str,
+        params: &[&str],
+    ) -> Result<(), TigronError> {
+        let wsdl = self.fetch_wsdl(service).await?;
+
+        // Operations, messages, parts and schema elements all declare their name in a 'name' attribute
+        let mut names = std::collections::HashSet::new();
+        for e in EventReader::from_str(&wsdl) {
+            match e {
+                Ok(XmlEvent::StartElement { attributes, .. }) => names.extend(
+                    attributes
+                        .into_iter()
+                        .filter(|attribute| attribute.name.local_name == "name")
+                        .map(|attribute| attribute.value),
+                ),
+                Err(e) => return Err(TigronError::Parse(e.to_string())),
+                _ => {}
+            }
+        }
+
+        match std::iter::once(&cmd).chain(params).find(|name| !names.contains(**name)) {
+            Some(unknown) => Err(TigronError::InvalidInput(format!(
+                "'{}' is not defined in the WSDL of service '{}'",
+                unknown, service
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // Quota reported by the last response, shared by all clones
+    fn last_quota(&self) -> Option<Quota> {
+        *self.quota.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /*
+        Function to retrieve user_id, it is cached and shared by all clones
+        Once the cache is older than its TTL the id is looked up again. If that lookup fails transiently the
+        stale id is used: a user_id practically never changes, so a stale id beats failing the send.
+        Only a failing first lookup, with nothing cached, is returned as an error.
+    */
+    async fn get_user_id(&self) -> Result<String, TigronError> {
+        let cached = self
+            .user_id
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        if let Some((user_id, fetched_at)) = &cached {
+            if self.clock.now().saturating_duration_since(*fetched_at) < self.user_id_ttl {
+                return Ok(user_id.to_string());
+            }
+        }
+
+        let user_id = match self.lookup_user_id().await {
+            Ok(user_id) => user_id,
+            Err(TigronError::Transport(_))
+            | Err(TigronError::DeadlineExceeded)
+            | Err(TigronError::CircuitOpen)
+                if cached.is_some() =>
+            {
+                return Ok(cached.map(|(user_id, _)| user_id).unwrap_or_default());
+            }
+            Err(e) => return Err(e),
+        };
+
+        if !user_id.is_empty() {
+            *self.user_id.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some((user_id.to_string(), self.clock.now()));
+        }
+
+        Ok(user_id)
+    }
+
+    /*
+        Retrieve the sender numbers registered on the account, the sources it may send from
+        The list is cached and shared by all clones, it is looked up again once older than the TTL of the user_id.
+        :return Result<Vec<String>, TigronError>: Returns the sender numbers, empty if the account lists none
+    */
+    pub async fn sender_numbers(&self) -> Result<std::vec::Vec<String>, TigronError> {
+        if let Some((sender_numbers, fetched_at)) = &*self
+            .sender_numbers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+        {
+            if self.clock.now().saturating_duration_since(*fetched_at) < self.user_id_ttl {
+                return Ok(sender_numbers.clone());
+            }
+        }
+
+        let sender_numbers: std::vec::Vec<String> = self
+            .account_info()
+            .await?
+            .senders
+            .into_iter()
+            .map(|sender| Self::normalize_number(&sender).unwrap_or(sender))
+            .collect();
+
+        *self.sender_numbers.lock().unwrap_or_else(|e| e.into_inner()) =
+            Some((sender_numbers.clone(), self.clock.now()));
+
+        Ok(sender_numbers)
+    }
+
+    // Look the user_id up with the 'info' procedure
+    async fn lookup_user_id(&self) -> Result<String, TigronError> {
+        Ok(self.account_info().await?.id)
+    }
+
+    /*
+        Retrieve the state of the account with the 'info' procedure
+        :return Result<AccountInfo, TigronError>: Returns the id, name, balance, sender numbers and limits of the account
+    */
+    pub async fn account_info(&self) -> Result<AccountInfo, TigronError> {
+        let soap_client = self.soap_client()?;
+
+        let response = soap_client.call("user", "info", None).await?;
+
+        Ok(AccountInfo::from_items(&self.parse_response(&response)?).await)
+    }
+
+    /*
+        Retrieve the balance of the account
+        :return Result<Option<Amount>, TigronError>: Returns the balance. Returns None if the account info doesn't report one.
+    */
+    pub async fn balance(&self) -> Result<Option<Amount>, TigronError> {
+        Ok(self.account_info().await?.balance)
+    }
+
+    // SOAP-client authenticated with the credentials of this client
+    // The client holds a snapshot of the credentials, so a rotation never changes them halfway a call.
+    // The snapshot is shared, copying the password would leave copies behind that are never scrubbed.
+    fn soap_client(&self) -> Result<SoapClient<'_>, TigronError> {
+        let credentials = self
+            .credentials
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+
+        // A password pasted with a trailing newline would otherwise end up in the header as a baffling auth failure
+        if credentials.0.chars().any(char::is_control) {
+            return Err(TigronError::Config(
+                "Username contains a control character (e.g. a newline or tab).".to_string(),
+            ));
+        }
+        if credentials.1.chars().any(char::is_control) {
+            return Err(TigronError::Config(
+                "Password contains a control character (e.g. a newline or tab).".to_string(),
+            ));
+        }
+
+        Ok(SoapClient {
+            http: self.http()?,
+            http_by_family: if self.family_failover {
+                Some(self.http_by_family()?)
+            } else {
+                None
+            },
+            local_address: self.local_address,
+            circuit_breaker: self.circuit_breaker.as_deref(),
+            in_flight: self.in_flight.as_deref(),
+            activity: &self.activity,
+            retry: &self.retry,
+            endpoint_suffix: &self.endpoint_suffix,
+            accept: &self.accept,
+            capture_on_error: self.capture_on_error(),
+            pii_policy: self.pii_policy,
+            namespace_style: self.namespace_style,
+            success_statuses: &self.success_statuses,
+            clock: &*self.clock,
+            on_attempt: self.on_attempt.as_deref(),
+            quota: &self.quota,
+            #[cfg(feature = "signing")]
+            signing_key: self.signing_key.as_deref(),
+            max_response_bytes: self.max_response_bytes,
+            url: self.environment.url().to_string(),
+            ns: self.environment.ns().to_string(),
+            credentials,
+        })
+    }
+
+    // HTTP-client of this client, built on first use and shared by all clones
+    fn http(&self) -> Result<&reqwest::Client, TigronError> {
+        self.http.get_or_try_init(|| self.build_http(self.local_address))
+    }
+
+    // HTTP-clients bound to IPv4 and to IPv6, for the family failover
+    fn http_by_family(&self) -> Result<&(reqwest::Client, reqwest::Client), TigronError> {
+        self.http_by_family.get_or_try_init(|| {
+            Ok((
+                self.build_http(Some(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED)))?,
+                self.build_http(Some(std::net::IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED)))?,
+            ))
+        })
+    }
+
+    // Build an HTTP-client with the TLS-settings of this client, bound to the given local address
+    fn build_http(
+        &self,
+        local_address: Option<std::net::IpAddr>,
+    ) -> Result<reqwest::Client, TigronError> {
+        // Redirects are never followed: a followed SOAP POST would silently change endpoint
+        let mut builder = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .danger_accept_invalid_certs(self.tls.accept_invalid_certs)
+            .local_address(local_address);
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+
+        // Pinned roots replace the system roots instead of being added to them
+        if !self.tls.root_certificates.is_empty() {
+            builder = builder.tls_built_in_root_certs(false);
+        }
+        for certificate in self.tls.root_certificates.iter() {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+
+        builder.build().map_err(TigronError::Transport)
+    }
+}
+
+// Rejection codes of congestion and throttling are transient, those about the message or the account are not
+fn default_retryability() -> HashMap<String, Retryability> {
+    let transient = [
+        "throttled",
+        "congestion",
+        "temporary_failure",
+        "service_unavailable",
+        "timeout",
+    ];
+    let permanent = [
+        "invalid_number",
+        "invalid_sender",
+        "blacklisted",
+        "insufficient_credit",
+        "message_too_long",
+    ];
+
+    transient
+        .iter()
+        .map(|code| (code.to_string(), Retryability::Transient))
+        .chain(
+            permanent
+                .iter()
+                .map(|code| (code.to_string(), Retryability::Permanent)),
+        )
+        .collect()
+}
+
+/*
+    Collect every value of a key in the pairs returned by 'raw_call', for fields the gateway repeats
+    :param items: (key, value) pairs of an API-response
+    :param key: The key to collect. E.g: "sender"
+    :return Vec<&str>: Returns the values matching the key, in document order
+*/
+pub fn response_values<'a>(items: &'a [(String, String)], key: &str) -> std::vec::Vec<&'a str> {
+    XmlResponseParser::values(items, key)
+}
+
+/*
+    Mask the telephone numbers and hide the message body of captured XML, as far as the policy asks
+    :param xml: Envelope or response of a call
+    :param policy: What to keep readable
+    :return String: Returns the XML with the personal data redacted
+*/
+fn redact_pii(xml: &str, policy: PiiPolicy) -> String {
+    let mut redacted = xml.to_string();
+
+    if !policy.log_message {
+        for element in ["message", "ns:message"].iter() {
+            let (open, close) = (format!("<{}>", element), format!("</{}>", element));
+            let mut from = 0;
+            while let Some(start) = redacted[from..].find(&open).map(|i| from + i + open.len()) {
+                let end = match redacted[start..].find(&close) {
+                    Some(end) => start + end,
+                    None => break,
+                };
+                redacted.replace_range(start..end, "[redacted]");
+                from = start + "[redacted]".len() + close.len();
+            }
+        }
+    }
+
+    if policy.mask_numbers {
+        if let Ok(number) = regex::Regex::new(r"\+(\d{1,3})\.(\d+)|\+(\d{8,15})") {
+            redacted = number
+                .replace_all(&redacted, |captures: &regex::Captures| match captures.get(3) {
+                    Some(e164) => format!("+{}", mask_digits(e164.as_str())),
+                    None => format!("+{}.{}", &captures[1], mask_digits(&captures[2])),
+                })
+                .into_owned();
+        }
+    }
+
+    redacted
+}
+
+// Keep the first and last three digits of a long number, hide a short one entirely
+// Counted in chars: '\d' also matches non-ASCII digits, which take more than one byte.
+fn mask_digits(digits: &str) -> String {
+    let digits: std::vec::Vec<char> = digits.chars().collect();
+    if digits.len() > 6 {
+        let first: String = digits[..3].iter().collect();
+        let last: String = digits[digits.len() - 3..].iter().collect();
+        format!("{}***{}", first, last)
+    } else {
+        "***".to_string()
+    }
+}
+
+// Render typed parameters to the text sent to the gateway
+fn render_params<'a, V: Into<ParamValue>>(
+    params: std::vec::Vec<(&'a str, V)>,
+) -> std::vec::Vec<(&'a str, String)> {
+    params
+        .into_iter()
+        .map(|(key, value)| (key, value.into().to_string()))
+        .collect()
+}
+
+// Text of a flag as the gateway expects it
+fn flag(value: bool) -> &'static str {
+    if value {
+        "1"
+    } else {
+        "0"
+    }
+}
+
+// Borrow rendered parameters in the form the SOAP-client takes
+fn as_params<'a>(params: &'a [(&'a str, String)]) -> std::vec::Vec<(&'a str, &'a str)> {
+    params
+        .iter()
+        .map(|(key, value)| (*key, value.as_str()))
+        .collect()
+}
+
+// Local address of the attempt after a connection failure over the given one, IPv4 unless IPv4 just failed
+fn alternate_family(local_address: Option<std::net::IpAddr>) -> std::net::IpAddr {
+    match local_address {
+        Some(std::net::IpAddr::V4(_)) => std::net::IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+        _ => std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+    }
+}
+
+// Whether the connection could not be set up, e.g. refused or unreachable, so the other family may get through
+// A reset, an abort or an early EOF happen on an open connection and are not connect failures.
+fn is_connect_failure(error: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(error);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<std::io::Error>() {
+            return matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionRefused
+                    | std::io::ErrorKind::AddrNotAvailable
+                    | std::io::ErrorKind::NetworkUnreachable
+                    | std::io::ErrorKind::HostUnreachable
+                    | std::io::ErrorKind::TimedOut
+            );
+        }
+        source = e.source();
+    }
+
+    false
+}
+
+/*
+    Turn a paged command into a stream of its items, fetching pages from 1 until one is empty
+    :param fetch: Fetches a page by its number
+    :return Stream<Item = Result<T, TigronError>>: Returns the items of all pages, ending after the first error
+*/
+fn paginate<'a, T: 'a, F>(fetch: F) -> impl futures::Stream<Item = Result<T, TigronError>> + 'a
+where
+    F: Fn(u32) -> BoxFuture<'a, Result<std::vec::Vec<T>, TigronError>> + 'a,
+{
+    let buffered: std::collections::VecDeque<T> = std::collections::VecDeque::new();
+
+    futures::stream::unfold(
+        (fetch, Some(1u32), buffered),
+        |(fetch, mut page, mut buffered)| async move {
+            loop {
+                if let Some(item) = buffered.pop_front() {
+                    return Some((Ok(item), (fetch, page, buffered)));
+                }
+
+                let current = page?;
+                match fetch(current).await {
+                    Ok(items) if items.is_empty() => return None,
+                    Ok(items) => {
+                        buffered.extend(items);
+                        page = Some(current + 1);
+                    }
+                    Err(e) => return Some((Err(e), (fetch, None, buffered))),
+                }
+            }
+        },
+    )
+}
+
+/*
+    Derive the country of a telephone number from its dialing prefix
+    :param number: Telephone number. Format: +xx.xxxxxxxxx (the dot is optional)
+    :return Option<CountryCode>: Returns the country. Returns None if the prefix is unknown.
+*/
+pub fn country_of(number: &str) -> Option<CountryCode> {
+    let number = number.trim_start_matches('+');
+
+    // The dotted format tells exactly where the dialing prefix ends
+    if let Some(dot) = number.find('.') {
+        return CountryCode::from_dialing_code(&number[..dot]);
+    }
+
+    // Dialing prefixes are prefix-free, so at most one length matches
+    (1..=3)
+        .filter_map(|length| number.get(..length))
+        .find_map(CountryCode::from_dialing_code)
+}
+
+// Drop the dots of a number, refusing one that doesn't sit between two digits. E.g: "+.32470", "+32..470", "+32470."
+fn strip_dots(number: &str) -> Result<String, TigronError> {
+    let bytes = number.as_bytes();
+    let misplaced = bytes.iter().enumerate().any(|(i, byte)| {
+        *byte == b'.'
+            && !(i > 0
+                && bytes[i - 1].is_ascii_digit()
+                && bytes.get(i + 1).map_or(false, u8::is_ascii_digit))
+    });
+    if misplaced {
+        return Err(TigronError::InvalidNumber(format!(
+            "{} has a dot in the wrong place",
+            number
+        )));
+    }
+
+    Ok(number.replace('.', ""))
+}
+
+/*
+    Walk the XML of a response event by event, the stream the response parser reads its pairs from
+    Useful to see why a field wasn't extracted, e.g. an unexpected element name or nesting.
+    :param xml: XML of a response. E.g: the body returned by 'raw_call' with capture on
+    :return Iterator<Item = Result<ParsedEvent, TigronError>>: Returns the events in document order, ending after the first TigronError::Parse
+*/
+pub fn parse_events(xml: &str) -> impl Iterator<Item = Result<ParsedEvent, TigronError>> + '_ {
+    EventReader::new(xml.trim_start_matches('\u{FEFF}').as_bytes())
+        .into_iter()
+        .filter_map(|event| match event {
+            Ok(XmlEvent::StartElement {
+                name, attributes, ..
+            }) => Some(Ok(ParsedEvent::Start {
+                name: name.local_name,
+                attributes: attributes
+                    .into_iter()
+                    .map(|attribute| (attribute.name.local_name, attribute.value))
+                    .collect(),
+            })),
+            Ok(XmlEvent::Characters(text)) | Ok(XmlEvent::CData(text)) => {
+                Some(Ok(ParsedEvent::Text(text)))
+            }
+            Ok(XmlEvent::EndElement { name }) => Some(Ok(ParsedEvent::End {
+                name: name.local_name,
+            })),
+            Ok(_) => None,
+            Err(e) => Some(Err(TigronError::Parse(e.to_string()))),
+        })
+}
+
+/*
+    Convert an E.164 number into the dotted format Tigron expects
+    :param e164: Telephone number in E.164 format. E.g: +32470123456
+    :return Result<String, TigronError>: Returns the number as +xx.xxxxxxxxx. Returns TigronError::InvalidNumber if the prefix is unknown.
+*/
+pub fn to_tigron_format(e164: &str) -> Result<String, TigronError> {
+    let digits = match e164.trim().strip_prefix('+') {
+        Some(digits) if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) => digits,
+        _ => {
+            return Err(TigronError::InvalidNumber(format!(
+                "{} is not an E.164 number",
+                e164
+            )))
+        }
+    };
+
+    let country = country_of(digits).ok_or_else(|| {
+        TigronError::InvalidNumber(format!("{} has an unknown country code", e164))
+    })?;
+
+    Ok(format!(
+        "+{}.{}",
+        country.dialing_code,
+        &digits[country.dialing_code.len()..]
+    ))
+}
+
+/*
+    Convert a number in Tigron's dotted format into E.164
+    :param dotted: Telephone number. Format: +xx.xxxxxxxxx
+    :return String: Returns the number in E.164 format. E.g: +32470123456
+*/
+pub fn from_tigron_format(dotted: &str) -> String {
+    dotted.trim().replace('.', "")
+}
+
+// Replace every ${NAME} in a setting with the environment variable NAME, an unset variable is an error
+fn interpolate_env(value: &str) -> Result<String, TigronError> {
+    let mut interpolated = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let name = &rest[start + 2..end];
+        let variable = std::env::var(name).map_err(|_| {
+            TigronError::Config(format!("Environment variable {} is not set.", name))
+        })?;
+
+        interpolated.push_str(&rest[..start]);
+        interpolated.push_str(&variable);
+        rest = &rest[end + 1..];
+    }
+    interpolated.push_str(rest);
+
+    Ok(interpolated)
+}
+
+impl CountryCode {
+
+    // Look up the country of an exact dialing prefix. E.g: "352" for Luxembourg
+    fn from_dialing_code(dialing_code: &str) -> Option<CountryCode> {
+        DIALING_CODES
+            .iter()
+            .find(|(code, _)| *code == dialing_code)
+            .map(|(code, iso)| CountryCode {
+                iso,
+                dialing_code: code,
+            })
+    }
+
+    // Look up a country by its ISO 3166-1 code, in any case. E.g: "LU" for Luxembourg
+    fn from_iso(iso: &str) -> Option<CountryCode> {
+        DIALING_CODES
+            .iter()
+            .find(|(_, code)| code.eq_ignore_ascii_case(iso.trim()))
+            .map(|(dialing_code, code)| CountryCode {
+                iso: code,
+                dialing_code,
+            })
+    }
+}
+
+impl fmt::Display for CountryCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.iso)
+    }
+}
+
+impl SendOutcome {
+
+    // Code of a rejection in the parsed response of 'send_sms', None when it was accepted or queued
+    fn rejection_code(items: &[(String, String)]) -> Option<String> {
+        let first = |key| XmlResponseParser::values(items, key).first().copied().unwrap_or("");
+        let status = first("status");
+
+        match status.to_lowercase().as_str() {
+            "" | "accepted" | "ok" | "sent" | "queued" => None,
+            _ if first("code").is_empty() => Some(status.to_string()),
+            _ => Some(first("code").to_string()),
+        }
+    }
+
+    /*
+        Map the parsed response of 'send_sms' to an outcome
+        :param items: Array of returned_items retrieved from API-response
+        :param request_id: Id of the call that returned the response
+        :return Result<SendOutcome, TigronError>: Returns the outcome. Returns an error if the response has no status.
+    */
+    async fn from_response(
+        items: &std::vec::Vec<(String, String)>,
+        request_id: &str,
+    ) -> Result<SendOutcome, TigronError> {
+        let request_id = request_id.to_string();
+        let status = XmlResponseParser::value(items, "status").await;
+        let id = XmlResponseParser::value(items, "id").await;
+
+        // A concatenated or bulk send returns an id per part
+        let message_ids: std::vec::Vec<String> = XmlResponseParser::values(items, "id")
+            .into_iter()
+            .filter(|message_id| !message_id.is_empty())
+            .map(|message_id| message_id.to_string())
+            .collect();
+
+        // The time of the gateway, in the formats of the delivery reports
+        let mut accepted_at = XmlResponseParser::value(items, "accepted_at").await;
+        if accepted_at.is_empty() {
+            accepted_at = XmlResponseParser::value(items, "timestamp").await;
+        }
+        let accepted_at = DeliveryReport::parse_timestamp(&accepted_at);
+
+        match status.to_lowercase().as_str() {
+            "" => Err(TigronError::Parse(
+                "Response of send_sms does not contain a status.".to_string(),
+            )),
+            "accepted" | "ok" | "sent" => Ok(SendOutcome::Accepted {
+                id,
+                message_ids,
+                request_id,
+                accepted_at,
+            }),
+            "queued" => Ok(SendOutcome::Queued {
+                id,
+                message_ids,
+                request_id,
+                accepted_at,
+            }),
+            _ => {
+                let code = XmlResponseParser::value(items, "code").await;
+                let reason = XmlResponseParser::value(items, "reason").await;
+
+                Ok(SendOutcome::Rejected {
+                    code: if code.is_empty() { status } else { code },
+                    reason,
+                    request_id,
+                })
+            }
+        }
+    }
+
+    // An accepted send answered with a 'queued' HTTP-status (202 by default) is only queued
+    fn apply_http_status(self, status: u16, queued_statuses: &[u16]) -> SendOutcome {
+        match self {
+            SendOutcome::Accepted {
+                id,
+                message_ids,
+                request_id,
+                accepted_at,
+            } if queued_statuses.contains(&status) => SendOutcome::Queued {
+                id,
+                message_ids,
+                request_id,
+                accepted_at,
+            },
+            outcome => outcome,
+        }
+    }
+}
+
+impl DiagnosisReport {
+
+    // Whether every step passed
+    pub fn is_ok(&self) -> bool {
+        self.steps
+            .iter()
+            .all(|step| matches!(step.status, DiagnosisStatus::Passed(_)))
+    }
+}
+
+impl fmt::Display for DiagnosisReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for step in self.steps.iter() {
+            let (label, detail) = match &step.status {
+                DiagnosisStatus::Passed(detail) => ("ok", detail.as_str()),
+                DiagnosisStatus::Failed(reason) => ("FAILED", reason.as_str()),
+                DiagnosisStatus::Skipped => ("skipped", ""),
+            };
+            writeln!(
+                f,
+                "{:<12} {:<8} {:>6} ms  {}",
+                step.name,
+                label,
+                step.elapsed.as_millis(),
+                detail
+            )?;
+        }
+
+        write!(
+            f,
+            "{}",
+            if self.is_ok() {
+                "Setup is working."
+            } else {
+                "Setup is broken at the first failed step."
+            }
+        )
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Currency::Credits => write!(f, "credits"),
+            Currency::Iso(code) => write!(f, "{}", code),
+        }
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.amount, self.currency)
+    }
+}
+
+impl fmt::Display for SendOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendOutcome::Accepted { id, .. } => write!(f, "message {} accepted", id),
+            SendOutcome::Queued { id, .. } => write!(f, "message {} queued", id),
+            SendOutcome::Rejected { code, reason, .. } => {
+                write!(f, "message rejected ({}): {}", code, reason)
+            }
+        }
+    }
+}
+
+impl DeliveryReport {
+
+    /*
+        Parse a delivery report of the gateway
+        :param xml: Takes the XML of the report as input. E.g: <item><key>status</key><value>delivered</value></item>
+        :return Result<DeliveryReport, TigronError>: Returns the report. Returns an error if it has no message id. A missing or unknown timestamp is None.
+    */
+    pub async fn from_xml(xml: &str) -> Result<DeliveryReport, TigronError> {
+        Self::from_items(&XmlResponseParser::parse(xml).await).await
+    }
+
+    // Delivery report from the pairs of a response
+    async fn from_items(items: &std::vec::Vec<(String, String)>) -> Result<DeliveryReport, TigronError> {
+        let mut message_id = XmlResponseParser::value(items, "message_id").await;
+        if message_id.is_empty() {
+            message_id = XmlResponseParser::value(items, "id").await;
+        }
+        if message_id.is_empty() {
+            return Err(TigronError::Parse(
+                "Delivery report does not contain a message id.".to_string(),
+            ));
+        }
+
+        let status = XmlResponseParser::value(items, "status").await;
+        let timestamp = XmlResponseParser::value(items, "timestamp").await;
+        let error_code = XmlResponseParser::value(items, "error_code").await;
+        let error_text = XmlResponseParser::value(items, "error_text").await;
+
+        Ok(DeliveryReport {
+            message_id,
+            status: DeliveryStatus::from_param(&status),
+            timestamp: Self::parse_timestamp(&timestamp),
+            error_code: error_code.trim().parse().ok(),
+            error_text: if error_text.is_empty() {
+                None
+            } else {
+                Some(error_text)
+            },
+        })
+    }
+
+    // Timestamps come as RFC 3339 or as "YYYY-MM-DD HH:MM:SS" in UTC
+    fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+        let timestamp = timestamp.trim();
+
+        DateTime::parse_from_rfc3339(timestamp)
+            .map(|timestamp| timestamp.with_timezone(&Utc))
+            .or_else(|_| {
+                NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S")
+                    .map(|timestamp| Utc.from_utc_datetime(&timestamp))
+            })
+            .ok()
+    }
+}
+
+impl AccountInfo {
+
+    /*
+        Parse the response of the 'info' procedure
+        :param xml: Takes the XML of the response as input. E.g: <item><key>id</key><value>42</value></item>
+        :return AccountInfo: Returns the account state. The id is empty if the response has none.
+    */
+    pub async fn from_xml(xml: &str) -> AccountInfo {
+        Self::from_items(&XmlResponseParser::parse(xml).await).await
+    }
+
+    // Account state from the pairs of a response
+    async fn from_items(items: &[(String, String)]) -> AccountInfo {
+        let first = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|key| XmlResponseParser::values(items, key).first().copied())
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+
+        AccountInfo {
+            id: first(&["id"]).unwrap_or_default(),
+            name: first(&["name", "account_name", "company"]),
+            balance: first(&["balance", "credit", "credits"])
+                .and_then(|balance| balance.parse().ok()),
+            senders: ["sender", "sender_number"]
+                .iter()
+                .flat_map(|key| XmlResponseParser::values(items, key))
+                .map(|sender| sender.to_string())
+                .collect(),
+            limits: items
+                .iter()
+                .filter(|(key, _)| key.contains("limit"))
+                .filter_map(|(key, value)| Some((key.to_string(), value.trim().parse().ok()?)))
+                .collect(),
+        }
+    }
+}
+
+impl MessageSummary {
+
+    /*
+        Map a record of the 'get_list' response to a summary
+        :param record: (key, value) pairs of a single message
+        :return Result<MessageSummary, TigronError>: Returns the summary. Returns an error if the record has no message id.
+    */
+    async fn from_record(record: &std::vec::Vec<(String, String)>) -> Result<MessageSummary, TigronError> {
+        let mut id = XmlResponseParser::value(record, "message_id").await;
+        if id.is_empty() {
+            id = XmlResponseParser::value(record, "id").await;
+        }
+        if id.is_empty() {
+            return Err(TigronError::Parse(
+                "Message of get_list does not contain a message id.".to_string(),
+            ));
+        }
+
+        let status = XmlResponseParser::value(record, "status").await;
+        let timestamp = XmlResponseParser::value(record, "timestamp").await;
+
+        Ok(MessageSummary {
+            id,
+            to: XmlResponseParser::value(record, "to").await,
+            status: DeliveryStatus::from_param(&status),
+            timestamp: DeliveryReport::parse_timestamp(&timestamp),
+        })
+    }
+}
+
+impl fmt::Debug for TigronConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TigronConfig")
+            .field("username", &self.username)
+            .field("password", &"********")
+            .field("url", &self.url)
+            .field("namespace", &self.namespace)
+            .field("deadline_ms", &self.deadline_ms)
+            .field("connect_timeout_ms", &self.connect_timeout_ms)
+            .field("retries", &self.retries)
+            .field("retry_delay_ms", &self.retry_delay_ms)
+            .field("max_backoff_ms", &self.max_backoff_ms)
+            .field("user_id_ttl_secs", &self.user_id_ttl_secs)
+            .field("max_concurrent", &self.max_concurrent)
+            .field("country_rate_limits", &self.country_rate_limits)
+            .field("rate_limit_behavior", &self.rate_limit_behavior)
+            .field("rate_limit_queue_cap", &self.rate_limit_queue_cap)
+            .finish()
+    }
+}
+
+impl Environment {
+
+    // Base URL of the services
+    fn url(&self) -> &str {
+        match self {
+            Environment::Production => "https://api.tigron.net/soap",
+            Environment::Custom { url, .. } => url.trim_end_matches('/'),
+        }
+    }
+
+    // Namespace of the commands
+    fn ns(&self) -> &str {
+        match self {
+            Environment::Production => "https://www.tigron.net/ns/",
+            Environment::Custom { ns, .. } => ns,
+        }
+    }
+}
+
+impl DeliveryStatus {
+
+    // Whether the state can no longer change
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            DeliveryStatus::Delivered | DeliveryStatus::Failed | DeliveryStatus::Expired
+        )
+    }
+
+    // Map the 'status' of a delivery report, unknown values are kept as they are
+    fn from_param(status: &str) -> DeliveryStatus {
+        match status.trim().to_lowercase().as_str() {
+            "delivered" | "delivrd" => DeliveryStatus::Delivered,
+            "pending" | "sent" | "buffered" | "enroute" => DeliveryStatus::Pending,
+            "failed" | "undeliv" | "undelivered" | "rejected" => DeliveryStatus::Failed,
+            "expired" => DeliveryStatus::Expired,
+            _ => DeliveryStatus::Unknown(status.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeliveryStatus::Delivered => write!(f, "delivered"),
+            DeliveryStatus::Pending => write!(f, "pending"),
+            DeliveryStatus::Failed => write!(f, "failed"),
+            DeliveryStatus::Expired => write!(f, "expired"),
+            DeliveryStatus::Unknown(status) => write!(f, "unknown ({})", status),
+        }
+    }
+}
+
+impl fmt::Display for TigronError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TigronError::InvalidInput(reason) => write!(f, "{}", reason),
+            TigronError::InvalidNumber(reason) => write!(f, "Invalid number: {}", reason),
+            TigronError::InvalidUtf8(e) => write!(f, "Message is not valid UTF-8: {}", e),
+            TigronError::Config(reason) => write!(f, "Invalid configuration: {}", reason),
+            TigronError::UserNotFound => {
+                write!(f, "User not found. Are your credentials correct?")
+            }
+            TigronError::SendRejected { code, reason } => {
+                write!(f, "Message rejected ({}): {}", code, reason)
+            }
+            TigronError::Fault { code, reason } => write!(f, "SOAP fault ({}): {}", code, reason),
+            TigronError::SpendCapExceeded { cap, spent } => write!(
+                f,
+                "Spend cap of {} reached ({} spent)",
+                cap, spent
+            ),
+            TigronError::RateLimited { country } => write!(
+                f,
+                "Too many messages to {} are waiting, message not sent",
+                country
+            ),
+            TigronError::ResponseTooLarge { limit } => {
+                write!(f, "Response is larger than {} bytes", limit)
+            }
+            TigronError::TruncatedResponse { expected, received } => write!(
+                f,
+                "Response was cut off after {} of {} bytes",
+                received, expected
+            ),
+            TigronError::CircuitOpen => {
+                write!(f, "Gateway is failing, calls are short-circuited")
+            }
+            TigronError::DeadlineExceeded => write!(f, "Deadline of the call exceeded"),
+            TigronError::ConfirmationTimeout { message_id } => write!(
+                f,
+                "Delivery of message {} was not confirmed in time",
+                message_id
+            ),
+            TigronError::QuotaUnavailable => {
+                write!(f, "Gateway does not report a rate-limit quota")
+            }
+            TigronError::SameFromTo => write!(f, "Source and destination are the same number"),
+            TigronError::UnregisteredSender { from } => {
+                write!(f, "Sender {} is not registered on the account", from)
+            }
+            TigronError::ContentRejected(reason) => {
+                write!(f, "Message refused by the content filter: {}", reason)
+            }
+            TigronError::AlreadySent { message_id } => {
+                write!(f, "Message {} was already sent", message_id)
+            }
+            TigronError::MessageNotFound { message_id } => {
+                write!(f, "Message {} is unknown to the gateway", message_id)
+            }
+            TigronError::Redirect { location } => {
+                write!(f, "Gateway endpoint moved to '{}'", location)
+            }
+            TigronError::HttpStatus { status } => {
+                write!(f, "Gateway answered with HTTP-status {}", status)
+            }
+            TigronError::NotXml { content_type } => write!(
+                f,
+                "Gateway answered with a non-XML page ({}), likely maintenance",
+                content_type.as_deref().unwrap_or("no content-type")
+            ),
+            TigronError::Shared(e) => write!(f, "{}", e),
+            TigronError::Storage(e) => write!(f, "Offline queue failed: {}", e),
+            TigronError::Io(e) => write!(f, "Could not read input: {}", e),
+            TigronError::Captured { error, .. } => write!(f, "{}", error),
+            TigronError::Transport(e) => write!(f, "Failed to get response: {}", e),
+            TigronError::Parse(reason) => write!(f, "Could not parse response: {}", reason),
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            base_delay: Duration::default(),
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            deadline: None,
+        }
+    }
+}
+
+impl Default for PiiPolicy {
+    fn default() -> Self {
+        PiiPolicy {
+            mask_numbers: true,
+            log_message: false,
+        }
+    }
+}
+
+impl TigronError {
+
+    /*
+        Whether the same send may succeed when tried again, here or on a backup gateway
+        True for failures to reach the gateway (transport, deadline, open circuit, maintenance page, cut-off
+        response), for HTTP 429 and 5xx, and for rejections with a transient code of the default table.
+        False for mistakes in the request, the account or the configuration, which fail on any gateway.
+        :return bool: Returns whether a retry or a fallback makes sense
+    */
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            TigronError::Transport(_)
+            | TigronError::DeadlineExceeded
+            | TigronError::CircuitOpen
+            | TigronError::RateLimited { .. }
+            | TigronError::NotXml { .. }
+            | TigronError::TruncatedResponse { .. } => true,
+            TigronError::HttpStatus { status } => *status == 429 || *status >= 500,
+            TigronError::SendRejected { code, .. } => {
+                default_retryability().get(&code.to_lowercase()) == Some(&Retryability::Transient)
+            }
+            TigronError::Shared(e) => e.is_retryable(),
+            TigronError::Captured { error, .. } => error.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /*
+        Recommend the HTTP-status to answer with when this error surfaces in your own HTTP API
+        Mistakes in the request are 4xx, failures of the gateway or its connection are 5xx:
+        invalid input or number 400, spend cap 402, unknown message 404, already sent 409, rejection 422,
+        throttling (rejected as 'throttled' or HTTP 429 from the gateway) 429, configuration and local I/O 500,
+        bad credentials and unusable gateway responses 502, gateway down or in maintenance 503, timeouts 504.
+        To override a mapping, match the variants you disagree on first and fall back to this for the rest.
+        :return u16: Returns the recommended status
+    */
+    pub fn suggested_http_status(&self) -> u16 {
+        match self {
+            TigronError::InvalidInput(_)
+            | TigronError::InvalidNumber(_)
+            | TigronError::InvalidUtf8(_)
+            | TigronError::SameFromTo => 400,
+            TigronError::SpendCapExceeded { .. } => 402,
+            TigronError::MessageNotFound { .. } => 404,
+            TigronError::AlreadySent { .. } => 409,
+            TigronError::SendRejected { code, .. } if code.eq_ignore_ascii_case("throttled") => 429,
+            TigronError::SendRejected { .. }
+            | TigronError::UnregisteredSender { .. }
+            | TigronError::ContentRejected(_) => 422,
+            TigronError::HttpStatus { status: 429 } | TigronError::RateLimited { .. } => 429,
+            TigronError::Config(_) | TigronError::Storage(_) | TigronError::Io(_) => 500,
+            TigronError::UserNotFound
+            | TigronError::ResponseTooLarge { .. }
+            | TigronError::TruncatedResponse { .. }
+            | TigronError::QuotaUnavailable
+            | TigronError::Redirect { .. }
+            | TigronError::HttpStatus { .. }
+            | TigronError::Fault { .. }
+            | TigronError::Parse(_) => 502,
+            TigronError::Transport(e) if e.is_timeout() => 504,
+            TigronError::Transport(_) => 502,
+            TigronError::CircuitOpen | TigronError::NotXml { .. } => 503,
+            TigronError::DeadlineExceeded | TigronError::ConfirmationTimeout { .. } => 504,
+            TigronError::Shared(e) => e.suggested_http_status(),
+            TigronError::Captured { error, .. } => error.suggested_http_status(),
+        }
+    }
+}
+
+impl std::error::Error for TigronError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TigronError::Transport(e) => Some(e),
+            TigronError::Storage(e) => Some(e),
+            TigronError::Io(e) => Some(e),
+            TigronError::InvalidUtf8(e) => Some(e),
+            TigronError::Shared(e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+impl SendOptions {
+
+    /*
+        Force the data coding scheme instead of relying on automatic GSM-7/UCS-2 detection
+        :param dcs: GSM 03.38 data coding scheme. E.g: 0 (GSM-7), 4 (8-bit), 8 (UCS-2)
+        :return SendOptions: Returns the options with the override applied
+    */
+    pub fn with_dcs(mut self, dcs: u8) -> Self {
+        self.dcs = Some(dcs);
+        self
+    }
+
+    /*
+        Flag the message as transactional or promotional for regulatory routing
+        :param message_type: Category of the message. Defaults to MessageType::Transactional
+        :return SendOptions: Returns the options with the message type applied
+    */
+    pub fn with_message_type(mut self, message_type: MessageType) -> Self {
+        self.message_type = message_type;
+        self
+    }
+
+    /*
+        Force a specific route/operator of the gateway instead of its own routing
+        Sent as the 'route' parameter of 'send_sms'. A route the gateway doesn't offer is rejected by the gateway.
+        :param route_id: Identifier of the route. E.g: "premium-be"
+        :return SendOptions: Returns the options with the route applied
+    */
+    pub fn with_route(mut self, route_id: &str) -> Self {
+        self.route = Some(route_id.to_string());
+        self
+    }
+
+    /*
+        Cancel the message when it isn't delivered within the validity, e.g. so a stale OTP never arrives
+        Tigron's 'send_sms' takes no validity, so the window is enforced client-side: see 'TigronSms::expire_after'.
+        A gateway honoring a validity itself (e.g. the validity period of SMPP) drops the message on its own and needs no cancel.
+        Applies to every accepted message of 'send', 'send_batch', 'send_to_many' and 'BatchingSender'.
+        The expiries run in the background, their failures are passed to 'TigronSms::with_on_expiry'.
+        :param validity: Time the message may take to be delivered. E.g: Duration::from_secs(300)
+        :return SendOptions: Returns the options with the validity applied
+    */
+    pub fn with_validity(mut self, validity: Duration) -> Self {
+        self.validity = Some(validity);
+        self
+    }
+
+    // Check the value against the general data coding and the data coding/message class groups
+    fn is_valid_dcs(dcs: u8) -> bool {
+        match dcs & 0xF0 {
+            0x00..=0x30 => dcs & 0x0C != 0x0C,
+            0xF0 => dcs & 0x08 == 0,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ParamValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamValue::Str(value) => write!(f, "{}", value),
+            ParamValue::Int(value) => write!(f, "{}", value),
+            ParamValue::Float(value) => write!(f, "{}", value),
+            ParamValue::Bool(value) => write!(f, "{}", flag(*value)),
+        }
+    }
+}
+
+impl From<&str> for ParamValue {
+    fn from(value: &str) -> Self {
+        ParamValue::Str(value.to_string())
+    }
+}
+
+impl From<String> for ParamValue {
+    fn from(value: String) -> Self {
+        ParamValue::Str(value)
+    }
+}
+
+impl From<&String> for ParamValue {
+    fn from(value: &String) -> Self {
+        ParamValue::Str(value.to_string())
+    }
+}
+
+impl From<i32> for ParamValue {
+    fn from(value: i32) -> Self {
+        ParamValue::Int(value.into())
+    }
+}
+
+impl From<u32> for ParamValue {
+    fn from(value: u32) -> Self {
+        ParamValue::Int(value.into())
+    }
+}
+
+impl From<i64> for ParamValue {
+    fn from(value: i64) -> Self {
+        ParamValue::Int(value)
+    }
+}
+
+impl From<f64> for ParamValue {
+    fn from(value: f64) -> Self {
+        ParamValue::Float(value)
+    }
+}
+
+impl From<bool> for ParamValue {
+    fn from(value: bool) -> Self {
+        ParamValue::Bool(value)
+    }
+}
+
+impl MessageType {
+
+    // Value of the 'type' parameter expected by the gateway
+    fn as_param(&self) -> &'static str {
+        match self {
+            MessageType::Transactional => "transactional",
+            MessageType::Promotional => "promotional",
+        }
+    }
+}
+
+impl Default for MessageType {
+    fn default() -> Self {
+        MessageType::Transactional
+    }
+}
+
+impl BatchingSender {
+
+    /*
+        Start coalescing sends of the client
+        :param client: Client used to send the batches
+        :param options: Overrides applied to every message
+        :param max_batch: Most messages in a single batch. E.g: 100
+        :param max_delay: Longest time a message waits for others to join its batch. E.g: Duration::from_millis(20)
+        :return BatchingSender: Returns the sender, clones feed the same batches
+    */
+    pub fn new(
+        client: TigronSms,
+        options: SendOptions,
+        max_batch: usize,
+        max_delay: Duration,
+    ) -> Self {
+        let (queue, mut pending) = tokio::sync::mpsc::unbounded_channel::<(SendRequest, Reply)>();
+        let max_batch = max_batch.max(1);
+
+        tokio::spawn(async move {
+            // The task ends once every sender is dropped and the queue is drained
+            while let Some(first) = pending.recv().await {
+                let deadline = tokio::time::Instant::now() + max_delay;
+                let mut batch = vec![first];
+                while batch.len() < max_batch {
+                    match tokio::time::timeout_at(deadline, pending.recv()).await {
+                        Ok(Some(next)) => batch.push(next),
+                        Ok(None) | Err(_) => break,
+                    }
+                }
+
+                let (requests, replies): (std::vec::Vec<SendRequest>, std::vec::Vec<Reply>) =
+                    batch.into_iter().unzip();
+                match client.send_batch(requests, options.clone()).await {
+                    Ok(results) => {
+                        for (reply, result) in replies.into_iter().zip(results) {
+                            let _ = reply.send(result);
+                        }
+                    }
+                    // The whole batch failed, every caller gets the same error
+                    Err(e) => {
+                        let e = Arc::new(e);
+                        for reply in replies {
+                            let _ = reply.send(Err(TigronError::Shared(e.clone())));
+                        }
+                    }
+                }
+            }
+        });
+
+        BatchingSender { queue }
+    }
+
+    /*
+        Queue a text-message for the next batch and wait for its result
+        :param request: Text-message to send
+        :return Result<SendOutcome, TigronError>: Returns the outcome of this message. Returns TigronError::Shared if its batch failed as a whole.
+    */
+    pub async fn send(&self, request: SendRequest) -> Result<SendOutcome, TigronError> {
+        let (reply, result) = tokio::sync::oneshot::channel();
+        let stopped = || {
+            TigronError::Parse("Batching sender stopped before sending this request.".to_string())
+        };
+
+        self.queue.send((request, reply)).map_err(|_| stopped())?;
+        result.await.map_err(|_| stopped())?
+    }
+}
+
+impl QueuedSms {
+
+    /*
+        Wrap a client with an offline queue
+        :param client: Client used to send the messages
+        :param store: Durable queue. E.g: Box::new(FileStore::new("/var/lib/sms/queue.jsonl"))
+        :return QueuedSms: Returns the store-and-forward client
+    */
+    pub fn new(client: TigronSms, store: Box<dyn Store>) -> Self {
+        QueuedSms {
+            client,
+            store,
+            dead_letter: None,
+        }
+    }
+
+    /*
+        Receive the queued messages 'flush' drops because they can never be sent, e.g. to persist or alert on them
+        :param dead_letter: Hook receiving the request and the error it was refused with
+        :return QueuedSms: Returns the store-and-forward client with the hook applied
+    */
+    pub fn with_dead_letter(mut self, dead_letter: DeadLetter) -> Self {
+        self.dead_letter = Some(dead_letter);
+        self
+    }
+
+    /*
+        Send a text-message, or queue it when the gateway can't be reached
+        While messages are queued new ones are queued behind them, so the order is kept.
+        :param request: Text-message to send
+        :return Result<Option<SendOutcome>, TigronError>: Returns the outcome, or None if the message was queued
+    */
+    pub async fn send(&self, request: SendRequest) -> Result<Option<SendOutcome>, TigronError> {
+        if self.store.peek()?.is_some() {
+            self.store.push(&request)?;
+            return Ok(None);
+        }
+
+        match self.send_request(&request).await {
+            Err(e) if Self::is_offline(&e) => {
+                self.store.push(&request)?;
+                Ok(None)
+            }
+            outcome => outcome.map(Some),
+        }
+    }
+
+    /*
+        Send the queued text-messages in order, until the queue is empty or the gateway can't take the next one
+        Flushing stops, keeping the message, on a network failure, a transient rejection and on errors of the
+        account or configuration (unknown user, HTTP 401/403, spend cap), which fail every message until fixed.
+        A message that can never be sent (e.g. an invalid number or a permanent rejection) is dropped from
+        the queue and passed to the 'with_dead_letter' hook, if one is set.
+        :return Result<usize, TigronError>: Returns the number of messages taken from the queue
+    */
+    pub async fn flush(&self) -> Result<usize, TigronError> {
+        let mut flushed = 0;
+        while let Some(request) = self.store.peek()? {
+            let error = match self.send_request(&request).await {
+                Ok(SendOutcome::Rejected { code, reason, .. }) => {
+                    if self.client.retryability(&code) == Retryability::Transient {
+                        break;
+                    }
+                    TigronError::SendRejected { code, reason }
+                }
+                Ok(_) => {
+                    self.store.pop()?;
+                    flushed += 1;
+                    continue;
+                }
+                Err(e) if Self::is_offline(&e) || Self::is_blocking(&e) => break,
+                Err(e) => e,
+            };
+
+            self.store.pop()?;
+            flushed += 1;
+            if let Some(dead_letter) = &self.dead_letter {
+                dead_letter(FailedSend {
+                    request,
+                    error: Arc::new(error),
+                });
+            }
+        }
+
+        Ok(flushed)
+    }
+
+    async fn send_request(&self, request: &SendRequest) -> Result<SendOutcome, TigronError> {
+        self.client
+            .send(
+                request.to.to_string(),
+                request.from.to_string(),
+                request.message.to_string(),
+            )
+            .await
+    }
+
+    // Errors that mean the gateway could not take the message now, as opposed to the message being refused
+    // E.g: no connection, a 5xx-status or an HTML-page of a gateway in maintenance
+    fn is_offline(error: &TigronError) -> bool {
+        error.is_retryable()
+    }
+
+    // Errors of the account or the configuration, every queued message would fail on them until they are fixed
+    fn is_blocking(error: &TigronError) -> bool {
+        match error {
+            TigronError::UserNotFound
+            | TigronError::SpendCapExceeded { .. }
+            | TigronError::Config(_)
+            | TigronError::Storage(_) => true,
+            TigronError::HttpStatus { status } => *status == 401 || *status == 403,
+            TigronError::Shared(e) => Self::is_blocking(e),
+            TigronError::Captured { error, .. } => Self::is_blocking(error),
+            _ => false,
+        }
+    }
+}
+
+impl FileStore {
+
+    /*
+        Keep the queue in a file, created on the first push
+        :param path: Path of the queue-file. E.g: "/var/lib/sms/queue.jsonl"
+        :return FileStore: Returns the store
+    */
+    pub fn new<P: Into<std::path::PathBuf>>(path: P) -> Self {
+        FileStore {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    // Lines of the queue-file, an absent file is an empty queue
+    fn lines(&self) -> Result<std::vec::Vec<String>, TigronError> {
+        match std::fs::File::open(&self.path) {
+            Ok(file) => std::io::BufReader::new(file)
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .collect::<Result<_, _>>()
+                .map_err(TigronError::Storage),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(std::vec::Vec::new()),
+            Err(e) => Err(TigronError::Storage(e)),
+        }
+    }
+}
+
+impl Store for FileStore {
+    fn push(&self, request: &SendRequest) -> Result<(), TigronError> {
+        let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let line = serde_json::to_string(request).map_err(|e| TigronError::Parse(e.to_string()))?;
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(TigronError::Storage)?;
+        writeln!(file, "{}", line).map_err(TigronError::Storage)?;
+        file.sync_all().map_err(TigronError::Storage)
+    }
+
+    fn peek(&self) -> Result<Option<SendRequest>, TigronError> {
+        let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        match self.lines()?.first() {
+            Some(line) => serde_json::from_str(line)
+                .map(Some)
+                .map_err(|e| TigronError::Parse(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    fn pop(&self) -> Result<(), TigronError> {
+        let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let lines = self.lines()?;
+
+        // Write the remainder next to the queue and swap it in, so a crash never loses the queue
+        let remainder: String = lines.iter().skip(1).map(|line| format!("{}\n", line)).collect();
+        let temporary = self.path.with_extension("tmp");
+        std::fs::write(&temporary, remainder).map_err(TigronError::Storage)?;
+        std::fs::rename(&temporary, &self.path).map_err(TigronError::Storage)
+    }
+}
+
+// A single Arc<TigronSms> is shared by many tokio tasks: the client and its send futures must be Send + Sync.
+// Every piece of shared state (user_id cache, spend counter, quota, circuit breaker) sits behind an Arc
+// with an atomic or a Mutex that is never held across an await.
+#[allow(dead_code)]
+fn assert_thread_safe(tigron_sms: &TigronSms) {
+    fn is_send_sync<T: Send + Sync>(_: &T) {}
+    fn is_send<T: Send>(_: &T) {}
+
+    is_send_sync(tigron_sms);
+    is_send(&tigron_sms.send(String::new(), String::new(), String::new()));
+    is_send(&tigron_sms.send_batch(std::vec::Vec::new(), SendOptions::default()));
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::delay_for(duration))
+    }
+}
+
+impl ManualClock {
+
+    // Clock standing still at the current instant
+    pub fn new() -> Self {
+        ManualClock {
+            start: Instant::now(),
+            offset: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    // Move the clock forward
+    pub fn advance(&self, duration: Duration) {
+        *self.offset.lock().unwrap_or_else(|e| e.into_inner()) += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.offset.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.advance(duration);
+        Box::pin(futures::future::ready(()))
+    }
+}
+
+impl RetryPolicy {
+
+    // Backoff before the retry after the given attempt, doubling each time up to the cap
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(Duration::MAX)
+            .min(self.max_backoff)
+    }
+}
+
+impl DedupWindow {
+
+    // Outcome of an identical send within the window, expired sends are pruned on the way
+    fn lookup(&self, to: &str, message: &str, now: Instant) -> Option<SendOutcome> {
+        let mut sent = self.sent.lock().unwrap_or_else(|e| e.into_inner());
+        sent.retain(|_, (_, sent_at)| now.saturating_duration_since(*sent_at) < self.window);
+
+        sent.get(&Self::key(to, message)).map(|(outcome, _)| outcome.clone())
+    }
+
+    // Remember an accepted or queued send
+    fn record(&self, to: &str, message: &str, outcome: &SendOutcome, now: Instant) {
+        if let SendOutcome::Rejected { .. } = outcome {
+            return;
+        }
+
+        self.sent
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(Self::key(to, message), (outcome.clone(), now));
+    }
+
+    // Hash of the destination and content, the message itself is not kept in memory
+    fn key(to: &str, message: &str) -> u64 {
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        (to, message).hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl LatencyTracker {
+
+    // Keep a latency, dropping the oldest once full
+    fn record(&self, latency: Duration) {
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    // Nearest-rank percentiles of the kept latencies
+    fn percentiles(&self) -> Percentiles {
+        let mut sorted: std::vec::Vec<Duration> = self
+            .samples
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .copied()
+            .collect();
+        sorted.sort();
+
+        let rank = |percentile: usize| {
+            let index = (sorted.len() * percentile).div_ceil(100).saturating_sub(1);
+            sorted.get(index).copied().unwrap_or_default()
+        };
+
+        Percentiles {
+            count: sorted.len(),
+            p50: rank(50),
+            p90: rank(90),
+            p99: rank(99),
+            max: sorted.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+impl CountryRateLimiter {
+
+    // Book the next slot of the country, returning how long to wait for it
+    // With a queue cap, a country that already has that many sends waiting is refused instead.
+    fn reserve(
+        &self,
+        country: CountryCode,
+        now: Instant,
+        queue_cap: Option<usize>,
+    ) -> Result<Duration, TigronError> {
+        self.reserve_all(&[country], now, queue_cap)
+    }
+
+    // Book a slot per country (twice for a country listed twice), returning the wait for the last one
+    // A refused country books nothing at all, so the slots of the other countries aren't lost.
+    fn reserve_all(
+        &self,
+        countries: &[CountryCode],
+        now: Instant,
+        queue_cap: Option<usize>,
+    ) -> Result<Duration, TigronError> {
+        let mut next_slot = self.next_slot.lock().unwrap_or_else(|e| e.into_inner());
+        let mut booked: HashMap<CountryCode, Instant> = HashMap::new();
+        let mut wait = Duration::from_secs(0);
+        for country in countries.iter().copied() {
+            let limit = match self.limits.get(&country) {
+                Some(limit) => *limit,
+                None => continue,
+            };
+            let interval = Duration::from_secs(1) / limit;
+
+            let slot = match booked.get(&country).or_else(|| next_slot.get(&country)) {
+                Some(next) if *next > now => *next,
+                _ => now,
+            };
+
+            // Slots booked after now belong to sends still waiting, not to the send under way
+            let ahead = slot.saturating_duration_since(now).as_nanos();
+            let interval_nanos = interval.as_nanos().max(1);
+            let waiting = ahead.div_ceil(interval_nanos).saturating_sub(1);
+            if let Some(queue_cap) = queue_cap {
+                if waiting >= queue_cap as u128 {
+                    return Err(TigronError::RateLimited { country });
+                }
+            }
+            booked.insert(country, slot + interval);
+            wait = wait.max(slot.saturating_duration_since(now));
+        }
+        next_slot.extend(booked);
+
+        Ok(wait)
+    }
+}
+
+impl CircuitBreaker {
+
+    // Let a call through, or refuse it while the circuit is open
+    fn before_call(&self, now: Instant) -> Result<CircuitCall<'_>, TigronError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        let trial = match state.opened_at {
+            None => false,
+            Some(opened_at) if now.saturating_duration_since(opened_at) < self.reset_timeout => {
+                return Err(TigronError::CircuitOpen);
+            }
+            Some(_) if state.trial_in_flight => return Err(TigronError::CircuitOpen),
+            Some(_) => {
+                state.trial_in_flight = true;
+                true
+            }
+        };
+
+        Ok(CircuitCall {
+            circuit_breaker: self,
+            trial,
+            finished: false,
+        })
+    }
+
+    // Close the circuit on success, open it once the threshold or the trial call fails
+    fn after_call(&self, success: bool, now: Instant) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        if success {
+            *state = CircuitState::default();
+            return;
+        }
+
+        state.consecutive_failures += 1;
+        if state.trial_in_flight || state.consecutive_failures >= self.failure_threshold {
+            state.opened_at = Some(now);
+            state.trial_in_flight = false;
+        }
+    }
+}
+
+impl Activity {
+
+    // Count a call or background task until the returned guard is dropped
+    fn start(activity: &Arc<Activity>) -> Running {
+        activity.running.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Running(activity.clone())
+    }
+
+    // Wait until nothing is running, a wakeup is passed on to the next waiter
+    async fn idle(&self) {
+        while self.running.load(std::sync::atomic::Ordering::SeqCst) > 0 {
+            self.idle.notified().await;
+        }
+        self.idle.notify();
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if self.0.running.fetch_sub(1, std::sync::atomic::Ordering::SeqCst) == 1 {
+            self.0.idle.notify();
+        }
+    }
+}
+
+impl CircuitCall<'_> {
+
+    // Report the result of the call to the circuit breaker
+    fn finish(mut self, success: bool, now: Instant) {
+        self.finished = true;
+        self.circuit_breaker.after_call(success, now);
+    }
+}
+
+impl Drop for CircuitCall<'_> {
+    fn drop(&mut self) {
+        if self.trial && !self.finished {
+            self.circuit_breaker
+                .state
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .trial_in_flight = false;
+        }
+    }
+}
+
+impl SoapClient<'_> {
+
+    /*
+        Send a command to the API and retrieve XML
+        :param service: Service of API to execute a command on. E.g: "sms"
+        :param cmd: The command to execute. E.g: "send_sms"
+        :param params: Parameters of the command. E.g: [("from", "xxxx.xxx.xxx"), ("to", "yyyy.yyy.yyy")]
+        :return Result<String, TigronError>: Returns the body of the API-response. Returns an error if the request failed.
+    */
+    pub async fn call(
+        &self,
+        service: &str,
+        cmd: &str,
+        params: Option<std::vec::Vec<(&str, &str)>>,
+    ) -> Result<String, TigronError> {
+        self.call_traced(service, cmd, params)
+            .await
+            .map(|response| response.body)
+    }
+
+    /*
+        Send a command to the API and retrieve XML together with the id of the request
+        Every call gets a fresh UUID in the X-Request-Id header, shared by its retries. An id echoed by the gateway is preferred.
+        :param service: Service of API to execute a command on. E.g: "sms"
+        :param cmd: The command to execute. E.g: "send_sms"
+        :param params: Parameters of the command. E.g: [("from", "xxxx.xxx.xxx"), ("to", "yyyy.yyy.yyy")]
+        :return Result<SoapResponse, TigronError>: Returns the body of the API-response, the request id and the HTTP-status
+    */
+    pub async fn call_traced(
+        &self,
+        service: &str,
+        cmd: &str,
+        params: Option<std::vec::Vec<(&str, &str)>>,
+    ) -> Result<SoapResponse, TigronError> {
+        self.call_resending(service, cmd, params, None).await
+    }
+
+    /*
+        Send a command to the API, sending it again while 'resend' asks for it
+        Resent calls share the retries, the deadline and the request id of transport retries.
+        :param service: Service of API to execute a command on. E.g: "sms"
+        :param cmd: The command to execute. E.g: "send_sms"
+        :param params: Parameters of the command. E.g: [("from", "xxxx.xxx.xxx"), ("to", "yyyy.yyy.yyy")]
+        :param resend: Decides from a response whether the command is sent again
+        :return Result<SoapResponse, TigronError>: Returns the body of the API-response, the request id and the HTTP-status
+    */
+    async fn call_resending(
+        &self,
+        service: &str,
+        cmd: &str,
+        params: Option<std::vec::Vec<(&str, &str)>>,
+        resend: Option<&Resend<'_>>,
+    ) -> Result<SoapResponse, TigronError> {
+        let request_id = uuid::Uuid::new_v4().to_string();
+
+        let params = match params {
+            Some(params) => params,
+            None => std::vec::Vec::new(),
+        };
+
+        let cmd_xml = self.cmd_and_params_to_wsdl(cmd, params).await;
+        let soap_body = self.soap_body(cmd_xml).await;
+
+        let result = self
+            .exchange(service, cmd, &soap_body, &request_id, resend)
+            .await;
+        if !self.capture_on_error {
+            return result;
+        }
+
+        // The response of a failed call was attached by 'post' if there was one
+        let envelope = redact_pii(&self.redact(&soap_body), self.pii_policy);
+        match result {
+            Ok(response) => Ok(SoapResponse {
+                envelope,
+                ..response
+            }),
+            Err(TigronError::Captured {
+                error, response, ..
+            }) => Err(TigronError::Captured {
+                error,
+                request: envelope,
+                response,
+            }),
+            Err(error) => Err(TigronError::Captured {
+                error: Box::new(error),
+                request: envelope,
+                response: None,
+            }),
+        }
+    }
+
+    /*
+        Post the SOAP-envelope, retrying transport failures
+        :param service: Service of API to execute a command on. E.g: "sms"
+        :param cmd: The command, reported to the attempt hook. E.g: "send_sms"
+        :param soap_body: Full SOAP-envelope of the call
+        :param request_id: Id sent in the X-Request-Id header
+        :return Result<SoapResponse, TigronError>: Returns the body of the API-response, the request id and the HTTP-status
+    */
+    async fn exchange(
+        &self,
+        service: &str,
+        cmd: &str,
+        soap_body: &str,
+        request_id: &str,
+        resend: Option<&Resend<'_>>,
+    ) -> Result<SoapResponse, TigronError> {
+        // Transport failures and the responses 'resend' asks for are retried, the deadline bounds attempts and backoff sleeps together
+        let deadline = self
+            .retry
+            .deadline
+            .map(|deadline| self.clock.now() + deadline);
+        let mut last_error: Option<TigronError> = None;
+        let mut last_response: Option<SoapResponse> = None;
+        let mut attempt: u32 = 0;
+        let _running = Activity::start(self.activity);
+        let mut family = self.local_address;
+        let mut http = self.http;
+        loop {
+            let circuit_call = match self.circuit_breaker {
+                Some(circuit_breaker) => Some(circuit_breaker.before_call(self.clock.now())?),
+                None => None,
+            };
+
+            let started = self.clock.now();
+            let response = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(self.clock.now());
+                    let post = self.post(http, service, soap_body, request_id);
+                    match tokio::time::timeout(remaining, post).await {
+                        Ok(response) => response,
+                        Err(_) => Err(last_error.unwrap_or(TigronError::DeadlineExceeded)),
+                    }
+                }
+                None => {
+                    self.post(http, service, soap_body, request_id)
+                        .await
+                }
+            };
+
+            let resent = match (&response, resend) {
+                (Ok(response), Some(resend)) => resend(response),
+                _ => false,
+            };
+            let failed = resent || response.as_ref().err().map_or(false, Self::is_outage);
+            if let Some(circuit_call) = circuit_call {
+                circuit_call.finish(!failed, self.clock.now());
+            }
+            if let Some(on_attempt) = self.on_attempt {
+                on_attempt(&AttemptInfo {
+                    command: cmd.to_string(),
+                    attempt: attempt + 1,
+                    latency: self.clock.now().saturating_duration_since(started),
+                    result: match &response {
+                        Ok(response) => Ok(response.status),
+                        Err(e) => Err(e.to_string()),
+                    },
+                });
+            }
+
+            // A response asked to be sent again is kept, it is the answer when no resend fits before the deadline
+            let error = match response {
+                Err(TigronError::Transport(e)) if attempt < self.retry.max_retries => {
+                    last_response = None;
+                    Some(e)
+                }
+                Ok(response) if resent && attempt < self.retry.max_retries => {
+                    last_response = Some(response);
+                    None
+                }
+                Err(TigronError::DeadlineExceeded) => {
+                    return last_response.ok_or(TigronError::DeadlineExceeded)
+                }
+                response => return response,
+            };
+
+            let delay = self.retry.delay(attempt);
+            if let Some(deadline) = deadline {
+                if self.clock.now() + delay >= deadline {
+                    return match error {
+                        Some(error) => Err(TigronError::Transport(error)),
+                        None => last_response.ok_or(TigronError::DeadlineExceeded),
+                    };
+                }
+            }
+
+            // The next attempt goes over the other family when this one couldn't connect
+            if let (Some((ipv4, ipv6)), Some(error)) = (self.http_by_family, &error) {
+                if is_connect_failure(error) {
+                    family = Some(alternate_family(family));
+                    http = match family {
+                        Some(std::net::IpAddr::V6(_)) => ipv6,
+                        _ => ipv4,
+                    };
+                }
+            }
+
+            last_error = error.map(TigronError::Transport);
+            self.clock.sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /*
+        Post the SOAP-envelope to the service and read the response
+        :param http: HTTP-client to post with, bound to the local address of this attempt
+        :param service: Service of API to execute a command on. E.g: "sms"
+        :param soap_body: Full SOAP-envelope of the call
+        :param request_id: Id sent in the X-Request-Id header
+        :return Result<SoapResponse, TigronError>: Returns the decoded body, the request id echoed by the gateway (or the one sent) and the HTTP-status
+    */
+    async fn post(
+        &self,
+        http: &reqwest::Client,
+        service: &str,
+        soap_body: &str,
+        request_id: &str,
+    ) -> Result<SoapResponse, TigronError> {
+        let _permit = self.permit().await;
+
+        let mut request = http
+            .post(&format!(
+                "{url}/{service}{suffix}",
+                url = self.url,
+                service = service,
+                suffix = self.endpoint_suffix
+            ))
+            .header("Content-Type", "application/xml")
+            .header("Accept", self.accept)
+            .header("X-Request-Id", request_id);
+        if let Some(signature) = self.sign(soap_body) {
+            request = request.header("X-Signature", signature);
+        }
+
+        // reqwest takes the body by value, so this copy is beyond the reach of the 'zeroize' feature
+        let mut response = request
+            .body(soap_body.to_string())
+            .send()
+            .await
+            .map_err(TigronError::Transport)?;
+
+        self.record_quota(response.headers());
+
+        let request_id = response
+            .headers()
+            .get("x-request-id")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(request_id)
+            .to_string();
+
+        // Surface an endpoint migration instead of parsing the empty body of the redirect
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            return Err(TigronError::Redirect { location });
+        }
+
+        // Any 2xx (or the configured statuses) carries a body to parse, success or fault is decided from it
+        let status = response.status().as_u16();
+        let success = if self.success_statuses.is_empty() {
+            response.status().is_success()
+        } else {
+            self.success_statuses.contains(&status)
+        };
+        if !success {
+            let error = TigronError::HttpStatus { status };
+            if !self.capture_on_error {
+                return Err(error);
+            }
+            let body = self.read_failed_body(&mut response).await;
+            return Err(self.capture_response(error, &body));
+        }
+
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+
+        // Only the header counts, the size hint of a chunked body is no promise
+        let content_length = response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok());
+
+        let limit = self.max_response_bytes;
+        if content_length.unwrap_or(0) > limit as u64 {
+            return Err(TigronError::ResponseTooLarge { limit });
+        }
+
+        // Read chunk by chunk so a runaway body is aborted instead of buffered
+        let mut body: std::vec::Vec<u8> = std::vec::Vec::new();
+        loop {
+            let chunk = match response.chunk().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                // The connection closed before the announced length arrived, reported as truncated below
+                Err(_) if content_length.is_some() => break,
+                Err(e) => return Err(TigronError::Transport(e)),
+            };
+            if body.len() + chunk.len() > limit {
+                return Err(TigronError::ResponseTooLarge { limit });
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        // A dropped connection ends the body early, with or without an error, parsing the rest would misread it
+        if let Some(expected) = content_length {
+            if body.len() as u64 != expected {
+                return Err(TigronError::TruncatedResponse {
+                    expected,
+                    received: body.len() as u64,
+                });
+            }
+        }
+
+        let body = Self::decode(&body, content_type.as_deref());
+        if let Err(error) = Self::ensure_xml(&body, content_type.as_deref()) {
+            return Err(self.capture_response(error, &body));
+        }
+
+        Ok(SoapResponse {
+            body,
+            request_id,
+            status,
+            envelope: String::new(),
+        })
+    }
+
+    // Body of a response with a failed HTTP-status, read as far as it arrives within the size cap
+    async fn read_failed_body(&self, response: &mut reqwest::Response) -> String {
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+
+        let mut body: std::vec::Vec<u8> = std::vec::Vec::new();
+        while let Ok(Some(chunk)) = response.chunk().await {
+            if body.len() + chunk.len() > self.max_response_bytes {
+                break;
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        Self::decode(&body, content_type.as_deref())
+    }
+
+    // Failures counted by the circuit breaker: the gateway is unreachable, down or in maintenance
+    // Throttling is the gateway working as intended and an open circuit is no call at all.
+    fn is_outage(error: &TigronError) -> bool {
+        match error {
+            TigronError::RateLimited { .. }
+            | TigronError::CircuitOpen
+            | TigronError::HttpStatus { status: 429 } => false,
+            TigronError::Captured { error, .. } => Self::is_outage(error),
+            error => error.is_retryable(),
+        }
+    }
+
+    // Attach the raw body of a failed response when capturing, 'call_resending' adds the envelope
+    fn capture_response(&self, error: TigronError, body: &str) -> TigronError {
+        if !self.capture_on_error {
+            return error;
+        }
+        TigronError::Captured {
+            error: Box::new(error),
+            request: String::new(),
+            response: Some(redact_pii(body, self.pii_policy)),
+        }
+    }
+
+    /*
+        Refuse a response that isn't XML, e.g. the HTML-page the gateway serves during maintenance
+        Parsing such a page yields empty results, which would be misread as a success.
+        :param body: Decoded response body
+        :param content_type: Content-Type header of the response
+        :return Result<(), TigronError>: Returns an error if the response is not XML
+    */
+    fn ensure_xml(body: &str, content_type: Option<&str>) -> Result<(), TigronError> {
+        let mime = content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+        let mime_is_xml = mime.is_empty() || mime.contains("xml");
+
+        let head = body.trim_start().get(..15).unwrap_or_else(|| body.trim_start());
+        let head = head.to_lowercase();
+        let body_is_html = head.starts_with("<!doctype") || head.starts_with("<html");
+
+        if mime_is_xml && !body_is_html {
+            return Ok(());
+        }
+
+        Err(TigronError::NotXml {
+            content_type: content_type.map(|content_type| content_type.to_string()),
+        })
+    }
+
+    /*
+        Download the WSDL-schema of a service
+        :param service: Service of the API. E.g: "sms"
+        :return Result<String, TigronError>: Returns the schema text
+    */
+    async fn get_wsdl(&self, service: &str) -> Result<String, TigronError> {
+        let _permit = self.permit().await;
+
+        let mut response = self
+            .http
+            .get(&format!("{url}/{service}?WSDL", url = self.url, service = service))
+            .header("Accept", self.accept)
+            .send()
+            .await
+            .map_err(TigronError::Transport)?;
+
+        let status = response.status().as_u16();
+        if !response.status().is_success() {
+            return Err(TigronError::HttpStatus { status });
+        }
+
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+
+        let limit = self.max_response_bytes;
+        let mut body: std::vec::Vec<u8> = std::vec::Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(TigronError::Transport)? {
+            if body.len() + chunk.len() > limit {
+                return Err(TigronError::ResponseTooLarge { limit });
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        let body = Self::decode(&body, content_type.as_deref());
+        Self::ensure_xml(&body, content_type.as_deref())?;
+
+        Ok(body)
+    }
+
+    // Wait for a free slot under the in-flight cap, held until the response is read
+    async fn permit(&self) -> Option<tokio::sync::SemaphorePermit<'_>> {
+        match self.in_flight {
+            Some(in_flight) => Some(in_flight.acquire().await),
+            None => None,
+        }
+    }
+
+    /*
+        Sign the SOAP-envelope with the signing key, if one is set
+        The signature is the hex-encoded HMAC-SHA256 of the envelope, sent in the X-Signature header.
+        :param soap_body: Full SOAP-envelope of the call
+        :return Option<String>: Returns the signature. Returns None without a key or without the 'signing' feature.
+    */
+    #[cfg(feature = "signing")]
+    fn sign(&self, soap_body: &str) -> Option<String> {
+        use hmac::{Hmac, Mac};
+
+        let key = self.signing_key?;
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).ok()?;
+        mac.update(soap_body.as_bytes());
+
+        Some(
+            mac.finalize()
+                .into_bytes()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        )
+    }
+
+    #[cfg(not(feature = "signing"))]
+    fn sign(&self, _soap_body: &str) -> Option<String> {
+        None
+    }
+
+    // Remember the rate-limit quota if the response reports one
+    fn record_quota(&self, headers: &reqwest::header::HeaderMap) {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+        };
+
+        if let Some(remaining) = header("x-ratelimit-remaining") {
+            let resets_at = header("x-ratelimit-reset")
+                .map(|seconds| self.clock.now() + Duration::from_secs(seconds));
+            *self.quota.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some(Quota { remaining, resets_at });
+        }
+    }
+
+    /*
+        Decode a response body using the charset it declares
+        :param body: Raw bytes of the response
+        :param content_type: Value of the Content-Type header. E.g: "text/xml; charset=ISO-8859-1"
+        :return String: Returns the decoded body. The header wins over the XML declaration, UTF-8 is the default.
+        A leading UTF-8 byte order mark is dropped, it would otherwise hide the XML declaration from the parser.
+    */
+    fn decode(body: &[u8], content_type: Option<&str>) -> String {
+        let header_charset = content_type.and_then(|content_type| {
+            content_type
+                .split(';')
+                .filter_map(|part| part.trim().split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("charset"))
+                .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        });
+
+        // The XML declaration is plain ASCII in every charset we support
+        let declaration_charset = || {
+            let head = String::from_utf8_lossy(&body[..body.len().min(200)]).into_owned();
+            let declaration = &head[..head.find("?>")?];
+            let start = declaration.find("encoding=")? + "encoding=".len();
+            let value = declaration[start..].trim_start_matches(['"', '\'']);
+            let end = value.find(['"', '\''])?;
+
+            Some(value[..end].to_string())
+        };
+
+        let charset = header_charset
+            .or_else(declaration_charset)
+            .unwrap_or_default()
+            .to_lowercase();
+
+        match charset.as_str() {
+            "iso-8859-1" | "iso8859-1" | "latin1" | "latin-1" | "l1" => {
+                let decoded: String = body.iter().map(|byte| *byte as char).collect();
+
+                // The text is UTF-8 now, a declaration still naming Latin-1 would have it decoded twice
+                match declaration_charset() {
+                    Some(declared) => decoded.replacen(&declared, "UTF-8", 1),
+                    None => decoded,
+                }
+            }
+            _ => {
+                let body = body.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(body);
+                String::from_utf8_lossy(body).into_owned()
+            }
+        }
+    }
+
+    // Convert the array from the command and params-array into WSDL/XML format
+    async fn cmd_and_params_to_wsdl(
+        &self,
+        cmd: &str,
+        mut params: std::vec::Vec<(&str, &str)>,
+    ) -> String {
+        let mut xml = String::new();
+
+        // Order-sensitive validators reject 'send_sms' unless the schema order is followed
+        if cmd == "send_sms" {
+            params.sort_by_key(|(key, _)| {
+                SEND_SMS_PARAMETER_ORDER
+                    .iter()
+                    .position(|ordered| ordered == key)
+                    .unwrap_or(SEND_SMS_PARAMETER_ORDER.len())
+            });
+        }
+
+        let (cmd_prefix, param_prefix) = match self.namespace_style {
+            NamespaceStyle::Default => ("", ""),
+            NamespaceStyle::Prefixed => ("ns:", ""),
+            NamespaceStyle::PrefixedQualified => ("ns:", "ns:"),
+        };
+
+        for param in params.iter() {
+            let element = format!(
+                "<{prefix}{key}>{value}</{prefix}{key}>",
+                prefix = param_prefix,
+                key = param.0,
+                value = Self::escape(param.1)
+            );
+            xml = format!("{}{}", xml, element);
+        }
+
+        xml = format!(
+            "<{prefix}{cmd} xmlns{binding}=\"{ns}\">{params}</{prefix}{cmd}>",
+            prefix = cmd_prefix,
+            binding = if cmd_prefix.is_empty() { "" } else { ":ns" },
+            cmd = cmd,
+            params = xml,
+            ns = self.ns
+        );
+
+        xml
+    }
+
+    // Function to get the full WSDL for the call
+    #[allow(clippy::useless_conversion)] // Identity conversion without the 'zeroize' feature
+    async fn soap_body(&self, cmd_xml: String) -> SecretText {
+        let wsdl = SecretText::from(format!(
+            r#"<?xml version="1.0"?>
+
+                <soap:Envelope
+                xmlns:soap="http://schemas.xmlsoap.org/soap/envelope/"
+                soap:encodingStyle="http://www.w3.org/2003/05/soap-encoding">
+
+                    <soap:Header>
+                        {auth_header}
+                    </soap:Header>
+
+                    <soap:Body>
+                        {cmd}
+                    </soap:Body>
+
+                </soap:Envelope>"#,
+            auth_header = self.auth_header().to_xml().as_str(),
+            cmd = cmd_xml
+        ));
+
+        wsdl
+    }
+
+    // Authentication header for the credentials of this client
+    fn auth_header(&self) -> AuthHeader<'_> {
+        AuthHeader {
+            ns: &self.ns,
+            username: &self.credentials.0,
+            password: &self.credentials.1,
+        }
+    }
+
+    // Envelope with the password masked, safe to attach to a support ticket
+    #[allow(clippy::useless_conversion)] // Identity conversion without the 'zeroize' feature
+    fn redact(&self, soap_body: &str) -> String {
+        let password = SecretText::from(Self::escape(&self.credentials.1));
+        let pattern = SecretText::from(format!("<password>{}</password>", password.as_str()));
+        soap_body.replace(pattern.as_str(), "<password>********</password>")
+    }
+
+    // Escape the characters with a special meaning in XML text
+    fn escape(value: &str) -> String {
+        value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&apos;")
+    }
+}
+
+impl AuthHeader<'_> {
+
+    /*
+        Render the header with the credentials escaped
+        :return SecretText: Returns the 'authenticate_user' element
+    */
+    #[allow(clippy::useless_conversion)] // Identity conversion without the 'zeroize' feature
+    fn to_xml(&self) -> SecretText {
+        let password = SecretText::from(SoapClient::escape(self.password));
+        SecretText::from(format!(
+            r#"<authenticate_user xmlns="{ns}">
+                          <username>{username}</username>
+                          <password>{password}</password>
+                        </authenticate_user>"#,
+            ns = SoapClient::escape(self.ns),
+            username = SoapClient::escape(self.username),
+            password = password.as_str(),
+        ))
+    }
+}
+
+impl<R: std::io::Read> Pairs<R> {
+    fn new(reader: R) -> Self {
+        Pairs {
+            events: EventReader::new(reader).into_iter(),
+            read_key: false,
+            read_value: false,
+            key: String::new(),
+            done: false,
+        }
+    }
+}
+
+impl<R: std::io::Read> Iterator for Pairs<R> {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<(String, String)> {
+        while !self.done {
+            match self.events.next() {
+                Some(Ok(XmlEvent::StartElement {
+                    name, attributes, ..
+                })) => {
+                    self.read_key = false;
+                    self.read_value = false;
+
+                    // Some responses carry the pair as attributes: <item key="id" value="123"/>
+                    let attribute = |local_name: &str| {
+                        attributes
+                            .iter()
+                            .find(|attribute| attribute.name.local_name == local_name)
+                            .map(|attribute| attribute.value.to_string())
+                    };
+                    if let (Some(key), Some(value)) = (attribute("key"), attribute("value")) {
+                        return Some((key, value));
+                    }
+
+                    if name.local_name == "key" {
+                        self.read_key = true;
+                    }
+                    if name.local_name == "value" {
+                        self.read_value = true;
+                    }
+                }
+                Some(Ok(XmlEvent::Characters(text))) => {
+                    if self.read_key {
+                        self.key = text.to_string();
+                    }
+                    if self.read_value {
+                        return Some((self.key.to_string(), text));
+                    }
+                }
+                Some(Err(_)) | None => self.done = true,
+                _ => {}
+            }
+        }
+
+        None
+    }
+}
+
+impl<R: std::io::Read> Records<R> {
+
+    /*
+        Parse the records of a list-response as they are iterated
+        :param reader: XML of the response. E.g: std::io::Cursor::new(body)
+        :return Records: Returns the iterator over the records
+    */
+    pub fn new(reader: R) -> Self {
+        Records {
+            pairs: Pairs::new(reader),
+            pending: None,
+        }
+    }
+}
+
+impl<R: std::io::Read> Iterator for Records<R> {
+    type Item = std::vec::Vec<(String, String)>;
+
+    fn next(&mut self) -> Option<std::vec::Vec<(String, String)>> {
+        let mut record = vec![self.pending.take().or_else(|| self.pairs.next())?];
+
+        for pair in &mut self.pairs {
+            if record.iter().any(|(key, _)| *key == pair.0) {
+                self.pending = Some(pair);
+                break;
+            }
+            record.push(pair);
+        }
+
+        Some(record)
+    }
+}
+
+impl XmlResponseParser {
+    
+    /*
+        :param xml: Takes XML as input. E.g: <item><key>xxx</key><value>yyy</value></item>
+        Pairs given as attributes are read as well. E.g: <item key="xxx" value="yyy"/>
+        :return Vec<(String, String)>: Returns a vector of tuples (key, value)
+    */
+    async fn parse(xml: &str) -> std::vec::Vec<(String, String)> {
+        XmlResponseParser.pairs(xml)
+    }
+
+    /*
+        Returns the value of the matching key
+        The first match wins: single-valued fields appear once, use 'values' for multi-valued fields.
+        :param items: Array of returned_items retrieved from API-response
+        :param key: The key we want to retrieve the value from
+        :return String: Returns the value of the first pair matching the key, empty if there is none
+    */
+    async fn value(items: &std::vec::Vec<(String, String)>, key: &str) -> String {
+        for pair in items.iter() {
+            if pair.0 == key {
+                return pair.1.to_string();
+            }
+        }
+
+        "".to_string()
+    }
+
+    /*
+        Returns all values of the matching key, e.g. the sender numbers of an account
+        :param items: Array of returned_items retrieved from API-response
+        :param key: The key we want to retrieve the values from
+        :return Vec<&str>: Returns the values matching the key, in document order
+    */
+    fn values<'a>(items: &'a [(String, String)], key: &str) -> std::vec::Vec<&'a str> {
+        items
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+}
+
+impl ResponseParser for XmlResponseParser {
+    fn pairs(&self, xml: &str) -> std::vec::Vec<(String, String)> {
+        Pairs::new(xml.trim_start_matches('\u{FEFF}').as_bytes()).collect()
+    }
+
+    /*
+        :param xml: Takes XML with several records as input. E.g: the per-recipient results of a batch
+        :return Vec<Vec<(String, String)>>: Returns a vector of records. A new record starts when a key repeats.
+    */
+    fn records(&self, xml: &str) -> std::vec::Vec<std::vec::Vec<(String, String)>> {
+        Records::new(xml.trim_start_matches('\u{FEFF}').as_bytes()).collect()
+    }
+
+    // <soap:Fault><faultcode>soap:Client</faultcode><faultstring>..</faultstring></soap:Fault>
+    fn fault(&self, xml: &str) -> Option<TigronError> {
+        let mut in_fault = false;
+        let mut element = String::new();
+        let mut code = String::new();
+        let mut reason = String::new();
+        for event in EventReader::new(xml.trim_start_matches('\u{FEFF}').as_bytes()) {
+            match event {
+                Ok(XmlEvent::StartElement { name, .. }) => {
+                    if name.local_name == "Fault" {
+                        in_fault = true;
+                    }
+                    element = name.local_name;
+                }
+                Ok(XmlEvent::Characters(text)) if in_fault => match element.as_str() {
+                    "faultcode" | "Value" if code.is_empty() => code = text.trim().to_string(),
+                    "faultstring" | "Text" => reason = text.trim().to_string(),
+                    _ => {}
+                },
+                Ok(XmlEvent::EndElement { .. }) => element.clear(),
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+
+        if in_fault {
+            Some(TigronError::Fault { code, reason })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    const NS: &str = "https://www.tigron.net/ns/";
+
+    fn client() -> TigronSms {
+        TigronSms::new("user&name".to_string(), "pass<word>".to_string())
+    }
+
+    // (local name, namespace, text) of every element of the envelope, in document order
+    fn elements(xml: &str) -> std::vec::Vec<(String, Option<String>, String)> {
+        let mut elements = std::vec::Vec::new();
+        for e in EventReader::from_str(xml) {
+            match e.expect("envelope is not well-formed XML") {
+                XmlEvent::StartElement { name, .. } => {
+                    elements.push((name.local_name, name.namespace, String::new()))
+                }
+                XmlEvent::Characters(text) => {
+                    if let Some(element) = elements.last_mut() {
+                        element.2 = text;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        elements
+    }
+
+    fn text<'a>(elements: &'a [(String, Option<String>, String)], name: &str) -> &'a str {
+        &elements
+            .iter()
+            .find(|(local_name, _, _)| local_name == name)
+            .unwrap_or_else(|| panic!("no <{}> element", name))
+            .2
+    }
+
+    // Request received by the mock gateway
+    struct Received {
+        head: String,
+        body: String,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.head.lines().skip(1).find_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(name) => {
+                        Some(value.trim())
+                    }
+                    _ => None,
+                }
+            })
+        }
+
+        // Local name of the command in the SOAP body. E.g: send_sms
+        fn command(&self) -> String {
+            let mut in_body = false;
+            for event in EventReader::from_str(&self.body) {
+                if let Ok(XmlEvent::StartElement { name, .. }) = event {
+                    if in_body {
+                        return name.local_name;
+                    }
+                    in_body = name.local_name == "Body";
+                }
+            }
+            String::new()
+        }
+
+        fn values(&self, name: &str) -> std::vec::Vec<String> {
+            let elements = elements(&self.body);
+            elements
+                .into_iter()
+                .filter(|(local_name, _, _)| local_name == name)
+                .map(|(_, _, text)| text)
+                .collect()
+        }
+    }
+
+    // Gateway on a local port, answering every request with the response of the responder
+    // An empty response closes the connection without answering, a failure of the transport.
+    struct MockGateway {
+        url: String,
+        received: Arc<std::sync::Mutex<std::vec::Vec<Received>>>,
+    }
+
+    impl MockGateway {
+        async fn start(
+            responder: impl Fn(&Received) -> std::vec::Vec<u8> + Send + Sync + 'static,
+        ) -> Self {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let received = Arc::new(std::sync::Mutex::new(std::vec::Vec::new()));
+            let responder = Arc::new(responder);
+
+            let log = received.clone();
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let log = log.clone();
+                    let responder = responder.clone();
+                    tokio::spawn(async move {
+                        let mut request = std::vec::Vec::new();
+                        let mut buffer = [0u8; 4096];
+                        let (head, body) = loop {
+                            let read = stream.read(&mut buffer).await.unwrap_or(0);
+                            if read == 0 {
+                                return;
+                            }
+                            request.extend_from_slice(&buffer[..read]);
+                            let text = String::from_utf8_lossy(&request).to_string();
+                            if let Some(end) = text.find("\r\n\r\n") {
+                                let head = text[..end].to_string();
+                                let length = Received {
+                                    head: head.to_string(),
+                                    body: String::new(),
+                                }
+                                .header("Content-Length")
+                                .and_then(|length| length.parse::<usize>().ok())
+                                .unwrap_or(0);
+                                if request.len() >= end + 4 + length {
+                                    break (head, text[end + 4..].to_string());
+                                }
+                            }
+                        };
+
+                        let received = Received { head, body };
+                        let response = responder(&received);
+                        log.lock().unwrap().push(received);
+                        if !response.is_empty() {
+                            let _ = stream.write_all(&response).await;
+                        }
+                    });
+                }
+            });
+
+            MockGateway { url, received }
+        }
+
+        // Client sending to this gateway
+        fn client(&self) -> TigronSms {
+            client().with_environment(Environment::Custom {
+                url: self.url.to_string(),
+                ns: NS.to_string(),
+            })
+        }
+
+        // Commands received so far, in order
+        fn commands(&self) -> std::vec::Vec<String> {
+            self.received
+                .lock()
+                .unwrap()
+                .iter()
+                .map(Received::command)
+                .collect()
+        }
+
+        // SOAP-client of the given client, sending to this gateway
+        fn soap_client<'a>(&self, client: &'a TigronSms) -> SoapClient<'a> {
+            SoapClient {
+                url: self.url.to_string(),
+                ..client.soap_client().unwrap()
+            }
+        }
+    }
+
+    fn http_response(status: &str, content_type: &str, body: &[u8]) -> std::vec::Vec<u8> {
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            content_type,
+            body.len()
+        );
+        [head.as_bytes(), body].concat()
+    }
+
+    // SOAP response with one <item> per (key, value)
+    fn soap_response(pairs: &[(&str, &str)]) -> std::vec::Vec<u8> {
+        let items: String = pairs
+            .iter()
+            .map(|(key, value)| format!("<item><key>{}</key><value>{}</value></item>", key, value))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><SOAP-ENV:Envelope xmlns:SOAP-ENV=\"http://schemas.xmlsoap.org/soap/envelope/\"><SOAP-ENV:Body><ns1:response xmlns:ns1=\"{}\"><return>{}</return></ns1:response></SOAP-ENV:Body></SOAP-ENV:Envelope>",
+            NS, items
+        );
+        http_response("200 OK", "text/xml; charset=utf-8", body.as_bytes())
+    }
+
+    // Answer of 'info', the user_id lookup
+    fn info_response() -> std::vec::Vec<u8> {
+        soap_response(&[("id", "42"), ("name", "user")])
+    }
+
+    #[tokio::test]
+    async fn send_sms_envelope_contains_auth_header_command_and_parameters() {
+        let envelope = client()
+            .envelope(
+                "send_sms",
+                vec![
+                    ("message", "Fish & chips"),
+                    ("to", "+32.470123456"),
+                    ("from", "+32.470654321"),
+                    ("user_id", "42"),
+                ],
+            )
+            .await
+            .unwrap();
+        let elements = elements(&envelope);
+
+        assert_eq!(text(&elements, "username"), "user&name");
+        assert_eq!(text(&elements, "password"), "********");
+
+        let command = elements
+            .iter()
+            .find(|(local_name, _, _)| local_name == "send_sms")
+            .expect("no <send_sms> element");
+        assert_eq!(command.1.as_deref(), Some(NS));
+
+        assert_eq!(text(&elements, "user_id"), "42");
+        assert_eq!(text(&elements, "from"), "+32.470654321");
+        assert_eq!(text(&elements, "to"), "+32.470123456");
+        assert_eq!(text(&elements, "message"), "Fish & chips");
+
+        // Parameters follow the schema order, not the order they were given in
+        let order: std::vec::Vec<&str> = elements
+            .iter()
+            .map(|(local_name, _, _)| local_name.as_str())
+            .filter(|local_name| SEND_SMS_PARAMETER_ORDER.contains(local_name))
+            .collect();
+        assert_eq!(order, ["user_id", "from", "to", "message"]);
+    }
+
+    #[tokio::test]
+    async fn user_info_envelope_contains_auth_header_and_command() {
+        let envelope = client().envelope("info", std::vec::Vec::<(&str, &str)>::new()).await.unwrap();
+        let elements = elements(&envelope);
+
+        assert_eq!(text(&elements, "username"), "user&name");
+        assert_eq!(text(&elements, "password"), "********");
+
+        let names: std::vec::Vec<&str> = elements
+            .iter()
+            .map(|(local_name, _, _)| local_name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "Envelope",
+                "Header",
+                "authenticate_user",
+                "username",
+                "password",
+                "Body",
+                "info"
+            ]
+        );
+
+        let command = elements.last().unwrap();
+        assert_eq!(command.1.as_deref(), Some(NS));
+        assert_eq!(elements[2].1.as_deref(), Some(NS));
+    }
+
+    #[tokio::test]
+    async fn duplicate_keys_keep_first_value_and_expose_all_values() {
+        let xml = "<items>\
+            <item><key>id</key><value>42</value></item>\
+            <item><key>sender</key><value>+32.470123456</value></item>\
+            <item><key>sender</key><value>+32.470654321</value></item>\
+            </items>";
+        let items = XmlResponseParser::parse(xml).await;
+
+        assert_eq!(XmlResponseParser::value(&items, "sender").await, "+32.470123456");
+        assert_eq!(
+            XmlResponseParser::values(&items, "sender"),
+            ["+32.470123456", "+32.470654321"]
+        );
+        assert!(XmlResponseParser::values(&items, "missing").is_empty());
+    }
+
+    #[tokio::test]
+    async fn bulk_envelope_repeats_the_recipient_element() {
+        let envelope = client()
+            .envelope(
+                "send_sms",
+                vec![
+                    ("user_id", "42"),
+                    ("from", "+32.470654321"),
+                    ("message", "Hello"),
+                    ("to", "+32.470000001"),
+                    ("to", "+32.470000002"),
+                    ("to", "+32.470000003"),
+                ],
+            )
+            .await
+            .unwrap();
+        let elements = elements(&envelope);
+
+        let recipients: std::vec::Vec<&str> = elements
+            .iter()
+            .filter(|(local_name, _, _)| local_name == "to")
+            .map(|(_, _, text)| text.as_str())
+            .collect();
+        assert_eq!(
+            recipients,
+            ["+32.470000001", "+32.470000002", "+32.470000003"]
+        );
+        assert_eq!(text(&elements, "from"), "+32.470654321");
+        assert_eq!(text(&elements, "message"), "Hello");
+    }
+
+    #[tokio::test]
+    async fn bulk_response_is_split_into_one_record_per_recipient() {
+        let xml = "<items>\
+            <item><key>to</key><value>+32.470000001</value></item>\
+            <item><key>status</key><value>accepted</value></item>\
+            <item><key>id</key><value>1</value></item>\
+            <item><key>to</key><value>+32.470000002</value></item>\
+            <item><key>status</key><value>rejected</value></item>\
+            <item><key>code</key><value>invalid_number</value></item>\
+            </items>";
+        let records = XmlResponseParser.records(xml);
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(XmlResponseParser::value(&records[0], "id").await, "1");
+        assert_eq!(
+            XmlResponseParser::value(&records[1], "code").await,
+            "invalid_number"
+        );
+    }
+
+    #[test]
+    fn config_is_deserialized_and_masks_the_password() {
+        let config: TigronConfig = serde_json::from_str(
+            r#"{"username": "user", "password": "secret", "url": "http://localhost:8080/soap/", "retries": 2}"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.retries, Some(2));
+        assert_eq!(config.deadline_ms, None);
+        assert!(!format!("{:?}", config).contains("secret"));
+
+        let client = TigronSms::from_config(config).unwrap();
+        assert_eq!(client.username(), "user");
+        assert_eq!(client.retry.max_retries, 2);
+        assert_eq!(client.environment.url(), "http://localhost:8080/soap");
+        assert_eq!(client.environment.ns(), NS);
+    }
+
+    #[test]
+    fn only_transient_rejection_codes_are_retried() {
+        let client = client();
+
+        assert_eq!(client.retryability("throttled"), Retryability::Transient);
+        assert_eq!(client.retryability("THROTTLED"), Retryability::Transient);
+        assert_eq!(client.retryability("invalid_number"), Retryability::Permanent);
+        assert_eq!(client.retryability("never_seen"), Retryability::Permanent);
+
+        let client = client.with_retryability(
+            vec![("Invalid_Number".to_string(), Retryability::Transient)]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(client.retryability("invalid_number"), Retryability::Transient);
+        assert_eq!(client.retryability("INVALID_NUMBER"), Retryability::Transient);
+        assert_eq!(client.retryability("throttled"), Retryability::Permanent);
+    }
+
+    struct FixedShortener;
+
+    impl UrlShortener for FixedShortener {
+        fn shorten<'a>(&'a self, _url: &'a str) -> BoxFuture<'a, Result<String, TigronError>> {
+            Box::pin(futures::future::ready(Ok("https://t.ly/a1".to_string())))
+        }
+    }
+
+    #[tokio::test]
+    async fn url_shortener_rewrites_urls_before_segments_are_counted() {
+        let message = format!(
+            "Our sale starts today, see https://shop.example.com/campaigns/autumn?utm_source=sms&utm_medium=text&utm_campaign={}.\nReply STOP to opt out",
+            "a".repeat(60)
+        );
+        assert_eq!(TigronSms::segments(&message), 2);
+
+        let shortened = client()
+            .with_url_shortener(Arc::new(FixedShortener))
+            .shorten_urls(&message)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            shortened,
+            "Our sale starts today, see https://t.ly/a1.\nReply STOP to opt out"
+        );
+        assert_eq!(TigronSms::segments(&shortened), 1);
+        assert_eq!(client().shorten_urls(&message).await.unwrap(), message);
+    }
+
+    #[tokio::test]
+    async fn list_response_without_parameters_is_parsed_into_maps() {
+        let envelope = client().envelope("get_senders", std::vec::Vec::<(&str, &str)>::new()).await.unwrap();
+        let names: std::vec::Vec<String> = elements(&envelope)
+            .into_iter()
+            .skip_while(|(local_name, _, _)| local_name != "Body")
+            .map(|(local_name, _, _)| local_name)
+            .collect();
+        assert_eq!(names, ["Body", "get_senders"]);
+
+        let xml = "<items>\
+            <item><key>number</key><value>+32.470000001</value></item>\
+            <item><key>status</key><value>active</value></item>\
+            <item><key>number</key><value>+32.470000002</value></item>\
+            <item><key>status</key><value>pending</value></item>\
+            </items>";
+        let records = client().parse_maps(xml).unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["number"], "+32.470000001");
+        assert_eq!(records[0]["status"], "active");
+        assert_eq!(records[1]["number"], "+32.470000002");
+        assert_eq!(records[1]["status"], "pending");
+    }
+
+    #[tokio::test]
+    async fn calls_above_the_in_flight_cap_wait_for_a_free_slot() {
+        use futures::FutureExt;
+
+        let client = client().with_max_concurrent(2);
+        let soap_client = client.soap_client().unwrap();
+
+        let first = soap_client.permit().await;
+        let second = soap_client.permit().await;
+        assert!(first.is_some() && second.is_some());
+
+        // A clone shares the cap
+        let clone = client.clone();
+        let clone_soap_client = clone.soap_client().unwrap();
+        let mut third = Box::pin(clone_soap_client.permit());
+        assert!((&mut third).now_or_never().is_none());
+
+        drop(first);
+        assert!(third.now_or_never().is_some());
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_the_calls_of_every_clone() {
+        use futures::FutureExt;
+
+        let client = client();
+        let running = Activity::start(&client.clone().activity);
+        let mut shutdown = Box::pin(client.shutdown());
+        assert!((&mut shutdown).now_or_never().is_none());
+
+        drop(running);
+        assert!(shutdown.now_or_never().is_some());
+    }
+
+    #[tokio::test]
+    async fn an_in_flight_cap_of_zero_lets_one_call_through() {
+        use futures::FutureExt;
+
+        let client = client().with_max_concurrent(0);
+        let soap_client = client.soap_client().unwrap();
+
+        let first = soap_client.permit().await;
+        assert!(first.is_some());
+        assert!(soap_client.permit().now_or_never().is_none());
+    }
+
+    #[test]
+    fn diagnosis_report_is_displayed_one_step_per_line() {
+        let report = DiagnosisReport {
+            steps: vec![
+                DiagnosisStep {
+                    name: "credentials",
+                    status: DiagnosisStatus::Passed("username 'user'".to_string()),
+                    elapsed: Duration::from_millis(0),
+                },
+                DiagnosisStep {
+                    name: "user_id",
+                    status: DiagnosisStatus::Failed("User not found.".to_string()),
+                    elapsed: Duration::from_millis(120),
+                },
+                DiagnosisStep {
+                    name: "balance",
+                    status: DiagnosisStatus::Skipped,
+                    elapsed: Duration::from_millis(0),
+                },
+            ],
+        };
+        let lines: std::vec::Vec<String> = report.to_string().lines().map(str::to_string).collect();
+
+        assert!(!report.is_ok());
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("credentials") && lines[0].contains("ok"));
+        assert!(lines[1].contains("FAILED") && lines[1].contains("120 ms"));
+        assert!(lines[2].contains("skipped"));
+        assert_eq!(lines[3], "Setup is broken at the first failed step.");
+    }
+
+    #[tokio::test]
+    async fn pages_are_fetched_until_an_empty_page() {
+        use futures::StreamExt;
+
+        let fetched = Arc::new(Mutex::new(std::vec::Vec::new()));
+        let pages = {
+            let fetched = fetched.clone();
+            paginate(move |page| {
+                fetched.lock().unwrap().push(page);
+                let items = match page {
+                    1 => vec!["a", "b"],
+                    2 => vec!["c"],
+                    _ => vec![],
+                };
+                Box::pin(futures::future::ready(Ok(items)))
+            })
+        };
+
+        let items: std::vec::Vec<&str> = pages.map(|item| item.unwrap()).collect().await;
+
+        assert_eq!(items, ["a", "b", "c"]);
+        assert_eq!(*fetched.lock().unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn normalization_keeps_pasted_text_in_gsm7() {
+        let substituted = Arc::new(Mutex::new(std::vec::Vec::new()));
+        let client = {
+            let substituted = substituted.clone();
+            client()
+                .with_normalize_text(true)
+                .with_on_substitution(Arc::new(move |substitutions: &[(char, &'static str)]| {
+                    substituted.lock().unwrap().extend_from_slice(substitutions)
+                }))
+        };
+
+        let pasted = "\u{201C}Don\u{2019}t miss it\u{201D} \u{2013} 20%\u{00A0}off\u{2026}";
+        let normalized = client.normalize(pasted);
+
+        assert_eq!(normalized, "\"Don't miss it\" - 20% off...");
+        assert!(normalized.chars().all(|c| GSM7_CHARACTERS.contains(c)));
+        assert_eq!(substituted.lock().unwrap().len(), 6);
+
+        // An emoji keeps the message in UCS-2, so the text is left as written
+        let emoji = "\u{2019}Tis the season \u{1F384}";
+        assert_eq!(client.normalize(emoji), emoji);
+    }
+
+    #[test]
+    fn connection_settings_after_first_use_build_a_new_http_client() {
+        let client = client().with_family_failover(true);
+        client.soap_client().unwrap();
+        assert!(client.http.get().is_some() && client.http_by_family.get().is_some());
+
+        let earlier = client.clone();
+        let client = client.with_connect_timeout(Duration::from_secs(3));
+        assert!(client.http.get().is_none() && client.http_by_family.get().is_none());
+        assert!(earlier.http.get().is_some());
+    }
+
+    #[test]
+    fn family_failover_alternates_between_ipv4_and_ipv6() {
+        let ipv4 = std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED);
+        let ipv6 = std::net::IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED);
+
+        assert_eq!(alternate_family(None), ipv4);
+        assert_eq!(alternate_family(Some(ipv4)), ipv6);
+        assert_eq!(alternate_family(Some(ipv6)), ipv4);
+        assert_eq!(alternate_family(Some("192.0.2.10".parse().unwrap())), ipv6);
+
+        let client = client().with_family_failover(true);
+        let soap_client = client.soap_client().unwrap();
+        assert!(soap_client.http_by_family.is_some());
+
+        let client = client.with_family_failover(false);
+        assert!(client.soap_client().unwrap().http_by_family.is_none());
+    }
+
+    #[test]
+    fn errors_suggest_an_http_status() {
+        let throttled = TigronError::SendRejected {
+            code: "throttled".to_string(),
+            reason: "Too many messages".to_string(),
+        };
+        let blacklisted = TigronError::SendRejected {
+            code: "blacklisted".to_string(),
+            reason: "Number opted out".to_string(),
+        };
+
+        assert_eq!(TigronError::InvalidNumber("+32".to_string()).suggested_http_status(), 400);
+        assert_eq!(throttled.suggested_http_status(), 429);
+        assert_eq!(blacklisted.suggested_http_status(), 422);
+        assert_eq!(TigronError::HttpStatus { status: 429 }.suggested_http_status(), 429);
+        assert_eq!(TigronError::HttpStatus { status: 500 }.suggested_http_status(), 502);
+        assert_eq!(TigronError::UserNotFound.suggested_http_status(), 502);
+        assert_eq!(TigronError::CircuitOpen.suggested_http_status(), 503);
+        assert_eq!(
+            TigronError::Shared(Arc::new(TigronError::DeadlineExceeded)).suggested_http_status(),
+            504
+        );
+    }
+
+    #[tokio::test]
+    async fn prepared_envelope_carries_the_credentials_unmasked() {
+        let params = vec![("user_id", "42"), ("to", "+32.470123456")];
+        let prepared = client().prepare_envelope("send_sms", params.clone()).await.unwrap();
+        let elements = elements(&prepared);
+
+        assert_eq!(text(&elements, "username"), "user&name");
+        assert_eq!(text(&elements, "password"), "pass<word>");
+        assert_eq!(
+            client().envelope("send_sms", params).await.unwrap(),
+            prepared.replace("pass&lt;word&gt;", "********")
+        );
+    }
+
+    #[test]
+    fn country_rate_limits_space_sends_per_country() {
+        let belgium = country_of("+32.470123456").unwrap();
+        let netherlands = country_of("+31.612345678").unwrap();
+        let client = client().with_country_rate_limits(
+            vec![(belgium, 4), (netherlands, 1)].into_iter().collect(),
+        );
+        let limiter = client.country_rate_limits.as_ref().unwrap();
+        let now = Instant::now();
+
+        let waits: std::vec::Vec<u128> = (0..3)
+            .map(|_| limiter.reserve(belgium, now, None).unwrap().as_millis())
+            .collect();
+        assert_eq!(waits, [0, 250, 500]);
+
+        // The Netherlands have their own slots, unaffected by the Belgian queue
+        assert_eq!(limiter.reserve(netherlands, now, None).unwrap().as_millis(), 0);
+        assert_eq!(limiter.reserve(netherlands, now, None).unwrap().as_millis(), 1000);
+
+        // Slots free up as time passes, unlimited countries never wait
+        assert_eq!(limiter
+                .reserve(belgium, now + Duration::from_secs(2), None)
+                .unwrap()
+                .as_millis(), 0);
+        let france = country_of("+33.612345678").unwrap();
+        assert_eq!(limiter.reserve(france, now, None).unwrap().as_millis(), 0);
+    }
+
+    #[test]
+    fn only_failures_that_may_pass_on_another_try_are_retryable() {
+        assert!(TigronError::DeadlineExceeded.is_retryable());
+        assert!(TigronError::HttpStatus { status: 503 }.is_retryable());
+        assert!(TigronError::HttpStatus { status: 429 }.is_retryable());
+        assert!(!TigronError::HttpStatus { status: 404 }.is_retryable());
+        assert!(TigronError::SendRejected {
+            code: "congestion".to_string(),
+            reason: String::new(),
+        }
+        .is_retryable());
+        assert!(!TigronError::SendRejected {
+            code: "invalid_number".to_string(),
+            reason: String::new(),
+        }
+        .is_retryable());
+        assert!(!TigronError::InvalidNumber("+32".to_string()).is_retryable());
+        assert!(TigronError::Shared(Arc::new(TigronError::CircuitOpen)).is_retryable());
+    }
+
+    #[tokio::test]
+    async fn byte_order_mark_before_the_response_is_ignored() {
+        let xml = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+            <soap:Envelope xmlns:soap=\"http://schemas.xmlsoap.org/soap/envelope/\"><soap:Body>\
+            <info_response><return>\
+            <item><key>id</key><value>42</value></item>\
+            </return></info_response>\
+            </soap:Body></soap:Envelope>";
+        let body = [b"\xEF\xBB\xBF".as_ref(), xml.as_bytes()].concat();
+
+        let decoded = SoapClient::decode(&body, Some("text/xml; charset=utf-8"));
+        assert_eq!(decoded, xml);
+        assert!(SoapClient::ensure_xml(&decoded, Some("text/xml")).is_ok());
+
+        let items = XmlResponseParser::parse(&decoded).await;
+        assert_eq!(XmlResponseParser::value(&items, "id").await, "42");
+
+        // A body decoded elsewhere may still carry it as a character
+        let items = XmlResponseParser::parse(&format!("\u{FEFF}{}", xml)).await;
+        assert_eq!(XmlResponseParser::value(&items, "id").await, "42");
+    }
+
+    #[tokio::test]
+    async fn captured_xml_masks_numbers_and_hides_the_message() {
+        let envelope = client()
+            .envelope(
+                "send_sms",
+                vec![
+                    ("user_id", "42"),
+                    ("from", "+32.470654321"),
+                    ("to", "+32470123456"),
+                    ("message", "Your code is 1234"),
+                ],
+            )
+            .await
+            .unwrap();
+
+        let redacted = redact_pii(&envelope, PiiPolicy::default());
+        let elements = elements(&redacted);
+        assert_eq!(text(&elements, "user_id"), "42");
+        assert_eq!(text(&elements, "from"), "+32.470***321");
+        assert_eq!(text(&elements, "to"), "+324***456");
+        assert_eq!(text(&elements, "message"), "[redacted]");
+
+        let readable = PiiPolicy {
+            mask_numbers: false,
+            log_message: true,
+        };
+        assert_eq!(redact_pii(&envelope, readable), envelope);
+
+        // Arabic-Indic digits take two bytes each
+        let to = "<to>+\u{663}\u{662}.\u{664}\u{667}\u{660}\u{661}\u{662}\u{663}\u{664}</to>";
+        assert_eq!(
+            redact_pii(to, PiiPolicy::default()),
+            "<to>+\u{663}\u{662}.\u{664}\u{667}\u{660}***\u{662}\u{663}\u{664}</to>"
+        );
+    }
+
+    #[tokio::test]
+    async fn account_info_is_parsed_from_the_info_response() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <SOAP-ENV:Envelope xmlns:SOAP-ENV="http://schemas.xmlsoap.org/soap/envelope/">
+              <SOAP-ENV:Body>
+                <ns1:infoResponse xmlns:ns1="https://www.tigron.net/ns/">
+                  <return>
+                    <item><key>id</key><value>4242</value></item>
+                    <item><key>name</key><value>ACME Belgium BV</value></item>
+                    <item><key>balance</key><value>125.50</value></item>
+                    <item><key>sender</key><value>+32.470000001</value></item>
+                    <item><key>sender</key><value>ACME</value></item>
+                    <item><key>daily_limit</key><value>5000</value></item>
+                    <item><key>rate_limit</key><value>10</value></item>
+                    <item><key>language</key><value>nl</value></item>
+                  </return>
+                </ns1:infoResponse>
+              </SOAP-ENV:Body>
+            </SOAP-ENV:Envelope>"#;
+        let info = AccountInfo::from_xml(xml).await;
+
+        assert_eq!(info.id, "4242");
+        assert_eq!(info.name.as_deref(), Some("ACME Belgium BV"));
+        assert_eq!(info.balance, "125.50".parse().ok());
+        assert_eq!(info.senders, ["+32.470000001", "ACME"]);
+        assert_eq!(info.limits.len(), 2);
+        assert_eq!(info.limits["daily_limit"], 5000);
+        assert_eq!(info.limits["rate_limit"], 10);
+
+        let empty = AccountInfo::from_xml("<return/>").await;
+        assert_eq!(empty.id, "");
+        assert_eq!(empty.balance, None);
+        assert!(empty.senders.is_empty());
+    }
+
+    #[tokio::test]
+    async fn numbers_are_trimmed_and_messages_only_on_request() {
+        assert_eq!(
+            TigronSms::normalize_number(" +32.470123456\r\n").unwrap(),
+            "+32.470123456"
+        );
+        assert_eq!(
+            TigronSms::normalize_number("\t+32470123456 ").unwrap(),
+            "+32.470123456"
+        );
+        assert!(TigronSms::normalize_number(" +32 ").is_err());
+
+        let message = "  Hello world! \n";
+        assert_eq!(client().prepare_message(message).await.unwrap(), message);
+        assert_eq!(
+            client()
+                .with_trim_message(true)
+                .prepare_message(message)
+                .await
+                .unwrap(),
+            "Hello world!"
+        );
+    }
+
+    #[tokio::test]
+    async fn prefix_and_suffix_count_towards_the_segments() {
+        let client = client()
+            .with_message_prefix("ACME: ".to_string())
+            .with_message_suffix("\nReply STOP to unsubscribe".to_string());
+        let message = "a".repeat(140);
+
+        let framed = client.prepare_message(&message).await.unwrap();
+
+        assert_eq!(framed, format!("ACME: {}\nReply STOP to unsubscribe", message));
+        assert_eq!(TigronSms::segments(&message), 1);
+        assert_eq!(TigronSms::segments(&framed), 2);
+        assert!(client.validate(&framed, &SendOptions::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn accepted_at_is_taken_from_the_gateway() {
+        let items = |accepted_at: &str| {
+            vec![
+                ("status".to_string(), "accepted".to_string()),
+                ("id".to_string(), "1".to_string()),
+                ("accepted_at".to_string(), accepted_at.to_string()),
+            ]
+        };
+        let accepted_at = |outcome: SendOutcome| match outcome {
+            SendOutcome::Accepted { accepted_at, .. } => accepted_at,
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        };
+
+        let outcome = SendOutcome::from_response(&items("2026-10-15 09:30:00"), "r").await;
+        assert_eq!(
+            accepted_at(outcome.unwrap()),
+            Some(Utc.with_ymd_and_hms(2026, 10, 15, 9, 30, 0).unwrap())
+        );
+
+        let outcome = SendOutcome::from_response(&items("2026-10-15T11:30:00+02:00"), "r").await;
+        assert_eq!(
+            accepted_at(outcome.unwrap()),
+            Some(Utc.with_ymd_and_hms(2026, 10, 15, 9, 30, 0).unwrap())
+        );
+
+        let outcome = SendOutcome::from_response(&items("yesterday"), "r").await;
+        assert_eq!(accepted_at(outcome.unwrap()), None);
+    }
+
+    #[test]
+    fn blocking_send_runs_without_a_runtime() {
+        // Fails on the number before the gateway is contacted, but only after the runtime is up
+        let result = client().send_blocking(
+            "not a number".to_string(),
+            "+32.470654321".to_string(),
+            "Hello".to_string(),
+        );
+
+        assert!(matches!(result, Err(TigronError::InvalidNumber(_))));
+    }
+
+    #[tokio::test]
+    async fn blocking_send_is_refused_inside_a_runtime() {
+        let result = client().send_blocking(
+            "+32.470123456".to_string(),
+            "+32.470654321".to_string(),
+            "Hello".to_string(),
+        );
+
+        assert!(matches!(result, Err(TigronError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn param_values_are_rendered_canonically() {
+        assert_eq!(ParamValue::from("+32.470123456").to_string(), "+32.470123456");
+        assert_eq!(ParamValue::from("a & b".to_string()).to_string(), "a & b");
+        assert_eq!(ParamValue::from(-42).to_string(), "-42");
+        assert_eq!(ParamValue::from(7u32).to_string(), "7");
+        assert_eq!(ParamValue::from(2.5).to_string(), "2.5");
+        assert_eq!(ParamValue::from(true).to_string(), "1");
+        assert_eq!(ParamValue::from(false).to_string(), "0");
+
+        let envelope = client()
+            .envelope(
+                "get_list",
+                vec![
+                    ("user_id", ParamValue::from("42")),
+                    ("page", ParamValue::Int(2)),
+                    ("unread", ParamValue::Bool(true)),
+                ],
+            )
+            .await
+            .unwrap();
+        let elements = elements(&envelope);
+        assert_eq!(text(&elements, "page"), "2");
+        assert_eq!(text(&elements, "unread"), "1");
+    }
+
+    #[test]
+    fn refused_batch_books_no_slot_for_the_other_countries() {
+        let belgium = country_of("+32.470123456").unwrap();
+        let netherlands = country_of("+31.612345678").unwrap();
+        let limiter = CountryRateLimiter {
+            limits: vec![(belgium, 1), (netherlands, 1)].into_iter().collect(),
+            next_slot: Mutex::new(HashMap::new()),
+        };
+        let now = Instant::now();
+
+        // The third Belgian recipient would be the second one waiting
+        let batch = [netherlands, belgium, belgium, belgium];
+        assert!(matches!(
+            limiter.reserve_all(&batch, now, Some(1)),
+            Err(TigronError::RateLimited { country }) if country == belgium
+        ));
+        assert_eq!(limiter.reserve(netherlands, now, None).unwrap().as_millis(), 0);
+        assert_eq!(limiter.reserve(belgium, now, None).unwrap().as_millis(), 0);
+
+        assert_eq!(limiter.reserve_all(&batch[..3], now, None).unwrap().as_millis(), 2000);
+    }
+
+    #[tokio::test]
+    async fn saturated_country_blocks_or_rejects() {
+        let belgium = country_of("+32.470123456").unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let limits: HashMap<CountryCode, u32> = vec![(belgium, 1)].into_iter().collect();
+
+        // Reject: of sends arriving together, the first goes now, two more may wait, the fourth is shed
+        let client = client()
+            .with_clock(clock.clone())
+            .with_country_rate_limits(limits.clone())
+            .with_rate_limit_behavior(RateLimitBehavior::Reject, 2);
+        let limiter = client.country_rate_limits.as_ref().unwrap();
+        let now = clock.now();
+        for _ in 0..3 {
+            limiter.reserve(belgium, now, Some(2)).unwrap();
+        }
+        assert!(matches!(
+            limiter.reserve(belgium, now, Some(2)),
+            Err(TigronError::RateLimited { country }) if country == belgium
+        ));
+
+        // Once time passes the queue drains, other countries are never affected
+        assert!(client.wait_for_country_slot(&["+31.612345678"]).await.is_ok());
+        clock.advance(Duration::from_secs(1));
+        assert!(client.wait_for_country_slot(&["+32.470123456"]).await.is_ok());
+
+        // Block: every send waits its turn, the fake clock advances by the waits
+        let clock = Arc::new(ManualClock::new());
+        let started = clock.now();
+        let client = client()
+            .with_clock(clock.clone())
+            .with_country_rate_limits(limits)
+            .with_rate_limit_behavior(RateLimitBehavior::Block, 2);
+        for _ in 0..4 {
+            client.wait_for_country_slot(&["+32.470123456"]).await.unwrap();
+        }
+        assert_eq!(clock.now() - started, Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn bulk_send_returns_every_message_id() {
+        let items = vec![
+            ("status".to_string(), "accepted".to_string()),
+            ("id".to_string(), "101".to_string()),
+            ("id".to_string(), "102".to_string()),
+            ("id".to_string(), "103".to_string()),
+        ];
+        match SendOutcome::from_response(&items, "r").await.unwrap() {
+            SendOutcome::Accepted { id, message_ids, .. } => {
+                assert_eq!(id, "101");
+                assert_eq!(message_ids, vec!["101", "102", "103"]);
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        let items = vec![
+            ("status".to_string(), "queued".to_string()),
+            ("id".to_string(), "7".to_string()),
+        ];
+        match SendOutcome::from_response(&items, "r").await.unwrap() {
+            SendOutcome::Queued { message_ids, .. } => assert_eq!(message_ids, vec!["7"]),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn batch_is_validated_per_recipient() {
+        let recipients = vec![
+            " +32.470123456".to_string(),
+            "0470123456".to_string(),
+            "+32470123456".to_string(),
+        ];
+        let results = TigronSms::validate_batch(&recipients);
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), "+32.470123456");
+        assert!(matches!(results[1], Err(TigronError::InvalidNumber(_))));
+        assert_eq!(results[2].as_ref().unwrap(), "+32.470123456");
+    }
+
+    #[tokio::test]
+    async fn response_parser_can_be_replaced() {
+        // Parser of a compatible endpoint answering with <field name=".."> elements
+        struct FieldParser;
+        impl ResponseParser for FieldParser {
+            fn pairs(&self, xml: &str) -> std::vec::Vec<(String, String)> {
+                regex::Regex::new(r#"<field name="(\w+)">([^<]*)</field>"#)
+                    .unwrap()
+                    .captures_iter(xml)
+                    .map(|captures| (captures[1].to_string(), captures[2].to_string()))
+                    .collect()
+            }
+            fn records(&self, xml: &str) -> std::vec::Vec<std::vec::Vec<(String, String)>> {
+                vec![self.pairs(xml)]
+            }
+            fn fault(&self, _xml: &str) -> Option<TigronError> {
+                None
+            }
+        }
+
+        let xml = r#"<result><field name="id">42</field><field name="name">ACME</field></result>"#;
+        let client = client().with_response_parser(Arc::new(FieldParser));
+        let account = AccountInfo::from_items(&client.parse_response(xml).unwrap()).await;
+        assert_eq!(account.id, "42");
+        assert_eq!(account.name.as_deref(), Some("ACME"));
+
+        let fault = "<soap:Envelope xmlns:soap=\"http://schemas.xmlsoap.org/soap/envelope/\"><soap:Body>\
+            <soap:Fault><faultcode>soap:Client</faultcode><faultstring>Unknown user</faultstring></soap:Fault>\
+            </soap:Body></soap:Envelope>";
+        match client().parse_response(fault) {
+            Err(TigronError::Fault { code, reason }) => {
+                assert_eq!(code, "soap:Client");
+                assert_eq!(reason, "Unknown user");
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(XmlResponseParser.fault(&format!("<items>{}</items>", xml)).is_none());
+    }
+
+    #[tokio::test]
+    async fn bytes_that_are_not_utf8_are_refused() {
+        let client = client();
+        let to = "+32.470123456".to_string();
+        let from = "+32.470654321".to_string();
+
+        for bytes in [&b"caf\xe9"[..], b"\xff\xfe", b"ok \xf0\x9f\x98"].iter() {
+            match client.send_bytes(to.clone(), from.clone(), bytes).await {
+                Err(e @ TigronError::InvalidUtf8(_)) => {
+                    assert_eq!(e.suggested_http_status(), 400);
+                    assert!(!e.is_retryable());
+                }
+                result => panic!("unexpected result {:?}", result),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unregistered_sender_is_refused_before_sending() {
+        let client = client().with_sender_check(true);
+        *client.sender_numbers.lock().unwrap() =
+            Some((vec!["+32.470000001".to_string()], client.clock.now()));
+
+        assert!(client.check_sender("+32.470000001").await.is_ok());
+        match client.check_sender("+32.470000002").await {
+            Err(TigronError::UnregisteredSender { from }) => assert_eq!(from, "+32.470000002"),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let outcome = client
+            .send(
+                "+32.470123456".to_string(),
+                "+32470000002".to_string(),
+                "Hello".to_string(),
+            )
+            .await;
+        assert!(matches!(outcome, Err(TigronError::UnregisteredSender { .. })));
+
+        // Without the check the list is never consulted
+        assert!(client().check_sender("+32.470000002").await.is_ok());
+    }
+
+    #[test]
+    fn backoff_never_exceeds_the_cap() {
+        let client = client().with_retries(100, Duration::from_millis(200));
+        assert_eq!(client.retry.delay(0), Duration::from_millis(200));
+        assert_eq!(client.retry.delay(2), Duration::from_millis(800));
+        for attempt in 0..100 {
+            assert!(client.retry.delay(attempt) <= DEFAULT_MAX_BACKOFF);
+        }
+        assert_eq!(client.retry.delay(99), DEFAULT_MAX_BACKOFF);
+
+        let client = client.with_max_backoff(Duration::from_secs(1));
+        for attempt in 0..100 {
+            assert!(client.retry.delay(attempt) <= Duration::from_secs(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn content_filter_sees_the_framed_message() {
+        let client = client()
+            .with_message_suffix(" Visit rival.example".to_string())
+            .with_content_filter(Arc::new(|message: &str| {
+                if message.contains("rival") {
+                    Err("mentions a competitor".to_string())
+                } else {
+                    Ok(())
+                }
+            }));
+
+        match client.prepare_message("Your code is 1234").await {
+            Err(TigronError::ContentRejected(reason)) => assert_eq!(reason, "mentions a competitor"),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let outcome = client
+            .send(
+                "+32.470123456".to_string(),
+                "+32.470654321".to_string(),
+                "Your code is 1234".to_string(),
+            )
+            .await;
+        assert!(matches!(outcome, Err(TigronError::ContentRejected(_))));
+
+        let client = client.with_message_suffix(String::new());
+        assert_eq!(
+            client.prepare_message("Your code is 1234").await.unwrap(),
+            "Your code is 1234"
+        );
+    }
+
+    #[test]
+    fn latency_percentiles_are_taken_from_a_bounded_window() {
+        assert_eq!(client().observed_latency_percentiles(), Percentiles::default());
+
+        let client = client().with_latency_tracking(100);
+        let latencies = client.latencies.as_ref().unwrap();
+        for seconds in 1..=150 {
+            latencies.record(Duration::from_secs(seconds));
+        }
+
+        // Only the latest 100 (51..=150 seconds) are kept
+        let percentiles = client.observed_latency_percentiles();
+        assert_eq!(percentiles.count, 100);
+        assert_eq!(percentiles.p50, Duration::from_secs(100));
+        assert_eq!(percentiles.p90, Duration::from_secs(140));
+        assert_eq!(percentiles.p99, Duration::from_secs(149));
+        assert_eq!(percentiles.max, Duration::from_secs(150));
+
+        // Clones share the observations
+        assert_eq!(client.clone().observed_latency_percentiles(), percentiles);
+    }
+
+    #[test]
+    fn dot_is_placed_after_the_country_code() {
+        for number in ["+32470123456", "+32.470123456", "+3.2.470123456", "+32.470.123.456"].iter() {
+            assert_eq!(TigronSms::normalize_number(number).unwrap(), "+32.470123456");
+        }
+
+        for number in ["+.32470123456", "+32..470123456", "+32470123456.", ".+32470123456"].iter() {
+            assert!(matches!(
+                TigronSms::normalize_number(number),
+                Err(TigronError::InvalidNumber(_))
+            ));
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "validate"))]
+    fn whole_number_must_match_the_dotted_format() {
+        assert_eq!(TigronSms::normalize_number("+12025550123").unwrap(), "+1.2025550123");
+        assert_eq!(TigronSms::normalize_number("+7.4951234567").unwrap(), "+7.4951234567");
+
+        for number in ["+32.470123456abc", "+32.4701234567890123", "x+32.470123456"].iter() {
+            assert!(matches!(
+                TigronSms::normalize_number(number),
+                Err(TigronError::InvalidNumber(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn recipients_are_read_line_by_line() {
+        use futures::StreamExt;
+
+        // The last line isn't UTF-8
+        let gateway = MockGateway::start(reversed_batch_response).await;
+        let mut list = b"# campaign\n\n  +32.470000011\n+32..470123456\r\n# end\n".to_vec();
+        list.extend_from_slice(b"+32470000022\n+32.470000099\n\xff\n");
+        let client = gateway.client();
+        let results: std::vec::Vec<(usize, Result<SendOutcome, TigronError>)> = client
+            .send_from_reader(
+                std::io::Cursor::new(list),
+                "+32.470654321".to_string(),
+                "Hello".to_string(),
+            )
+            .collect()
+            .await;
+
+        let line_numbers: std::vec::Vec<usize> =
+            results.iter().map(|(line_number, _)| *line_number).collect();
+        assert_eq!(line_numbers, vec![3, 4, 6, 7, 8]);
+        match &results[0].1 {
+            Ok(SendOutcome::Accepted { id, .. }) => assert_eq!(id, "11"),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(matches!(results[1].1, Err(TigronError::InvalidNumber(_))));
+        match &results[2].1 {
+            Ok(SendOutcome::Accepted { id, .. }) => assert_eq!(id, "22"),
+            result => panic!("unexpected result {:?}", result),
+        }
+        match &results[3].1 {
+            Err(TigronError::SendRejected { code, .. }) => assert_eq!(code, "invalid_number"),
+            result => panic!("unexpected result {:?}", result),
+        }
+        match &results[4].1 {
+            Err(e @ TigronError::Io(_)) => {
+                assert!(e.to_string().starts_with("Could not read input"))
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        // The valid recipients went out in a single call
+        let received = gateway.received.lock().unwrap();
+        let sends: std::vec::Vec<&Received> = received
+            .iter()
+            .filter(|request| request.command() == "send_sms")
+            .collect();
+        assert_eq!(sends.len(), 1);
+        assert_eq!(
+            sends[0].values("to"),
+            vec!["+32.470000011", "+32.470000022", "+32.470000099"]
+        );
+    }
+
+    #[tokio::test]
+    async fn undelivered_message_is_checked_once_the_validity_passed() {
+        let rejected = SendOutcome::Rejected {
+            code: "invalid".to_string(),
+            reason: "Invalid number".to_string(),
+            request_id: "r".to_string(),
+        };
+        assert!(client().expire_after(&rejected, Duration::from_secs(60)).is_none());
+
+        // The window passes at once on the manual clock, then the unreachable gateway is asked for the status
+        let client = client()
+            .with_clock(Arc::new(ManualClock::new()))
+            .with_environment(Environment::Custom {
+                url: "http://127.0.0.1:1".to_string(),
+                ns: NS.to_string(),
+            });
+        let accepted = SendOutcome::Accepted {
+            id: "1".to_string(),
+            message_ids: vec!["1".to_string(), "2".to_string()],
+            request_id: "r".to_string(),
+            accepted_at: None,
+        };
+        let expiry = client.expire_after(&accepted, Duration::from_secs(300)).unwrap();
+        assert!(matches!(expiry.await.unwrap(), Err(TigronError::Transport(_))));
+    }
+
+    #[test]
+    fn response_is_walked_event_by_event() {
+        let xml = "<ns1:result xmlns:ns1=\"urn:x\">\n  <item key=\"id\" value=\"42\"/>\n  <item><key>status</key><value>ok</value></item>\n</ns1:result>";
+        let events: std::vec::Vec<ParsedEvent> = parse_events(xml).map(Result::unwrap).collect();
+
+        let start = |name: &str, attributes: &[(&str, &str)]| ParsedEvent::Start {
+            name: name.to_string(),
+            attributes: attributes
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+        let end = |name: &str| ParsedEvent::End {
+            name: name.to_string(),
+        };
+        let text = |text: &str| ParsedEvent::Text(text.to_string());
+        assert_eq!(
+            events,
+            vec![
+                start("result", &[]),
+                start("item", &[("key", "id"), ("value", "42")]),
+                end("item"),
+                start("item", &[]),
+                start("key", &[]),
+                text("status"),
+                end("key"),
+                start("value", &[]),
+                text("ok"),
+                end("value"),
+                end("item"),
+                end("result"),
+            ]
+        );
+
+        let events: std::vec::Vec<Result<ParsedEvent, TigronError>> =
+            parse_events("<result><item></result>").collect();
+        assert!(matches!(events.last(), Some(Err(TigronError::Parse(_)))));
+        assert_eq!(events.iter().filter(|event| event.is_err()).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn mixed_batch_response_has_a_record_per_recipient() {
+        let response = "<items>\
+            <item><key>to</key><value>+32.470000002</value></item>\
+            <item><key>status</key><value>rejected</value></item>\
+            <item><key>code</key><value>invalid_number</value></item>\
+            <item><key>reason</key><value>Unknown subscriber</value></item>\
+            <item><key>to</key><value>+32.470000001</value></item>\
+            <item><key>status</key><value>accepted</value></item>\
+            <item><key>id</key><value>1</value></item>\
+            </items>";
+
+        let records = XmlResponseParser.records(response);
+        assert_eq!(records.len(), 2);
+        assert_eq!(XmlResponseParser::value(&records[0], "to").await, "+32.470000002");
+        match SendOutcome::from_response(&records[0], "r").await.unwrap() {
+            SendOutcome::Rejected { code, reason, .. } => {
+                assert_eq!(code, "invalid_number");
+                assert_eq!(reason, "Unknown subscriber");
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+        assert_eq!(XmlResponseParser::value(&records[1], "to").await, "+32.470000001");
+        match SendOutcome::from_response(&records[1], "r").await.unwrap() {
+            SendOutcome::Accepted { id, .. } => assert_eq!(id, "1"),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+
+    #[tokio::test]
+    async fn latin1_response_keeps_its_accented_characters() {
+        let body = |declaration: &str| {
+            let mut body = format!(
+                "<?xml version=\"1.0\" encoding=\"{}\"?><items><item><key>name</key><value>Soci",
+                declaration
+            )
+            .into_bytes();
+            body.extend_from_slice(b"\xE9t\xE9 G\xE9n\xE9rale</value></item></items>");
+            body
+        };
+
+        // Charset in the Content-Type, or only in the XML declaration
+        let decoded = SoapClient::decode(&body("ISO-8859-1"), Some("text/xml; charset=ISO-8859-1"));
+        assert!(decoded.contains("Société Générale"));
+        let decoded = SoapClient::decode(&body("ISO-8859-1"), Some("text/xml"));
+        assert!(decoded.contains("Société Générale"));
+        assert!(decoded.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>"));
+
+        // Read through a gateway answering in ISO-8859-1
+        let gateway = MockGateway::start(move |_| {
+            http_response("200 OK", "text/xml; charset=ISO-8859-1", &body("ISO-8859-1"))
+        })
+        .await;
+        let client = client();
+        let response = gateway.soap_client(&client).call("user", "info", None).await.unwrap();
+        let items = XmlResponseParser::parse(&response).await;
+        assert_eq!(XmlResponseParser::value(&items, "name").await, "Société Générale");
+    }
+
+    #[tokio::test]
+    async fn circuit_opens_on_failures_and_closes_once_a_trial_succeeds() {
+        // The first three requests fail on the transport, the gateway recovers after that
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = requests.clone();
+        let gateway = MockGateway::start(move |_| {
+            match counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0..=2 => std::vec::Vec::new(),
+                _ => info_response(),
+            }
+        })
+        .await;
+        let client = client().with_circuit_breaker(2, Duration::from_millis(50));
+        let soap_client = gateway.soap_client(&client);
+        let info = || soap_client.call("user", "info", None);
+        let sent = || requests.load(std::sync::atomic::Ordering::SeqCst);
+
+        // Closed: failures pass through until the threshold opens the circuit
+        assert!(matches!(info().await, Err(TigronError::Transport(_))));
+        assert!(matches!(info().await, Err(TigronError::Transport(_))));
+        assert!(matches!(info().await, Err(TigronError::CircuitOpen)));
+        assert_eq!(sent(), 2);
+
+        // Half-open: a failing trial opens it again for another reset timeout
+        tokio::time::delay_for(Duration::from_millis(60)).await;
+        assert!(matches!(info().await, Err(TigronError::Transport(_))));
+        assert!(matches!(info().await, Err(TigronError::CircuitOpen)));
+        assert_eq!(sent(), 3);
+
+        // A succeeding trial closes it
+        tokio::time::delay_for(Duration::from_millis(60)).await;
+        assert!(info().await.is_ok());
+        assert!(info().await.is_ok());
+        assert_eq!(sent(), 5);
+    }
+
+    #[tokio::test]
+    async fn circuit_counts_server_errors_and_maintenance_pages_but_not_throttling() {
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = requests.clone();
+        let gateway = MockGateway::start(move |_| {
+            match counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 | 1 => http_response("429 Too Many Requests", "text/plain", b"Slow down"),
+                2 => http_response("503 Service Unavailable", "text/plain", b"Unavailable"),
+                _ => http_response("200 OK", "text/html", b"<html>Maintenance</html>"),
+            }
+        })
+        .await;
+        let client = client().with_circuit_breaker(2, Duration::from_secs(60));
+        let soap_client = gateway.soap_client(&client);
+        let info = || soap_client.call("user", "info", None);
+
+        assert!(matches!(info().await, Err(TigronError::HttpStatus { status: 429 })));
+        assert!(matches!(info().await, Err(TigronError::HttpStatus { status: 429 })));
+        assert!(matches!(info().await, Err(TigronError::HttpStatus { status: 503 })));
+        assert!(matches!(info().await, Err(TigronError::NotXml { .. })));
+        assert!(matches!(info().await, Err(TigronError::CircuitOpen)));
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn dropped_trial_call_frees_the_trial() {
+        // Accepts connections but never answers them
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = client().with_circuit_breaker(1, Duration::from_millis(50));
+        let soap_client = SoapClient {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            ..client.soap_client().unwrap()
+        };
+        let circuit_breaker = client.circuit_breaker.clone().unwrap();
+        circuit_breaker.before_call(Instant::now()).unwrap().finish(false, Instant::now());
+        let info = || soap_client.call("user", "info", None);
+        assert!(matches!(info().await, Err(TigronError::CircuitOpen)));
+
+        // The caller gives up on the trial, e.g. a timeout of its own
+        tokio::time::delay_for(Duration::from_millis(60)).await;
+        let trial = tokio::time::timeout(Duration::from_millis(100), info()).await;
+        assert!(trial.is_err());
+        assert!(!circuit_breaker.state.lock().unwrap().trial_in_flight);
+
+        // The next call becomes the trial instead of being refused forever
+        let trial = tokio::time::timeout(Duration::from_millis(100), info()).await;
+        assert!(trial.is_err());
+    }
+
+    #[tokio::test]
+    async fn slow_failures_stop_retrying_at_the_deadline() {
+        // Every request fails on the transport after 300ms
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = attempts.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::spawn(async move {
+                    tokio::time::delay_for(Duration::from_millis(300)).await;
+                    drop(stream);
+                });
+            }
+        });
+
+        let client = client()
+            .with_retries(10, Duration::from_millis(100))
+            .with_deadline(Duration::from_secs(1));
+        let soap_client = SoapClient {
+            url,
+            ..client.soap_client().unwrap()
+        };
+        let started = Instant::now();
+
+        // 300 + 100 + 300 + 200: the third attempt is cut off at the deadline, without a fourth
+        let result = soap_client.call("user", "info", None).await;
+        assert!(matches!(result, Err(TigronError::Transport(_))));
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 3);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(900), "stopped after {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "stopped after {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn every_call_sends_a_uuid_request_id_shared_by_its_retries() {
+        // The first request fails on the transport and is retried
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let gateway = MockGateway::start(move |_| {
+            match requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => std::vec::Vec::new(),
+                _ => info_response(),
+            }
+        })
+        .await;
+        let client = client().with_retries(1, Duration::from_millis(1));
+        let soap_client = gateway.soap_client(&client);
+
+        let request_id = soap_client.call_traced("user", "info", None).await.unwrap().request_id;
+        soap_client.call_traced("user", "info", None).await.unwrap();
+
+        let received = gateway.received.lock().unwrap();
+        let ids: std::vec::Vec<&str> = received
+            .iter()
+            .map(|request| request.header("X-Request-Id").expect("no X-Request-Id header"))
+            .collect();
+        assert_eq!(ids.len(), 3);
+        for id in ids.iter() {
+            assert!(uuid::Uuid::parse_str(id).is_ok(), "{} is not a UUID", id);
+        }
+        assert_eq!(ids[0], ids[1]);
+        assert_ne!(ids[1], ids[2]);
+        assert_eq!(request_id, ids[0]);
+    }
+
+    #[test]
+    fn numbers_round_trip_between_e164_and_dotted_format() {
+        // Dialing codes of one, two and three digits
+        let numbers = [
+            ("+14155550123", "+1.4155550123"),
+            ("+74951234567", "+7.4951234567"),
+            ("+32470123456", "+32.470123456"),
+            ("+447911123456", "+44.7911123456"),
+            ("+353861234567", "+353.861234567"),
+            ("+352621123456", "+352.621123456"),
+            ("+97150123456", "+971.50123456"),
+        ];
+        for (e164, dotted) in numbers.iter() {
+            assert_eq!(to_tigron_format(e164).unwrap(), *dotted);
+            assert_eq!(from_tigron_format(dotted), *e164);
+            assert_eq!(from_tigron_format(&to_tigron_format(e164).unwrap()), *e164);
+        }
+
+        assert!(matches!(to_tigron_format("+8001234567"), Err(TigronError::InvalidNumber(_))));
+        assert!(matches!(to_tigron_format("32470123456"), Err(TigronError::InvalidNumber(_))));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn one_shared_client_calls_from_many_tasks() {
+        // Every response carries the request id of its request
+        let gateway = MockGateway::start(|request| {
+            soap_response(&[("request", request.header("X-Request-Id").unwrap_or(""))])
+        })
+        .await;
+        let client = Arc::new(client());
+
+        let tasks: std::vec::Vec<_> = (0..32)
+            .map(|_| {
+                let client = client.clone();
+                let url = gateway.url.to_string();
+                tokio::spawn(async move {
+                    let soap_client = SoapClient {
+                        url,
+                        ..client.soap_client().unwrap()
+                    };
+                    soap_client.call_traced("user", "info", None).await
+                })
+            })
+            .collect();
+
+        // Every task gets the response to its own request
+        for task in tasks {
+            let response = task.await.unwrap().unwrap();
+            let items = XmlResponseParser::parse(&response.body).await;
+            assert_eq!(XmlResponseParser::value(&items, "request").await, response.request_id);
+        }
+        assert_eq!(gateway.received.lock().unwrap().len(), 32);
+    }
+
+    #[tokio::test]
+    async fn maintenance_page_is_not_xml() {
+        let page = b"<!DOCTYPE html><html><body><h1>Scheduled maintenance</h1></body></html>";
+        let requests = std::sync::atomic::AtomicUsize::new(0);
+        let gateway = MockGateway::start(move |_| {
+            match requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                // Mislabelled as XML, the page itself still gives it away
+                0 => http_response("200 OK", "text/xml", page),
+                _ => http_response("200 OK", "text/html; charset=utf-8", page),
+            }
+        })
+        .await;
+        let client = client();
+        let soap_client = gateway.soap_client(&client);
+
+        match soap_client.call("user", "info", None).await {
+            Err(TigronError::NotXml { content_type }) => {
+                assert_eq!(content_type.as_deref(), Some("text/xml"))
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        match soap_client.call("sms", "send_sms", None).await {
+            Err(TigronError::NotXml { content_type }) => {
+                assert_eq!(content_type.as_deref(), Some("text/html; charset=utf-8"))
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn auth_header_escapes_the_credentials() {
+        let header = AuthHeader {
+            ns: NS,
+            username: "user",
+            password: "secret",
+        }
+        .to_xml();
+        assert!(header.starts_with(&format!("<authenticate_user xmlns=\"{}\">", NS)));
+        assert!(header.contains("<username>user</username>"));
+        assert!(header.contains("<password>secret</password>"));
+
+        let header = AuthHeader {
+            ns: NS,
+            username: "<user & \"name\">",
+            password: "pass'<&>\"word",
+        }
+        .to_xml();
+        assert!(header.contains("<username>&lt;user &amp; &quot;name&quot;&gt;</username>"));
+        assert!(header.contains("<password>pass&apos;&lt;&amp;&gt;&quot;word</password>"));
+
+        // The header stays well-formed and reads back as the original credentials
+        let mut texts = std::vec::Vec::new();
+        for event in EventReader::from_str(&header) {
+            if let XmlEvent::Characters(text) = event.unwrap() {
+                texts.push(text);
+            }
+        }
+        assert_eq!(texts, ["<user & \"name\">", "pass'<&>\"word"]);
+    }
+
+    #[tokio::test]
+    async fn pairs_and_records_are_read_from_attributes() {
+        let xml = "<soap:Envelope xmlns:soap=\"http://schemas.xmlsoap.org/soap/envelope/\"><soap:Body>\
+            <ns1:info_response xmlns:ns1=\"urn:x\"><return>\
+            <ns1:item key=\"id\" value=\"123\"/>\
+            <ns1:item key=\"name\" value=\"Fish &amp; chips\"></ns1:item>\
+            <ns1:item key=\"company\" value=\"\"/>\
+            </return></ns1:info_response>\
+            </soap:Body></soap:Envelope>";
+        let items = XmlResponseParser::parse(xml).await;
+        assert_eq!(
+            items,
+            vec![
+                ("id".to_string(), "123".to_string()),
+                ("name".to_string(), "Fish & chips".to_string()),
+                ("company".to_string(), String::new()),
+            ]
+        );
+
+        // Per-recipient results of a bulk send, one attribute form record each
+        let xml = "<items>\
+            <item key=\"to\" value=\"+32.470000001\"/><item key=\"status\" value=\"accepted\"/>\
+            <item key=\"to\" value=\"+32.470000002\"/><item key=\"status\" value=\"rejected\"/>\
+            <item key=\"code\" value=\"invalid_number\"/>\
+            </items>";
+        let records = XmlResponseParser.records(xml);
+        assert_eq!(records.len(), 2);
+        assert_eq!(XmlResponseParser::value(&records[0], "status").await, "accepted");
+        assert_eq!(XmlResponseParser::value(&records[1], "to").await, "+32.470000002");
+        assert_eq!(XmlResponseParser::value(&records[1], "code").await, "invalid_number");
+
+        // Both forms mixed in one response
+        let xml = "<items><item key=\"id\" value=\"1\"/>\
+            <item><key>status</key><value>queued</value></item></items>";
+        let records = XmlResponseParser.records(xml);
+        assert_eq!(records.len(), 1);
+        assert_eq!(XmlResponseParser::value(&records[0], "status").await, "queued");
+    }
+
+    #[tokio::test]
+    async fn credentials_with_a_newline_or_tab_are_refused() {
+        let refused = |client: &TigronSms, field: &str| match client.soap_client() {
+            Err(TigronError::Config(reason)) => assert!(reason.starts_with(field), "{}", reason),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("credentials with a control character were accepted"),
+        };
+
+        refused(&TigronSms::new("user\n".to_string(), "password".to_string()), "Username");
+        refused(&TigronSms::new("us\ter".to_string(), "password".to_string()), "Username");
+        refused(&TigronSms::new("user".to_string(), "password\r\n".to_string()), "Password");
+        refused(&TigronSms::new("user".to_string(), "pass\tword".to_string()), "Password");
+        assert!(client().soap_client().is_ok());
+
+        // A rotation to a pasted password is refused before anything is sent
+        let client = client();
+        client.update_credentials("user".to_string(), "password\n".to_string());
+        refused(&client, "Password");
+        let sent = client
+            .send(
+                "+32.470000001".to_string(),
+                "+32.470654321".to_string(),
+                "Hello".to_string(),
+            )
+            .await;
+        assert!(matches!(sent, Err(TigronError::Config(_))));
+    }
 
-// Client to send a text-message through Tigron's API
-pub struct TigronSms {
-    pub credentials: (String, String),
-}
+    #[tokio::test]
+    async fn body_follows_the_namespace_style() {
+        let params = || vec![("to", "+32.470123456"), ("message", "Hello")];
+        let namespaces = |xml: &str| -> std::vec::Vec<(String, Option<String>)> {
+            EventReader::from_str(xml)
+                .into_iter()
+                .filter_map(|event| match event.unwrap() {
+                    XmlEvent::StartElement { name, .. } => Some((name.local_name, name.namespace)),
+                    _ => None,
+                })
+                .collect()
+        };
+        let ns = Some(NS.to_string());
 
-// Basic SOAP-client to interact with API
-// Note: This SOAP-client will only suffice for the sms use-case.
-struct SoapClient {
-    pub url: String,
-    pub ns: String,
-    pub credentials: (String, String),
-}
+        // Default: the command declares the default namespace, the parameters inherit it
+        let client = client();
+        let xml = client.soap_client().unwrap().cmd_and_params_to_wsdl("send_sms", params()).await;
+        assert!(xml.starts_with(&format!("<send_sms xmlns=\"{}\"><to>", NS)));
+        assert_eq!(
+            namespaces(&xml),
+            vec![
+                ("send_sms".to_string(), ns.clone()),
+                ("to".to_string(), ns.clone()),
+                ("message".to_string(), ns.clone()),
+            ]
+        );
 
-// Basic XML Parser to interpet the response from the Tigron-API
-// Note: This parser will only suffice for interpreting the response of the 'info' procedure.
-struct XmlResponseParser;
+        // Prefixed: only the command is qualified
+        let client = client.with_namespace_style(NamespaceStyle::Prefixed);
+        let xml = client.soap_client().unwrap().cmd_and_params_to_wsdl("send_sms", params()).await;
+        assert!(xml.starts_with(&format!("<ns:send_sms xmlns:ns=\"{}\"><to>", NS)));
+        assert!(xml.ends_with("</message></ns:send_sms>"));
+        assert_eq!(
+            namespaces(&xml),
+            vec![
+                ("send_sms".to_string(), ns.clone()),
+                ("to".to_string(), None),
+                ("message".to_string(), None),
+            ]
+        );
 
-impl TigronSms {
+        // PrefixedQualified: the parameters carry the prefix as well
+        let client = client.with_namespace_style(NamespaceStyle::PrefixedQualified);
+        let xml = client.soap_client().unwrap().cmd_and_params_to_wsdl("send_sms", params()).await;
+        assert!(xml.starts_with(&format!("<ns:send_sms xmlns:ns=\"{}\"><ns:to>", NS)));
+        assert!(xml.ends_with("</ns:message></ns:send_sms>"));
+        assert_eq!(
+            namespaces(&xml),
+            vec![
+                ("send_sms".to_string(), ns.clone()),
+                ("to".to_string(), ns.clone()),
+                ("message".to_string(), ns),
+            ]
+        );
+    }
 
-    /*
-        Method to send a text-message
-        :param to: Telephone number to send message to. Format: +xx.xxxxxxxxx
-        :param from: Source of message. Format: +xx.xxxxxxxxx
-        :param message: Content of message to send
-        :return Result<(), &str>: Returns Ok() if successfull. Returns an error otherwise.
-    */
-    pub async fn send(&self, to: String, from: String, message: String) -> Result<(), &str> {
-        // Input validation
-        let phone_number_regex = Regex::new(r"\+\d{2,3}\.\d{7,12}").unwrap();
-        if message.len() == 0 {
-            return Err("Message cannot be empty.");
+    fn batched_request(to: &str, message: &str) -> SendRequest {
+        SendRequest {
+            to: to.to_string(),
+            from: "+32.470654321".to_string(),
+            message: message.to_string(),
         }
-        if message.len() > 160 {
-            return Err("Message cannot be more than 160 characters");
+    }
+
+    #[tokio::test]
+    async fn batching_sender_sends_a_full_batch_at_once() {
+        // The delay would hold the batch for a minute, it is sent once full instead
+        let sender =
+            BatchingSender::new(client(), SendOptions::default(), 3, Duration::from_secs(60));
+        let requests = vec![
+            batched_request("+32.470654321", "Hello"),
+            batched_request("12345", "Hello"),
+            batched_request("+32.470000001", ""),
+        ];
+        let sends = requests.into_iter().map(|request| {
+            let sender = sender.clone();
+            async move { sender.send(request).await }
+        });
+        let results =
+            tokio::time::timeout(Duration::from_secs(5), futures::future::join_all(sends))
+                .await
+                .expect("a full batch waited for the delay");
+
+        // Every caller gets the result of its own request
+        assert!(matches!(results[0], Err(TigronError::SameFromTo)));
+        assert!(matches!(results[1], Err(TigronError::InvalidNumber(_))));
+        assert!(matches!(results[2], Err(TigronError::InvalidInput(_))));
+    }
+
+    #[tokio::test]
+    async fn batching_sender_sends_a_partial_batch_after_the_delay() {
+        // Every batch fails as a whole on the credentials, before anything is sent
+        let client = TigronSms::new("user".to_string(), "password\n".to_string());
+        let sender =
+            BatchingSender::new(client, SendOptions::default(), 100, Duration::from_millis(50));
+
+        // Two sends arriving together wait out the delay for a third that doesn't come
+        let started = Instant::now();
+        let (first, second) = futures::join!(
+            sender.send(batched_request("+32.470000011", "Hello")),
+            sender.send(batched_request("+32.470000022", "Hello"))
+        );
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        for result in [first, second].iter() {
+            match result {
+                Err(TigronError::Shared(e)) => assert!(matches!(**e, TigronError::Config(_))),
+                result => panic!("unexpected result {:?}", result),
+            }
         }
-        if !phone_number_regex.is_match(&to) || !phone_number_regex.is_match(&from) {
-            return Err(
-                "Phone numbers must be in the format: +xx.yyyyyyyyy where xx is the country code.",
-            );
+    }
+
+    #[tokio::test]
+    async fn short_read_is_a_truncated_response() {
+        // The connection closes 40 bytes short of the announced length
+        let gateway = MockGateway::start(|_| {
+            let mut response = info_response();
+            let full = response.len();
+            response.truncate(full - 40);
+            response
+        })
+        .await;
+        let client = client();
+
+        match gateway.soap_client(&client).call("user", "info", None).await {
+            Err(TigronError::TruncatedResponse { expected, received }) => {
+                assert_eq!(expected - received, 40)
+            }
+            result => panic!("unexpected result {:?}", result),
         }
+    }
 
-        let soap_client = SoapClient {
-            url: "https://api.tigron.net/soap".to_string(),
-            ns: "https://www.tigron.net/ns/".to_string(),
-            credentials: (
-                self.credentials.0.to_string(),
-                self.credentials.1.to_string(),
-            ),
+    #[tokio::test]
+    async fn message_is_read_from_a_file() {
+        let client = client();
+        let send = |path: std::path::PathBuf| {
+            client.send_from_file("+32.470000001".to_string(), "+32.470654321".to_string(), path)
+        };
+        let directory = std::env::temp_dir();
+        let path = directory.join(format!("tigron-message-{}.txt", uuid::Uuid::new_v4()));
+        let invalid = |result: Result<SendOutcome, TigronError>| match result {
+            Err(TigronError::InvalidInput(reason)) => reason,
+            result => panic!("unexpected result {:?}", result),
         };
 
-        let user_id = &*self.get_user_id().await;
-        if user_id.is_empty() {
-            return Err("User not found. Are your credentials correct?");
+        // Only the final line ending is trimmed, the validation sees what is left
+        std::fs::write(&path, "\r\n").unwrap();
+        assert_eq!(invalid(send(path.clone()).await), "Message cannot be empty.");
+        std::fs::write(&path, format!("{}\n\n", "a".repeat(160))).unwrap();
+        assert_eq!(
+            invalid(send(path.clone()).await),
+            "Message cannot be more than 160 characters"
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(send(path).await, Err(TigronError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn accept_header_is_sent_and_can_be_overridden() {
+        let gateway = MockGateway::start(|_| info_response()).await;
+
+        let client = client();
+        gateway.soap_client(&client).call("user", "info", None).await.unwrap();
+        let client = client.with_accept("application/soap+xml");
+        gateway.soap_client(&client).call("user", "info", None).await.unwrap();
+
+        let received = gateway.received.lock().unwrap();
+        assert_eq!(received[0].header("Accept"), Some(DEFAULT_ACCEPT));
+        assert_eq!(received[0].header("Accept"), Some("text/xml, application/soap+xml"));
+        assert_eq!(received[1].header("Accept"), Some("application/soap+xml"));
+        assert_eq!(received[1].header("Content-Type"), Some("application/xml"));
+    }
+
+    #[test]
+    fn only_delivered_failed_and_expired_are_final() {
+        assert!(DeliveryStatus::Delivered.is_final());
+        assert!(DeliveryStatus::Failed.is_final());
+        assert!(DeliveryStatus::Expired.is_final());
+        assert!(!DeliveryStatus::Pending.is_final());
+        assert!(!DeliveryStatus::Unknown("buffered".to_string()).is_final());
+    }
+
+    #[tokio::test]
+    async fn mixed_batch_response_fills_every_recipient_slot() {
+        let gateway = MockGateway::start(|request| match request.command().as_str() {
+            "info" => info_response(),
+            _ => soap_response(&[
+                ("to", "+32.470000002"),
+                ("status", "rejected"),
+                ("code", "invalid_number"),
+                ("reason", "Unknown subscriber"),
+                ("to", "+32.470000001"),
+                ("status", "accepted"),
+                ("id", "1"),
+            ]),
+        })
+        .await;
+
+        let results = gateway
+            .client()
+            .send_to_many(
+                vec![
+                    "+32.470000001".to_string(),
+                    "0470".to_string(),
+                    "+32.470000002".to_string(),
+                ],
+                "+32.470654321".to_string(),
+                "Hello".to_string(),
+                SendOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 3);
+        match &results[0] {
+            Ok(SendOutcome::Accepted { id, .. }) => assert_eq!(id, "1"),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(matches!(results[1], Err(TigronError::InvalidNumber(_))));
+        match &results[2] {
+            Err(TigronError::SendRejected { code, reason }) => {
+                assert_eq!(code, "invalid_number");
+                assert_eq!(reason, "Unknown subscriber");
+            }
+            result => panic!("unexpected result {:?}", result),
         }
 
-        let sms_params = vec![
-            ("user_id", user_id),
-            ("from", &*from),
-            ("to", &*to),
-            ("message", &*message),
-        ];
+        // The invalid recipient never reaches the gateway, the others share a single request
+        assert_eq!(gateway.commands(), vec!["info", "send_sms"]);
+        let received = gateway.received.lock().unwrap();
+        assert_eq!(received[1].values("to"), vec!["+32.470000001", "+32.470000002"]);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn one_shared_client_sends_from_many_tasks() {
+        let gateway = MockGateway::start(|request| match request.command().as_str() {
+            "info" => info_response(),
+            _ => {
+                let to = request.values("to").join("");
+                soap_response(&[("status", "accepted"), ("id", &to[to.len() - 2..])])
+            }
+        })
+        .await;
+        let client = Arc::new(gateway.client());
 
-        soap_client.call("sms", "send_sms", Some(sms_params)).await;
+        let tasks: std::vec::Vec<_> = (10..42)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let outcome = client
+                        .send(
+                            format!("+32.4700000{}", i),
+                            "+32.470654321".to_string(),
+                            "Hello".to_string(),
+                        )
+                        .await;
+                    (i, outcome)
+                })
+            })
+            .collect();
 
-        Ok(())
+        // Every task gets the outcome of its own message
+        for task in tasks {
+            match task.await.unwrap() {
+                (i, Ok(SendOutcome::Accepted { id, .. })) => assert_eq!(id, i.to_string()),
+                (_, outcome) => panic!("unexpected outcome {:?}", outcome),
+            }
+        }
+        let sends = gateway
+            .commands()
+            .into_iter()
+            .filter(|command| command == "send_sms")
+            .count();
+        assert_eq!(sends, 32);
     }
 
-    // Function to retrieve user_id
-    async fn get_user_id(&self) -> String {
-        let soap_client = SoapClient {
-            url: "https://api.tigron.net/soap".to_string(),
-            ns: "https://www.tigron.net/ns/".to_string(),
-            credentials: (
-                self.credentials.0.to_string(),
-                self.credentials.1.to_string(),
-            ),
+    #[tokio::test]
+    async fn queued_message_stays_until_the_gateway_takes_it() {
+        // Down on the transport twice, then a 503, then back up
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = requests.clone();
+        let gateway = MockGateway::start(move |request| {
+            match counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 | 1 => std::vec::Vec::new(),
+                2 => http_response("503 Service Unavailable", "text/plain", b"Unavailable"),
+                _ if request.command() == "info" => info_response(),
+                _ => soap_response(&[("status", "accepted"), ("id", "1")]),
+            }
+        })
+        .await;
+        let path = std::env::temp_dir().join(format!("tigron-queue-{}.jsonl", uuid::Uuid::new_v4()));
+        let queued = QueuedSms::new(gateway.client(), Box::new(FileStore::new(path.clone())));
+        let request = SendRequest {
+            to: "+32.470000001".to_string(),
+            from: "+32.470654321".to_string(),
+            message: "Hello".to_string(),
         };
 
-        let response = soap_client.call("user", "info", None).await;
-        let response_items = XmlResponseParser::parse(&response).await;
-        let user_id = XmlResponseParser::value(&response_items, "id").await;
+        assert_eq!(queued.send(request.clone()).await.unwrap(), None);
+        assert_eq!(queued.flush().await.unwrap(), 0);
+        assert_eq!(queued.store.peek().unwrap(), Some(request.clone()));
+        assert_eq!(queued.flush().await.unwrap(), 0);
+        assert_eq!(queued.store.peek().unwrap(), Some(request));
 
-        user_id
+        assert_eq!(queued.flush().await.unwrap(), 1);
+        assert_eq!(queued.store.peek().unwrap(), None);
+        assert_eq!(gateway.commands().last().unwrap(), "send_sms");
+        let _ = std::fs::remove_file(path);
     }
-}
 
-impl SoapClient {
+    #[tokio::test]
+    async fn flush_drops_only_messages_that_can_never_be_sent() {
+        // The second message is throttled, then refused with a 401, then accepted
+        let sends = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let gateway = MockGateway::start(move |request| match request.command().as_str() {
+            "info" => info_response(),
+            _ if request.values("to") == vec!["+32.470000002"] => {
+                soap_response(&[("status", "rejected"), ("code", "invalid_number")])
+            }
+            _ if request.values("to") == vec!["+32.470000003"] => {
+                soap_response(&[("status", "accepted"), ("id", "3")])
+            }
+            _ => match sends.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => soap_response(&[("status", "rejected"), ("code", "throttled")]),
+                1 => http_response("401 Unauthorized", "text/plain", b"Unauthorized"),
+                _ => soap_response(&[("status", "accepted"), ("id", "1")]),
+            },
+        })
+        .await;
+        let path = std::env::temp_dir().join(format!("tigron-queue-{}.jsonl", uuid::Uuid::new_v4()));
+        let dropped = Arc::new(Mutex::new(std::vec::Vec::new()));
+        let log = dropped.clone();
+        let queued = QueuedSms::new(gateway.client(), Box::new(FileStore::new(path.clone())))
+            .with_dead_letter(Arc::new(move |failed: FailedSend| log.lock().unwrap().push(failed)));
+        let request = |to: &str| SendRequest {
+            to: to.to_string(),
+            from: "+32.470654321".to_string(),
+            message: "Hello".to_string(),
+        };
+        for to in &["+32.470000002", "+32.470000001", "+32.470000003"] {
+            queued.store.push(&request(to)).unwrap();
+        }
 
-    /*
-        Send a command to the API and retrieve XML
-        :param service: Service of API to execute a command on. E.g: "sms"
-        :param cmd: The command to execute. E.g: "send_sms"
-        :param params: Parameters of the command. E.g: [("from", "xxxx.xxx.xxx"), ("to", "yyyy.yyy.yyy")]
-        :param String: Returns the body of the API-response
-    */
-    pub async fn call(
-        &self,
-        service: &str,
-        cmd: &str,
-        params: Option<std::vec::Vec<(&str, &str)>>,
-    ) -> String {
-        let http = reqwest::Client::new();
+        // The permanent rejection is dropped and reported, the transient one stays
+        assert_eq!(queued.flush().await.unwrap(), 1);
+        assert_eq!(queued.store.peek().unwrap(), Some(request("+32.470000001")));
+        {
+            let dropped = dropped.lock().unwrap();
+            assert_eq!(dropped.len(), 1);
+            assert_eq!(dropped[0].request, request("+32.470000002"));
+            match &*dropped[0].error {
+                TigronError::SendRejected { code, .. } => assert_eq!(code, "invalid_number"),
+                error => panic!("unexpected error {:?}", error),
+            }
+        }
 
-        let params = match params {
-            Some(params) => params,
-            None => std::vec::Vec::new(),
+        // Bad credentials fail every message, so the queue is kept as well
+        assert_eq!(queued.flush().await.unwrap(), 0);
+        assert_eq!(queued.store.peek().unwrap(), Some(request("+32.470000001")));
+
+        assert_eq!(queued.flush().await.unwrap(), 2);
+        assert_eq!(queued.store.peek().unwrap(), None);
+        assert_eq!(dropped.lock().unwrap().len(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn captured_errors_carry_the_envelope_and_the_response() {
+        let gateway = MockGateway::start(|request| match request.values("to").join("").as_str() {
+            "+32.470000001" => http_response("500 Internal Server Error", "text/plain", b"Boom"),
+            "+32.470000002" => http_response("200 OK", "text/html", b"<html>Maintenance</html>"),
+            _ => soap_response(&[("id", "3")]),
+        })
+        .await;
+        let client = gateway.client().with_capture_on_error(true);
+        *client.user_id.lock().unwrap() = Some(("42".to_string(), Instant::now()));
+        let send = |to: &str| {
+            client.send(to.to_string(), "+32.470654321".to_string(), "Hello".to_string())
         };
 
-        let cmd_xml = self.cmd_and_params_to_wsdl(cmd, params).await;
-        let soap_body = self.soap_body(cmd_xml).await;
+        for (to, body) in &[
+            ("+32.470000001", "Boom"),
+            ("+32.470000002", "Maintenance"),
+            ("+32.470000003", "<value>3</value>"),
+        ] {
+            match send(to).await {
+                Err(TigronError::Captured {
+                    request, response, ..
+                }) => {
+                    assert!(request.contains("send_sms"), "{}", request);
+                    assert!(request.contains("<password>********</password>"), "{}", request);
+                    assert!(response.unwrap().contains(body));
+                }
+                result => panic!("unexpected result {:?}", result),
+            }
+        }
+    }
 
-        let response = http
-            .post(&format!(
-                "{url}/{service}?WSDL",
-                url = self.url,
-                service = service
-            ))
-            .header("Content-Type", "application/xml")
-            .body(soap_body)
-            .send()
+    #[tokio::test]
+    async fn maintenance_page_fails_a_send_and_queues_the_message() {
+        let page = b"<!DOCTYPE html><html><body><h1>Scheduled maintenance</h1></body></html>";
+        let gateway =
+            MockGateway::start(move |_| http_response("200 OK", "text/html; charset=utf-8", page))
+                .await;
+
+        let client = gateway.client();
+        *client.user_id.lock().unwrap() = Some(("42".to_string(), Instant::now()));
+        let error = client
+            .send(
+                "+32.470000001".to_string(),
+                "+32.470654321".to_string(),
+                "Hello".to_string(),
+            )
             .await
-            .expect("Failed to get response.")
-            .text()
+            .unwrap_err();
+        match &error {
+            TigronError::NotXml { content_type } => {
+                assert_eq!(content_type.as_deref(), Some("text/html; charset=utf-8"))
+            }
+            error => panic!("unexpected error {:?}", error),
+        }
+        assert_eq!(gateway.commands(), vec!["send_sms"]);
+
+        // The page is temporary, so the message is queued rather than failed
+        let directory = std::env::temp_dir();
+        let path = directory.join(format!("tigron-queue-{}.jsonl", uuid::Uuid::new_v4()));
+        let queued = QueuedSms::new(client, Box::new(FileStore::new(path.clone())));
+        let request = SendRequest {
+            to: "+32.470000001".to_string(),
+            from: "+32.470654321".to_string(),
+            message: "Hello".to_string(),
+        };
+        assert_eq!(queued.send(request.clone()).await.unwrap(), None);
+        assert_eq!(queued.store.peek().unwrap(), Some(request));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn diagnose_reports_a_fault_of_the_user_lookup() {
+        let fault = "<soap:Envelope xmlns:soap=\"http://schemas.xmlsoap.org/soap/envelope/\"><soap:Body>\
+            <soap:Fault><faultcode>soap:Client</faultcode><faultstring>Unknown user</faultstring></soap:Fault>\
+            </soap:Body></soap:Envelope>";
+        let gateway = MockGateway::start(move |_| {
+            http_response("200 OK", "text/xml", fault.as_bytes())
+        })
+        .await;
+
+        let report = gateway.client().diagnose().await;
+        let step = report.steps.iter().find(|step| step.name == "user_id").unwrap();
+        match &step.status {
+            DiagnosisStatus::Failed(reason) => {
+                assert!(reason.contains("Unknown user"), "{}", reason)
+            }
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert!(!report.is_ok());
+    }
+
+    #[tokio::test]
+    async fn records_of_a_response_with_a_byte_order_mark_are_read() {
+        let gateway = MockGateway::start(|_| {
+            let items = "<item><key>number</key><value>+32.470000001</value></item>\
+                <item><key>number</key><value>+32.470000002</value></item>";
+            let body = format!("\u{FEFF}<result>{}</result>", items);
+            http_response("200 OK", "text/xml; charset=utf-8", body.as_bytes())
+        })
+        .await;
+
+        let records: std::vec::Vec<std::vec::Vec<(String, String)>> = gateway
+            .client()
+            .raw_call_records("sms", "get_list", vec![("page", 1)])
             .await
-            .unwrap();
+            .unwrap()
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1], vec![("number".to_string(), "+32.470000002".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn scheduled_message_is_cancelled_unless_already_sent() {
+        let gateway = MockGateway::start(|request| match request.command().as_str() {
+            "info" => info_response(),
+            _ => match request.values("id").join("").as_str() {
+                "1" => soap_response(&[("status", "cancelled")]),
+                "2" => soap_response(&[("status", "already_sent")]),
+                _ => soap_response(&[("status", "not_found")]),
+            },
+        })
+        .await;
+        let client = gateway.client();
+
+        client.cancel("1").await.unwrap();
+        match client.cancel("2").await {
+            Err(TigronError::AlreadySent { message_id }) => assert_eq!(message_id, "2"),
+            result => panic!("unexpected result {:?}", result),
+        }
+        match client.cancel("3").await {
+            Err(TigronError::MessageNotFound { message_id }) => assert_eq!(message_id, "3"),
+            result => panic!("unexpected result {:?}", result),
+        }
 
-        response
+        let received = gateway.received.lock().unwrap();
+        let cancels: std::vec::Vec<&Received> = received
+            .iter()
+            .filter(|request| request.command() == "cancel")
+            .collect();
+        assert_eq!(cancels.len(), 3);
+        assert_eq!(cancels[0].values("user_id"), vec!["42"]);
+        assert_eq!(cancels[0].values("id"), vec!["1"]);
     }
 
-    // Convert the array from the command and params-array into WSDL/XML format
-    async fn cmd_and_params_to_wsdl(
-        &self,
-        cmd: &str,
-        params: std::vec::Vec<(&str, &str)>,
-    ) -> String {
-        let mut xml = String::new();
+    #[tokio::test]
+    async fn batch_expires_every_accepted_recipient_and_reports_failures() {
+        let gateway = MockGateway::start(|request| match request.command().as_str() {
+            "get_status" => {
+                let id = request.values("id").join("");
+                soap_response(&[("id", id.as_str()), ("status", "pending")])
+            }
+            "cancel" if request.values("id") == vec!["02"] => {
+                http_response("503 Service Unavailable", "text/plain", b"Unavailable")
+            }
+            "cancel" => soap_response(&[("status", "cancelled")]),
+            _ => reversed_batch_response(request),
+        })
+        .await;
+        let (expired, mut expiries) = tokio::sync::mpsc::unbounded_channel();
+        let client = gateway
+            .client()
+            .with_clock(Arc::new(ManualClock::new()))
+            .with_on_expiry(Arc::new(
+                move |outcome: &SendOutcome, result: &Result<usize, TigronError>| {
+                    let result = result.as_ref().map(|cancelled| *cancelled);
+                    let _ = expired.send((outcome.clone(), result.map_err(|e| e.to_string())));
+                },
+            ));
+        let requests = vec![
+            batched_request("+32.470000001", "Hello"),
+            batched_request("+32.470000002", "Hello"),
+            batched_request("+32.470000099", "Hello"),
+        ];
 
-        for param in params.iter() {
-            let element = format!("<{key}>{value}</{key}>", key = param.0, value = param.1);
-            xml = format!("{}{}", xml, element);
+        let options = SendOptions::default().with_validity(Duration::from_secs(300));
+        let results = client.send_batch(requests, options).await.unwrap();
+        assert!(matches!(results[2], Ok(SendOutcome::Rejected { .. })));
+
+        // The rejected recipient has nothing to cancel, the others each get their own expiry
+        let mut reports = std::vec::Vec::new();
+        for _ in 0..2 {
+            let report = tokio::time::timeout(Duration::from_secs(5), expiries.recv()).await;
+            match report.unwrap().unwrap() {
+                (SendOutcome::Accepted { id, .. }, result) => reports.push((id, result)),
+                (outcome, _) => panic!("unexpected outcome {:?}", outcome),
+            }
         }
+        reports.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(reports[0], ("01".to_string(), Ok(1)));
+        assert_eq!(reports[1].0, "02");
+        assert!(reports[1].1.is_err());
+        let cancels = gateway.commands().into_iter().filter(|command| command == "cancel").count();
+        assert_eq!(cancels, 2);
+    }
 
-        xml = format!(
-            "<{cmd} xmlns=\"{ns}\">{params}</{cmd}>",
-            cmd = cmd,
-            params = xml,
-            ns = self.ns
+    // Answer of a bulk send, a record per recipient in reverse order
+    // The id is the last two digits of the number, a number ending in 99 is rejected.
+    fn reversed_batch_response(request: &Received) -> std::vec::Vec<u8> {
+        if request.command() == "info" {
+            return info_response();
+        }
+
+        let mut pairs: std::vec::Vec<(&str, String)> = std::vec::Vec::new();
+        for to in request.values("to").into_iter().rev() {
+            let id = to[to.len() - 2..].to_string();
+            pairs.push(("to", to.to_string()));
+            if id == "99" {
+                pairs.push(("status", "rejected".to_string()));
+                pairs.push(("code", "invalid_number".to_string()));
+            } else {
+                pairs.push(("status", "accepted".to_string()));
+                pairs.push(("id", id));
+            }
+        }
+        let pairs: std::vec::Vec<(&str, &str)> =
+            pairs.iter().map(|(key, value)| (*key, value.as_str())).collect();
+
+        soap_response(&pairs)
+    }
+
+    #[tokio::test]
+    async fn batching_sender_routes_every_gateway_result_to_its_caller() {
+        let gateway = MockGateway::start(reversed_batch_response).await;
+        let sender = BatchingSender::new(
+            gateway.client(),
+            SendOptions::default(),
+            3,
+            Duration::from_secs(60),
         );
+        let numbers = ["+32.470000011", "+32.470000099", "+32.470000033"];
+        let sends = numbers.iter().map(|to| {
+            let sender = sender.clone();
+            async move { sender.send(batched_request(to, "Hello")).await }
+        });
+        let results =
+            tokio::time::timeout(Duration::from_secs(5), futures::future::join_all(sends))
+                .await
+                .expect("a full batch waited for the delay");
 
-        xml
+        // Every caller gets the result of its own recipient
+        match &results[0] {
+            Ok(SendOutcome::Accepted { id, .. }) => assert_eq!(id, "11"),
+            result => panic!("unexpected result {:?}", result),
+        }
+        match &results[1] {
+            Err(TigronError::SendRejected { code, .. }) => assert_eq!(code, "invalid_number"),
+            result => panic!("unexpected result {:?}", result),
+        }
+        match &results[2] {
+            Ok(SendOutcome::Accepted { id, .. }) => assert_eq!(id, "33"),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        // A later send starts a batch of its own
+        let sender = BatchingSender::new(
+            gateway.client(),
+            SendOptions::default(),
+            100,
+            Duration::from_millis(50),
+        );
+        let later = sender.send(batched_request("+32.470000044", "Hello")).await;
+        assert!(matches!(later, Ok(SendOutcome::Accepted { id, .. }) if id == "44"));
+
+        let received = gateway.received.lock().unwrap();
+        let batches: std::vec::Vec<std::vec::Vec<String>> = received
+            .iter()
+            .filter(|request| request.command() == "send_sms")
+            .map(|request| request.values("to"))
+            .collect();
+        assert_eq!(
+            batches,
+            vec![
+                vec!["+32.470000011", "+32.470000099", "+32.470000033"],
+                vec!["+32.470000044"]
+            ]
+        );
     }
 
-    // Function to get the full WSDL for the call
-    async fn soap_body(&self, cmd_xml: String) -> String {
-        let wsdl = format!(
-            r#"<?xml version="1.0"?>
+    #[tokio::test]
+    async fn file_content_is_sent_with_its_final_line_ending_trimmed() {
+        let gateway = MockGateway::start(|request| match request.command().as_str() {
+            "info" => info_response(),
+            _ => soap_response(&[("status", "accepted"), ("id", "1")]),
+        })
+        .await;
+        let client = gateway.client();
 
-                <soap:Envelope
-                xmlns:soap="http://schemas.xmlsoap.org/soap/envelope/"
-                soap:encodingStyle="http://www.w3.org/2003/05/soap-encoding">
+        let directory = std::env::temp_dir();
+        let path = directory.join(format!("tigron-message-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "Fish & chips\nat 8 ✓\n\n").unwrap();
+        let outcome = client
+            .send_from_file("+32.470000001".to_string(), "+32.470654321".to_string(), &path)
+            .await;
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(outcome, Ok(SendOutcome::Accepted { .. })));
 
-                    <soap:Header>
-                        <authenticate_user xmlns="{ns}">
-                          <username>{username}</username>
-                          <password>{password}</password>
-                        </authenticate_user>
-                    </soap:Header>
+        let received = gateway.received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].values("message"), vec!["Fish & chips\nat 8 ✓\n"]);
+    }
 
-                    <soap:Body>
-                        {cmd}
-                    </soap:Body>
+    #[tokio::test]
+    async fn send_and_confirm_polls_on_the_clock_until_delivered() {
+        // Pending for the first two polls of each gateway, then delivered unless 'delivers' is off
+        let gateway = |delivers: bool| {
+            let polls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            MockGateway::start(move |request| match request.command().as_str() {
+                "info" => info_response(),
+                "send_sms" => soap_response(&[("status", "accepted"), ("id", "7")]),
+                _ => match polls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                    n if n >= 2 && delivers => {
+                        soap_response(&[("id", "7"), ("status", "delivered")])
+                    }
+                    _ => soap_response(&[("id", "7"), ("status", "pending")]),
+                },
+            })
+        };
+        let polls = |gateway: &MockGateway| {
+            gateway
+                .commands()
+                .into_iter()
+                .filter(|command| command == "get_status")
+                .count()
+        };
+        async fn send(
+            client: &TigronSms,
+            timeout: Duration,
+        ) -> Result<DeliveryStatus, TigronError> {
+            client
+                .send_and_confirm(
+                    "+32.470000001".to_string(),
+                    "+32.470654321".to_string(),
+                    "Your code is 123456".to_string(),
+                    Duration::from_secs(10),
+                    timeout,
+                )
+                .await
+        }
 
-                </soap:Envelope>"#,
-            ns = self.ns,
-            username = self.credentials.0,
-            password = self.credentials.1,
-            cmd = cmd_xml
+        let delivering = gateway(true).await;
+        let clock = Arc::new(ManualClock::new());
+        let client = delivering.client().with_clock(clock.clone());
+        let started = clock.now();
+        assert_eq!(
+            send(&client, Duration::from_secs(60)).await.unwrap(),
+            DeliveryStatus::Delivered
         );
+        assert_eq!(clock.now() - started, Duration::from_secs(20));
+        assert_eq!(polls(&delivering), 3);
 
-        wsdl
+        // Polls at 0, 10 and 20 seconds, the next one would be past the timeout
+        let pending = gateway(false).await;
+        let clock = Arc::new(ManualClock::new());
+        let client = pending.client().with_clock(clock.clone());
+        match send(&client, Duration::from_secs(25)).await {
+            Err(TigronError::ConfirmationTimeout { message_id }) => assert_eq!(message_id, "7"),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(polls(&pending), 3);
     }
-}
 
-impl XmlResponseParser {
-    
-    /*
-        :param xml: Takes XML as input. E.g: <item><key>xxx</key><value>yyy</value></item>
-        :return Vec<(String, String)>: Returns a vector of tuples (key, value)
-    */
-    async fn parse(xml: &str) -> std::vec::Vec<(String, String)> {
-        let mut return_items: std::vec::Vec<(String, String)> = std::vec::Vec::new();
+    #[test]
+    fn config_sets_timeouts_rate_limits_and_reads_the_password_from_the_environment() {
+        std::env::set_var("TIGRON_TEST_CONFIG_PASSWORD", "from-env");
+        let config: TigronConfig = serde_json::from_str(
+            r#"{
+                "username": "user",
+                "password": "${TIGRON_TEST_CONFIG_PASSWORD}",
+                "deadline_ms": 10000,
+                "connect_timeout_ms": 3000,
+                "max_concurrent": 4,
+                "country_rate_limits": {"BE": 10, "lu": 5},
+                "rate_limit_behavior": "reject",
+                "rate_limit_queue_cap": 20
+            }"#,
+        )
+        .unwrap();
+        assert!(!format!("{:?}", config).contains("from-env"));
 
-        let parser = EventReader::from_str(xml);
-        let mut read_key = false;
-        let mut read_value = false;
-        let mut key: String = String::new();
-        for e in parser {
-            match e {
-                Ok(XmlEvent::StartElement { name, .. }) => {
-                    read_key = false;
-                    read_value = false;
+        let client = TigronSms::from_config(config.clone()).unwrap();
+        assert_eq!(client.credentials.read().unwrap().1.as_str(), "from-env");
+        assert_eq!(client.retry.deadline, Some(Duration::from_secs(10)));
+        assert_eq!(client.connect_timeout, Some(Duration::from_secs(3)));
+        assert_eq!(client.in_flight.as_ref().unwrap().available_permits(), 4);
+        let limits = &client.country_rate_limits.as_ref().unwrap().limits;
+        assert_eq!(limits.get(&country_of("+32").unwrap()), Some(&10));
+        assert_eq!(limits.get(&country_of("+352").unwrap()), Some(&5));
+        assert_eq!(client.rate_limit_behavior, RateLimitBehavior::Reject);
+        assert_eq!(client.rate_limit_queue_cap, 20);
 
-                    if name.local_name == "key" {
-                        read_key = true;
-                    }
-                    if name.local_name == "value" {
-                        read_value = true;
-                    }
-                }
-                Ok(XmlEvent::Characters(text)) => {
-                    if read_key {
-                        key = text.to_string();
-                    }
-                    if read_value {
-                        return_items.push((key.to_string(), text.to_string()));
-                    }
-                }
-                Err(_e) => {
-                    break;
-                }
-                _ => {}
+        // Mistakes in the settings fail when the client is made, not at the first send
+        let mut unknown_country = config.clone();
+        let limits = vec![("XX".to_string(), 1)].into_iter().collect();
+        unknown_country.country_rate_limits = Some(limits);
+        assert!(matches!(TigronSms::from_config(unknown_country), Err(TigronError::Config(_))));
+        let mut unset_variable = config;
+        unset_variable.password = "${TIGRON_TEST_CONFIG_UNSET}".to_string();
+        assert!(matches!(TigronSms::from_config(unset_variable), Err(TigronError::Config(_))));
+
+        assert_eq!(interpolate_env("a${TIGRON_TEST_CONFIG_PASSWORD}b$").unwrap(), "afrom-envb$");
+        assert_eq!(interpolate_env("no ${ closing").unwrap(), "no ${ closing");
+    }
+
+    #[tokio::test]
+    async fn only_transient_rejections_are_sent_again() {
+        // The first send is throttled, the second is accepted
+        let sends = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let gateway = MockGateway::start(move |request| match request.command().as_str() {
+            "info" => info_response(),
+            _ if request.values("to") == vec!["+32.470000002"] => {
+                soap_response(&[("status", "rejected"), ("code", "invalid_number")])
+            }
+            _ if sends.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 => {
+                soap_response(&[("status", "rejected"), ("code", "throttled")])
             }
+            _ => soap_response(&[("status", "accepted"), ("id", "1")]),
+        })
+        .await;
+        let attempts = Arc::new(Mutex::new(std::vec::Vec::new()));
+        let log = attempts.clone();
+        let client = gateway
+            .client()
+            .with_clock(Arc::new(ManualClock::new()))
+            .with_retries(3, Duration::from_millis(100))
+            .with_on_attempt(Arc::new(move |attempt: &AttemptInfo| {
+                log.lock().unwrap().push(attempt.attempt)
+            }));
+        let send = |to: &str| {
+            client.send(to.to_string(), "+32.470654321".to_string(), "Hello".to_string())
+        };
+
+        assert!(matches!(send("+32.470000001").await, Ok(SendOutcome::Accepted { .. })));
+        assert_eq!(*attempts.lock().unwrap(), vec![1, 1, 2]);
+
+        // A permanent code is returned after a single send
+        attempts.lock().unwrap().clear();
+        match send("+32.470000002").await {
+            Ok(SendOutcome::Rejected { code, .. }) => assert_eq!(code, "invalid_number"),
+            outcome => panic!("unexpected outcome {:?}", outcome),
         }
+        assert_eq!(*attempts.lock().unwrap(), vec![1]);
 
-        return_items
+        let commands = gateway.commands();
+        assert_eq!(commands.iter().filter(|command| *command == "send_sms").count(), 3);
     }
 
-    /*
-        Returns the value of the matching key
-        :param items: Array of returned_items retrieved from API-response
-        :param key: The key we want to retrieve the value from
-        :return String: Returns the value matching the key
-    */
-    async fn value(items: &std::vec::Vec<(String, String)>, key: &str) -> String {
-        for pair in items.iter() {
-            if pair.0 == key {
-                return pair.1.to_string();
-            }
+    #[tokio::test]
+    async fn only_sends_the_gateway_took_count_against_the_spend_cap() {
+        let gateway = MockGateway::start(|request| match request.command().as_str() {
+            "info" => info_response(),
+            _ => match request.values("to").join("").as_str() {
+                "+32.470000001" => soap_response(&[("status", "accepted"), ("id", "1")]),
+                "+32.470000002" => {
+                    soap_response(&[("status", "rejected"), ("code", "invalid_number")])
+                }
+                _ => soap_response(&[("id", "3")]),
+            },
+        })
+        .await;
+        let client = gateway.client().with_spend_cap(Amount::from(10u32));
+        let send = |to: &str| {
+            client.send(to.to_string(), "+32.470654321".to_string(), "Hello".to_string())
+        };
+
+        assert!(matches!(send("+32.470000001").await, Ok(SendOutcome::Accepted { .. })));
+        assert_eq!(client.spent().amount, Amount::from(1u32));
+
+        // Neither a rejection nor an answer without a status is charged
+        assert!(matches!(send("+32.470000002").await, Ok(SendOutcome::Rejected { .. })));
+        assert_eq!(client.spent().amount, Amount::from(1u32));
+        assert!(matches!(send("+32.470000003").await, Err(TigronError::Parse(_))));
+        assert_eq!(client.spent().amount, Amount::from(1u32));
+    }
+
+    #[tokio::test]
+    async fn transient_rejections_stop_at_the_deadline() {
+        let gateway = MockGateway::start(|request| match request.command().as_str() {
+            "info" => info_response(),
+            _ => soap_response(&[("status", "rejected"), ("code", "throttled")]),
+        })
+        .await;
+        let clock = Arc::new(ManualClock::new());
+        let client = gateway
+            .client()
+            .with_clock(clock.clone())
+            .with_retries(5, Duration::from_millis(200))
+            .with_deadline(Duration::from_millis(500));
+        client.get_user_id().await.unwrap();
+        let started = clock.now();
+
+        // 200ms after the first send, the second retry would sleep 400ms more and pass the deadline
+        let outcome = client
+            .send(
+                "+32.470000001".to_string(),
+                "+32.470654321".to_string(),
+                "Hello".to_string(),
+            )
+            .await;
+        match outcome {
+            Ok(SendOutcome::Rejected { code, .. }) => assert_eq!(code, "throttled"),
+            outcome => panic!("unexpected outcome {:?}", outcome),
         }
+        let commands = gateway.commands();
+        assert_eq!(commands.iter().filter(|command| *command == "send_sms").count(), 2);
+        assert_eq!(clock.now() - started, Duration::from_millis(200));
+    }
 
-        "".to_string()
+    // Shortener taking 30ms on the manual clock
+    struct SlowShortener(Arc<ManualClock>);
+
+    impl UrlShortener for SlowShortener {
+        fn shorten<'a>(&'a self, _url: &'a str) -> BoxFuture<'a, Result<String, TigronError>> {
+            self.0.advance(Duration::from_millis(30));
+            Box::pin(futures::future::ready(Ok("https://t.ly/a1".to_string())))
+        }
+    }
+
+    #[tokio::test]
+    async fn send_timed_reports_the_exchange_apart_from_the_preparation() {
+        let gateway = MockGateway::start(|request| match request.command().as_str() {
+            "info" => info_response(),
+            _ => soap_response(&[("status", "accepted"), ("id", "1")]),
+        })
+        .await;
+
+        // Every exchange with the gateway takes 100ms on the manual clock
+        let clock = Arc::new(ManualClock::new());
+        let slow_clock = clock.clone();
+        let client = gateway
+            .client()
+            .with_clock(clock.clone())
+            .with_url_shortener(Arc::new(SlowShortener(clock.clone())))
+            .with_on_attempt(Arc::new(move |_: &AttemptInfo| {
+                slow_clock.advance(Duration::from_millis(100))
+            }));
+        let send = || {
+            client.send_timed(
+                "+32.470000001".to_string(),
+                "+32.470654321".to_string(),
+                "See https://example.com/a/very/long/tracking/url".to_string(),
+            )
+        };
+
+        let (outcome, timing) = send().await.unwrap();
+        assert!(matches!(outcome, SendOutcome::Accepted { .. }));
+        assert_eq!(
+            timing,
+            Timing {
+                preparation: Duration::from_millis(30),
+                user_id_lookup: Duration::from_millis(100),
+                send_call: Duration::from_millis(100),
+                total: Duration::from_millis(230),
+            }
+        );
+
+        // The user_id is cached for the second send
+        let (_, timing) = send().await.unwrap();
+        assert_eq!(timing.user_id_lookup, Duration::from_secs(0));
+        assert_eq!(timing.send_call, Duration::from_millis(100));
+        assert_eq!(timing.total, Duration::from_millis(130));
+    }
+
+    // HTTP-client whose connections are refused: it goes through a proxy on a closed port
+    fn refusing_http() -> reqwest::Client {
+        reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all("http://127.0.0.1:1").unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn refused_connection_fails_over_to_the_other_family() {
+        let gateway =
+            MockGateway::start(|_| soap_response(&[("status", "accepted"), ("id", "1")])).await;
+        let results = Arc::new(Mutex::new(std::vec::Vec::new()));
+        let log = results.clone();
+        let client = gateway
+            .client()
+            .with_family_failover(true)
+            .with_retries(1, Duration::from_millis(1))
+            .with_on_attempt(Arc::new(move |attempt: &AttemptInfo| {
+                log.lock().unwrap().push(attempt.result.clone())
+            }));
+        *client.user_id.lock().unwrap() = Some(("42".to_string(), Instant::now()));
+
+        // The first attempt can't connect, the retry goes over IPv4
+        assert!(client.http.set(refusing_http()).is_ok());
+        assert!(client
+            .http_by_family
+            .set((reqwest::Client::new(), refusing_http()))
+            .is_ok());
+
+        let outcome = client
+            .send(
+                "+32.470000001".to_string(),
+                "+32.470654321".to_string(),
+                "Hello".to_string(),
+            )
+            .await;
+        assert!(matches!(outcome, Ok(SendOutcome::Accepted { .. })));
+        let results = results.lock().unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+        assert_eq!(results[1], Ok(200));
+        assert_eq!(gateway.commands(), vec!["send_sms"]);
+    }
+
+    #[tokio::test]
+    async fn dropped_connection_does_not_switch_family() {
+        // The gateway drops the first connection after reading the request
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let gateway = MockGateway::start(move |_| {
+            match requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => std::vec::Vec::new(),
+                _ => soap_response(&[("status", "accepted"), ("id", "1")]),
+            }
+        })
+        .await;
+        let client = gateway
+            .client()
+            .with_family_failover(true)
+            .with_retries(1, Duration::from_millis(1));
+        *client.user_id.lock().unwrap() = Some(("42".to_string(), Instant::now()));
+
+        // Both families refuse, so only a retry over the same client reaches the gateway
+        assert!(client
+            .http_by_family
+            .set((refusing_http(), refusing_http()))
+            .is_ok());
+
+        let outcome = client
+            .send(
+                "+32.470000001".to_string(),
+                "+32.470654321".to_string(),
+                "Hello".to_string(),
+            )
+            .await;
+        assert!(matches!(outcome, Ok(SendOutcome::Accepted { .. })));
+        assert_eq!(gateway.commands(), vec!["send_sms", "send_sms"]);
+    }
+
+    #[tokio::test]
+    async fn failed_group_keeps_the_results_of_the_others() {
+        // The request of the second message fails on the transport
+        let gateway = MockGateway::start(|request| match request.command().as_str() {
+            "info" => info_response(),
+            _ if request.values("message") == vec!["Second"] => std::vec::Vec::new(),
+            _ => soap_response(&[("status", "accepted"), ("id", "1")]),
+        })
+        .await;
+        let dead_letters = Arc::new(Mutex::new(std::vec::Vec::new()));
+        let log = dead_letters.clone();
+        let client = gateway
+            .client()
+            .with_dead_letter(Arc::new(move |failed: FailedSend| {
+                log.lock().unwrap().push(failed.request.message)
+            }));
+
+        let request = |to: &str, message: &str| SendRequest {
+            to: to.to_string(),
+            from: "+32.470654321".to_string(),
+            message: message.to_string(),
+        };
+        let results = client
+            .send_batch(
+                vec![
+                    request("+32.470000001", "First"),
+                    request("+32.470000002", "Second"),
+                    request("+32.470000003", "Second"),
+                ],
+                SendOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        assert!(matches!(results[0], Ok(SendOutcome::Accepted { .. })));
+        for result in &results[1..] {
+            match result {
+                Err(TigronError::Shared(e)) => assert!(matches!(**e, TigronError::Transport(_))),
+                result => panic!("unexpected result {:?}", result),
+            }
+        }
+        assert_eq!(*dead_letters.lock().unwrap(), vec!["Second", "Second"]);
     }
 }